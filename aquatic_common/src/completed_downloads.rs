@@ -0,0 +1,114 @@
+use std::hash::Hash;
+
+use hashbrown::HashSet;
+
+/// Completed downloads of a torrent, counted once per peer id
+///
+/// Peers are counted when turning from leechers into seeders, or when
+/// announcing with event completed without already being seeders. Ids of
+/// counted peers are kept for as long as the torrent is, even after the
+/// peers have been removed from it, so peers that stop or expire and then
+/// complete again are not counted twice. This means that memory use grows
+/// with the number of completed downloads of each active torrent.
+#[derive(Clone, Debug)]
+pub struct CompletedDownloads<P>(HashSet<P>);
+
+impl<P> Default for CompletedDownloads<P> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<P: Copy + Eq + Hash> CompletedDownloads<P> {
+    /// Count download of peer if it completed it with this announce.
+    /// `opt_was_seeder` should be Some(is_seeder) if peer was already
+    /// present in torrent.
+    ///
+    /// Returns true if peer was counted.
+    pub fn update(
+        &mut self,
+        peer_id: P,
+        is_seeder: bool,
+        opt_was_seeder: Option<bool>,
+        event_completed: bool,
+    ) -> bool {
+        let completed = match (is_seeder, opt_was_seeder) {
+            (true, Some(false)) => true,
+            (true, None) => event_completed,
+            _ => false,
+        };
+
+        completed && self.0.insert(peer_id)
+    }
+
+    /// Add peer id without checking announce state, e.g., when restoring
+    /// saved state
+    pub fn insert(&mut self, peer_id: P) {
+        self.0.insert(peer_id);
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Number of peers counted in either `self` or `other`, e.g., for
+    /// combining counts of IPv4 and IPv6 swarms of a torrent without counting
+    /// dual-stack peers twice
+    pub fn count_union(&self, other: &Self) -> usize {
+        let (smaller, larger) = if self.0.len() <= other.0.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+
+        larger.0.len()
+            + smaller
+                .0
+                .iter()
+                .filter(|peer_id| !larger.0.contains(*peer_id))
+                .count()
+    }
+
+    pub fn peer_ids(&self) -> impl Iterator<Item = &P> {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completed_downloads() {
+        let mut completed = CompletedDownloads::default();
+
+        // Initial seeder is not counted
+        assert!(!completed.update(0u8, true, None, false));
+        // Leecher turning into seeder is counted once
+        assert!(!completed.update(1, false, None, false));
+        assert!(completed.update(1, true, Some(false), false));
+        assert!(!completed.update(1, true, Some(true), true));
+        // New peer announcing completion is counted
+        assert!(completed.update(2, true, None, true));
+
+        assert_eq!(completed.count(), 2);
+
+        // Peers that were removed (stopped or expired) and then complete
+        // again are not counted again
+        assert!(!completed.update(2, true, None, true));
+        assert!(!completed.update(1, false, None, false));
+        assert!(!completed.update(1, true, Some(false), false));
+
+        assert_eq!(completed.count(), 2);
+
+        // Peers counted in both are only counted once in union
+        let mut other = CompletedDownloads::default();
+
+        other.insert(2);
+        other.insert(3);
+
+        assert_eq!(completed.count_union(&other), 3);
+        assert_eq!(other.count_union(&completed), 3);
+        assert_eq!(completed.count_union(&Default::default()), 2);
+    }
+}
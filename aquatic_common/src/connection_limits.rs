@@ -0,0 +1,345 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use aquatic_toml_config::TomlConfig;
+use hashbrown::hash_map::Entry;
+use hashbrown::HashMap;
+use serde::Deserialize;
+
+/// Limits on number of concurrent connections, tracked across socket workers
+///
+/// Connections exceeding a limit are closed right after being accepted.
+/// For connections from PROXY protocol sources, per-IP limits apply to the
+/// client address once it has been read. aquatic_ws also applies them to
+/// client addresses from reverse proxy headers in WebSocket handshakes, while
+/// aquatic_http doesn't apply them to connections from trusted reverse
+/// proxies, since a single connection carries requests from many clients.
+/// Set a limit to 0 to disable it.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionLimitConfig {
+    /// Maximum number of concurrent connections
+    pub max_connections: usize,
+    /// Maximum number of concurrent connections from each IP address (or
+    /// address prefix, see below)
+    pub max_connections_per_ip: usize,
+    /// Apply per-IP limit to IPv4 address prefixes of this length. 32 means
+    /// single addresses.
+    pub ipv4_prefix_length: u8,
+    /// Apply per-IP limit to IPv6 address prefixes of this length. Since
+    /// hosts are often assigned whole /64 ranges, limiting single addresses
+    /// (128) is usually not effective.
+    pub ipv6_prefix_length: u8,
+}
+
+impl Default for ConnectionLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            ipv4_prefix_length: 32,
+            ipv6_prefix_length: 64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionLimitExceeded {
+    MaxConnections,
+    /// Contains address prefix that the limit was exceeded for
+    MaxConnectionsPerIp(IpAddr),
+}
+
+impl ConnectionLimitExceeded {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MaxConnections => "max_connections",
+            Self::MaxConnectionsPerIp(_) => "max_connections_per_ip",
+        }
+    }
+}
+
+impl ::std::fmt::Display for ConnectionLimitExceeded {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        match self {
+            Self::MaxConnections => write!(f, "maximum number of connections reached"),
+            Self::MaxConnectionsPerIp(prefix) => write!(
+                f,
+                "maximum number of connections reached for address prefix {}",
+                prefix
+            ),
+        }
+    }
+}
+
+impl ::std::error::Error for ConnectionLimitExceeded {}
+
+/// Connection counts, shared by socket workers
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    config: ConnectionLimitConfig,
+    num_connections: AtomicUsize,
+    /// Only used if per-IP limit is set
+    by_ip_prefix: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: &ConnectionLimitConfig) -> anyhow::Result<Self> {
+        if config.ipv4_prefix_length > 32 {
+            return Err(anyhow::anyhow!(
+                "invalid IPv4 prefix length: {}",
+                config.ipv4_prefix_length
+            ));
+        }
+        if config.ipv6_prefix_length > 128 {
+            return Err(anyhow::anyhow!(
+                "invalid IPv6 prefix length: {}",
+                config.ipv6_prefix_length
+            ));
+        }
+
+        Ok(Self {
+            config: config.clone(),
+            num_connections: Default::default(),
+            by_ip_prefix: Default::default(),
+        })
+    }
+
+    /// Register connection if limits allow it. Per-IP limit is only checked
+    /// if `opt_ip` is Some. The connection is unregistered when the returned
+    /// permit is dropped.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        opt_ip: Option<IpAddr>,
+    ) -> Result<ConnectionPermit, ConnectionLimitExceeded> {
+        let num_connections = self.num_connections.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if self.config.max_connections != 0 && num_connections > self.config.max_connections {
+            self.num_connections.fetch_sub(1, Ordering::Relaxed);
+
+            return Err(ConnectionLimitExceeded::MaxConnections);
+        }
+
+        let opt_ip_prefix = match opt_ip.map(|ip| self.try_count_ip(ip)).transpose() {
+            Ok(opt_ip_prefix) => opt_ip_prefix.flatten(),
+            Err(err) => {
+                self.num_connections.fetch_sub(1, Ordering::Relaxed);
+
+                return Err(err);
+            }
+        };
+
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            opt_ip_prefix,
+        })
+    }
+
+    /// Count connection against per-IP limit if it is set. Returns address
+    /// prefix that the connection was counted for.
+    fn try_count_ip(&self, ip: IpAddr) -> Result<Option<IpAddr>, ConnectionLimitExceeded> {
+        if self.config.max_connections_per_ip == 0 {
+            return Ok(None);
+        }
+
+        let ip_prefix = self.ip_prefix(ip);
+
+        let mut by_ip_prefix = self.by_ip_prefix.lock().unwrap();
+        let count = by_ip_prefix.entry(ip_prefix).or_insert(0);
+
+        if *count >= self.config.max_connections_per_ip {
+            return Err(ConnectionLimitExceeded::MaxConnectionsPerIp(ip_prefix));
+        }
+
+        *count += 1;
+
+        Ok(Some(ip_prefix))
+    }
+
+    pub fn num_connections(&self) -> usize {
+        self.num_connections.load(Ordering::Relaxed)
+    }
+
+    /// Number of address prefixes with connections counted against per-IP
+    /// limit
+    pub fn num_ip_prefixes(&self) -> usize {
+        self.by_ip_prefix.lock().unwrap().len()
+    }
+
+    fn ip_prefix(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.config.ipv4_prefix_length))
+                    .unwrap_or(0);
+
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.config.ipv6_prefix_length))
+                    .unwrap_or(0);
+
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+        }
+    }
+
+    fn release(&self, opt_ip_prefix: Option<IpAddr>) {
+        if let Some(ip_prefix) = opt_ip_prefix {
+            let mut by_ip_prefix = self.by_ip_prefix.lock().unwrap();
+
+            if let Entry::Occupied(mut entry) = by_ip_prefix.entry(ip_prefix) {
+                *entry.get_mut() -= 1;
+
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+
+        self.num_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Proof of connection being counted by limiter. Unregisters connection
+/// when dropped.
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    opt_ip_prefix: Option<IpAddr>,
+}
+
+impl ConnectionPermit {
+    /// Apply per-IP limit to connection that was registered without an
+    /// address, e.g., once client address of connection from proxy is
+    /// known. Does nothing if connection is already counted for an address.
+    ///
+    /// On error, connection remains counted against global limit until
+    /// permit is dropped.
+    pub fn try_limit_ip(&mut self, ip: IpAddr) -> Result<(), ConnectionLimitExceeded> {
+        if self.opt_ip_prefix.is_none() {
+            self.opt_ip_prefix = self.limiter.try_count_ip(ip)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.opt_ip_prefix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limiter() {
+        let limiter = Arc::new(
+            ConnectionLimiter::new(&ConnectionLimitConfig {
+                max_connections: 4,
+                max_connections_per_ip: 2,
+                ipv4_prefix_length: 24,
+                ipv6_prefix_length: 64,
+            })
+            .unwrap(),
+        );
+
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        let a = limiter.try_acquire(ip("1.2.3.4")).unwrap();
+        let b = limiter.try_acquire(ip("1.2.3.5")).unwrap();
+
+        assert_eq!(
+            limiter.try_acquire(ip("1.2.3.6")).err(),
+            Some(ConnectionLimitExceeded::MaxConnectionsPerIp(
+                "1.2.3.0".parse().unwrap()
+            ))
+        );
+
+        let c = limiter.try_acquire(ip("2001:db8::1")).unwrap();
+        let d = limiter.try_acquire(None).unwrap();
+
+        assert_eq!(
+            limiter.try_acquire(ip("1.2.4.1")).err(),
+            Some(ConnectionLimitExceeded::MaxConnections)
+        );
+        assert_eq!(limiter.num_connections(), 4);
+        assert_eq!(limiter.num_ip_prefixes(), 2);
+
+        drop(a);
+
+        let a = limiter.try_acquire(ip("1.2.3.7")).unwrap();
+
+        drop((a, b, c, d));
+
+        // Connections from proxies are limited once client address is known
+        let mut a = limiter.try_acquire(None).unwrap();
+        let mut b = limiter.try_acquire(None).unwrap();
+        let mut c = limiter.try_acquire(None).unwrap();
+
+        a.try_limit_ip("1.2.3.4".parse().unwrap()).unwrap();
+        b.try_limit_ip("1.2.3.5".parse().unwrap()).unwrap();
+
+        assert_eq!(
+            c.try_limit_ip("1.2.3.6".parse().unwrap()).err(),
+            Some(ConnectionLimitExceeded::MaxConnectionsPerIp(
+                "1.2.3.0".parse().unwrap()
+            ))
+        );
+        assert_eq!(limiter.num_connections(), 3);
+
+        // Connections already counted for an address are not counted again
+        a.try_limit_ip("1.2.4.1".parse().unwrap()).unwrap();
+
+        assert_eq!(limiter.num_ip_prefixes(), 1);
+
+        drop((a, b, c));
+
+        assert_eq!(limiter.num_connections(), 0);
+        assert_eq!(limiter.num_ip_prefixes(), 0);
+
+        assert!(ConnectionLimiter::new(&ConnectionLimitConfig {
+            ipv4_prefix_length: 33,
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_connection_limiter_prefix_lengths() {
+        let limiter = |ipv4_prefix_length, ipv6_prefix_length| {
+            ConnectionLimiter::new(&ConnectionLimitConfig {
+                ipv4_prefix_length,
+                ipv6_prefix_length,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        let ipv4: IpAddr = "1.2.3.4".parse().unwrap();
+        let ipv6: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+
+        assert_eq!(limiter(32, 128).ip_prefix(ipv4), ipv4);
+        assert_eq!(limiter(32, 128).ip_prefix(ipv6), ipv6);
+        assert_eq!(
+            limiter(16, 48).ip_prefix(ipv4),
+            "1.2.0.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            limiter(16, 48).ip_prefix(ipv6),
+            "2001:db8:1::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            limiter(0, 0).ip_prefix(ipv4),
+            "0.0.0.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            limiter(0, 0).ip_prefix(ipv6),
+            "::".parse::<IpAddr>().unwrap()
+        );
+    }
+}
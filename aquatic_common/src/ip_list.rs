@@ -0,0 +1,368 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use arc_swap::{ArcSwap, Cache};
+use serde::{Deserialize, Serialize};
+
+/// IP list mode. Available modes are allow, deny and off.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpListMode {
+    /// Only accept traffic from addresses in ranges present in file
+    Allow,
+    /// Do not accept traffic from addresses in ranges present in file
+    Deny,
+    /// Turn off IP list functionality
+    Off,
+}
+
+impl IpListMode {
+    pub fn is_on(&self) -> bool {
+        !matches!(self, Self::Off)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpListConfig {
+    pub mode: IpListMode,
+    /// Path to IP list file consisting of newline-separated IPv4 or IPv6
+    /// CIDR ranges (e.g., 10.0.0.0/8 or 2001:db8::/32) or single addresses.
+    /// Lines starting with # are ignored.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+}
+
+impl Default for IpListConfig {
+    fn default() -> Self {
+        Self {
+            path: "./ip-list.txt".into(),
+            mode: IpListMode::Off,
+        }
+    }
+}
+
+/// Sorted, non-overlapping and non-adjacent inclusive address ranges
+///
+/// Lookups are done with binary search.
+#[derive(Clone, Debug)]
+struct RangeSet<T>(Vec<(T, T)>);
+
+impl<T> Default for RangeSet<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T: Copy + Ord + RangeBound> RangeSet<T> {
+    fn from_unsorted(mut ranges: Vec<(T, T)>) -> Self {
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some((_, last_end)) if last_end.saturating_next() >= start => {
+                    if end > *last_end {
+                        *last_end = end;
+                    }
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+
+        merged.shrink_to_fit();
+
+        Self(merged)
+    }
+
+    fn contains(&self, value: T) -> bool {
+        // Index of first range with start larger than value
+        let index = self.0.partition_point(|(start, _)| *start <= value);
+
+        index
+            .checked_sub(1)
+            .map(|i| value <= self.0[i].1)
+            .unwrap_or(false)
+    }
+}
+
+trait RangeBound {
+    fn saturating_next(self) -> Self;
+}
+
+impl RangeBound for u32 {
+    fn saturating_next(self) -> Self {
+        self.saturating_add(1)
+    }
+}
+
+impl RangeBound for u128 {
+    fn saturating_next(self) -> Self {
+        self.saturating_add(1)
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct IpList {
+    ipv4: RangeSet<u32>,
+    ipv6: RangeSet<u128>,
+}
+
+impl IpList {
+    pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+
+        let lines = reader.lines().collect::<Result<Vec<_>, _>>()?;
+
+        Self::create_from_lines(&lines)
+    }
+
+    /// Create from IPv4 or IPv6 CIDR ranges or single addresses. Empty lines
+    /// and lines starting with # are ignored.
+    pub fn create_from_lines<S: AsRef<str>>(lines: &[S]) -> anyhow::Result<Self> {
+        let mut ipv4_ranges = Vec::new();
+        let mut ipv6_ranges = Vec::new();
+
+        for line in lines {
+            let line = line.as_ref().trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_ip_range(line)
+                .with_context(|| format!("Invalid line in IP list: {}", line))?
+            {
+                IpRange::V4(start, end) => ipv4_ranges.push((start, end)),
+                IpRange::V6(start, end) => ipv6_ranges.push((start, end)),
+            }
+        }
+
+        Ok(Self {
+            ipv4: RangeSet::from_unsorted(ipv4_ranges),
+            ipv6: RangeSet::from_unsorted(ipv6_ranges),
+        })
+    }
+
+    /// Check if traffic from address is allowed. Expects IPv4-mapped IPv6
+    /// addresses to have been converted to IPv4 addresses.
+    pub fn allows(&self, mode: IpListMode, ip: IpAddr) -> bool {
+        match mode {
+            IpListMode::Allow => self.contains(ip),
+            IpListMode::Deny => !self.contains(ip),
+            IpListMode::Off => true,
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.ipv4.contains(u32::from(ip)),
+            IpAddr::V6(ip) => self.ipv6.contains(u128::from(ip)),
+        }
+    }
+
+    /// Number of (merged) ranges in list
+    pub fn len(&self) -> usize {
+        self.ipv4.0.len() + self.ipv6.0.len()
+    }
+}
+
+pub type IpListArcSwap = ArcSwap<IpList>;
+pub type IpListCache = Cache<Arc<IpListArcSwap>, Arc<IpList>>;
+
+pub fn create_ip_list_cache(arc_swap: &Arc<IpListArcSwap>) -> IpListCache {
+    Cache::from(Arc::clone(arc_swap))
+}
+
+pub fn update_ip_list(config: &IpListConfig, ip_list: &Arc<IpListArcSwap>) -> anyhow::Result<()> {
+    if config.mode.is_on() {
+        match IpList::create_from_path(&config.path) {
+            Ok(new_list) => {
+                ip_list.store(Arc::new(new_list));
+
+                ::log::info!("IP list updated")
+            }
+            Err(err) => {
+                ::log::error!("Updating IP list failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum IpRange {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+
+/// Parse CIDR range (or single address) into inclusive range of addresses
+fn parse_ip_range(line: &str) -> anyhow::Result<IpRange> {
+    let (ip, opt_prefix_len) = match line.split_once('/') {
+        Some((ip, prefix_len)) => (ip, Some(prefix_len)),
+        None => (line, None),
+    };
+
+    let ip: IpAddr = ip.trim().parse().with_context(|| "parse ip address")?;
+
+    let max_prefix_len = match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+
+    let prefix_len: u32 = match opt_prefix_len {
+        Some(prefix_len) => prefix_len
+            .trim()
+            .parse()
+            .with_context(|| "parse prefix length")?,
+        None => max_prefix_len,
+    };
+
+    if prefix_len > max_prefix_len {
+        return Err(anyhow::anyhow!(
+            "prefix length {} is larger than {}",
+            prefix_len,
+            max_prefix_len
+        ));
+    }
+
+    match ip {
+        IpAddr::V4(ip) => {
+            let host_mask = u32::MAX.checked_shr(prefix_len).unwrap_or(0);
+            let start = u32::from(ip) & !host_mask;
+
+            Ok(IpRange::V4(start, start | host_mask))
+        }
+        IpAddr::V6(ip) => {
+            let host_mask = u128::MAX.checked_shr(prefix_len).unwrap_or(0);
+            let start = u128::from(ip) & !host_mask;
+
+            Ok(IpRange::V6(start, start | host_mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_ip_range() {
+        let f = parse_ip_range;
+
+        assert_eq!(
+            f("10.0.0.0/8").unwrap(),
+            IpRange::V4(
+                Ipv4Addr::new(10, 0, 0, 0).into(),
+                Ipv4Addr::new(10, 255, 255, 255).into()
+            )
+        );
+        assert_eq!(
+            f("192.168.1.17/24").unwrap(),
+            IpRange::V4(
+                Ipv4Addr::new(192, 168, 1, 0).into(),
+                Ipv4Addr::new(192, 168, 1, 255).into()
+            )
+        );
+        assert_eq!(
+            f("1.2.3.4").unwrap(),
+            IpRange::V4(
+                Ipv4Addr::new(1, 2, 3, 4).into(),
+                Ipv4Addr::new(1, 2, 3, 4).into()
+            )
+        );
+        assert_eq!(f("0.0.0.0/0").unwrap(), IpRange::V4(0, u32::MAX));
+        assert_eq!(
+            f("2001:db8::/32").unwrap(),
+            IpRange::V6(
+                "2001:db8::".parse::<Ipv6Addr>().unwrap().into(),
+                "2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"
+                    .parse::<Ipv6Addr>()
+                    .unwrap()
+                    .into()
+            )
+        );
+        assert_eq!(f("::/0").unwrap(), IpRange::V6(0, u128::MAX));
+
+        assert!(f("1.2.3.4/33").is_err());
+        assert!(f("2001:db8::/129").is_err());
+        assert!(f("1.2.3/24").is_err());
+        assert!(f("1.2.3.4/").is_err());
+        assert!(f("abc").is_err());
+    }
+
+    #[test]
+    fn test_ip_list_allows() {
+        let ipv4_ranges = [
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "192.168.0.0/24",
+            "192.168.1.0/24",
+        ]
+        .iter()
+        .map(|line| match parse_ip_range(line).unwrap() {
+            IpRange::V4(start, end) => (start, end),
+            IpRange::V6(..) => unreachable!(),
+        })
+        .collect();
+        let ipv6_ranges = ["2001:db8::/32"]
+            .iter()
+            .map(|line| match parse_ip_range(line).unwrap() {
+                IpRange::V6(start, end) => (start, end),
+                IpRange::V4(..) => unreachable!(),
+            })
+            .collect();
+
+        let ip_list = IpList {
+            ipv4: RangeSet::from_unsorted(ipv4_ranges),
+            ipv6: RangeSet::from_unsorted(ipv6_ranges),
+        };
+
+        // Overlapping and adjacent ranges are merged
+        assert_eq!(ip_list.len(), 3);
+
+        let listed: [IpAddr; 5] = [
+            Ipv4Addr::new(10, 0, 0, 0).into(),
+            Ipv4Addr::new(10, 255, 255, 255).into(),
+            Ipv4Addr::new(192, 168, 0, 1).into(),
+            Ipv4Addr::new(192, 168, 1, 255).into(),
+            "2001:db8::1".parse().unwrap(),
+        ];
+        let unlisted: [IpAddr; 5] = [
+            Ipv4Addr::new(9, 255, 255, 255).into(),
+            Ipv4Addr::new(11, 0, 0, 0).into(),
+            Ipv4Addr::new(192, 168, 2, 0).into(),
+            "2001:db9::1".parse().unwrap(),
+            "::ffff:10.0.0.1".parse().unwrap(),
+        ];
+
+        for ip in listed {
+            assert!(ip_list.allows(IpListMode::Allow, ip));
+            assert!(!ip_list.allows(IpListMode::Deny, ip));
+            assert!(ip_list.allows(IpListMode::Off, ip));
+        }
+        for ip in unlisted {
+            assert!(!ip_list.allows(IpListMode::Allow, ip));
+            assert!(ip_list.allows(IpListMode::Deny, ip));
+            assert!(ip_list.allows(IpListMode::Off, ip));
+        }
+
+        let empty = IpList::default();
+
+        assert!(!empty.allows(IpListMode::Allow, listed[0]));
+        assert!(empty.allows(IpListMode::Deny, listed[0]));
+    }
+}
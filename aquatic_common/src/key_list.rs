@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aquatic_toml_config::TomlConfig;
+use arc_swap::{ArcSwap, Cache};
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+
+/// Key list mode. Available modes are allow, deny and off.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyListMode {
+    /// Only accept requests with key present in file
+    Allow,
+    /// Do not accept requests with key present in file
+    Deny,
+    /// Turn off key list functionality
+    Off,
+}
+
+impl KeyListMode {
+    pub fn is_on(&self) -> bool {
+        !matches!(self, Self::Off)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyListConfig {
+    pub mode: KeyListMode,
+    /// Path to key list file consisting of newline-separated keys. Lines
+    /// starting with # are ignored.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+}
+
+impl Default for KeyListConfig {
+    fn default() -> Self {
+        Self {
+            path: "./key-list.txt".into(),
+            mode: KeyListMode::Off,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct KeyList(HashSet<String>);
+
+impl KeyList {
+    pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+
+        let lines = reader.lines().collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self::create_from_lines(&lines))
+    }
+
+    /// Create from keys. Surrounding whitespace is trimmed. Empty lines and
+    /// lines starting with # are ignored.
+    pub fn create_from_lines<S: AsRef<str>>(lines: &[S]) -> Self {
+        let keys = lines
+            .iter()
+            .map(|line| line.as_ref().trim())
+            .filter(|line| !(line.is_empty() || line.starts_with('#')))
+            .map(|line| line.to_owned())
+            .collect();
+
+        Self(keys)
+    }
+
+    /// Check if request with key is allowed. Requests without keys are only
+    /// allowed when list is off or in deny mode.
+    pub fn allows(&self, mode: KeyListMode, opt_key: Option<&str>) -> bool {
+        match (mode, opt_key) {
+            (KeyListMode::Allow, Some(key)) => self.0.contains(key),
+            (KeyListMode::Allow, None) => false,
+            (KeyListMode::Deny, Some(key)) => !self.0.contains(key),
+            (KeyListMode::Deny, None) => true,
+            (KeyListMode::Off, _) => true,
+        }
+    }
+}
+
+pub type KeyListArcSwap = ArcSwap<KeyList>;
+pub type KeyListCache = Cache<Arc<KeyListArcSwap>, Arc<KeyList>>;
+
+pub fn create_key_list_cache(arc_swap: &Arc<KeyListArcSwap>) -> KeyListCache {
+    Cache::from(Arc::clone(arc_swap))
+}
+
+pub fn update_key_list(
+    config: &KeyListConfig,
+    key_list: &Arc<KeyListArcSwap>,
+) -> anyhow::Result<()> {
+    if config.mode.is_on() {
+        match KeyList::create_from_path(&config.path) {
+            Ok(new_list) => {
+                key_list.store(Arc::new(new_list));
+
+                ::log::info!("Key list updated")
+            }
+            Err(err) => {
+                ::log::error!("Updating key list failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_list_allows() {
+        let key_list = KeyList::create_from_lines(&["# comment", " abc ", "", "def"]);
+
+        for key in ["abc", "def"] {
+            assert!(key_list.allows(KeyListMode::Allow, Some(key)));
+            assert!(!key_list.allows(KeyListMode::Deny, Some(key)));
+            assert!(key_list.allows(KeyListMode::Off, Some(key)));
+        }
+        for key in ["ab", "# comment", ""] {
+            assert!(!key_list.allows(KeyListMode::Allow, Some(key)));
+            assert!(key_list.allows(KeyListMode::Deny, Some(key)));
+            assert!(key_list.allows(KeyListMode::Off, Some(key)));
+        }
+
+        assert!(!key_list.allows(KeyListMode::Allow, None));
+        assert!(key_list.allows(KeyListMode::Deny, None));
+        assert!(key_list.allows(KeyListMode::Off, None));
+    }
+}
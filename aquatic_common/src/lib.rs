@@ -8,10 +8,17 @@ use rand::Rng;
 
 pub mod access_list;
 pub mod cli;
+pub mod completed_downloads;
+pub mod connection_limits;
 pub mod cpu_pinning;
+pub mod ip_list;
+pub mod key_list;
 pub mod privileges;
+pub mod proxy_protocol;
+pub mod reverse_proxy;
 #[cfg(feature = "rustls")]
 pub mod rustls_config;
+pub mod sharded_map;
 
 /// IndexMap using AHash hasher
 pub type IndexMap<K, V> = indexmap::IndexMap<K, V, RandomState>;
@@ -36,6 +43,10 @@ impl ValidUntil {
     pub fn valid(&self, now: SecondsSinceServerStart) -> bool {
         self.0 .0 > now.0
     }
+    /// Number of seconds left until expiry, or zero if already expired
+    pub fn seconds_remaining(&self, now: SecondsSinceServerStart) -> u32 {
+        self.0 .0.saturating_sub(now.0)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -59,6 +70,13 @@ impl ServerStartInstant {
 #[derive(Debug, Clone, Copy)]
 pub struct SecondsSinceServerStart(u32);
 
+impl SecondsSinceServerStart {
+    /// Number of seconds elapsed since `earlier`, or zero if it is later
+    pub fn seconds_since(&self, earlier: Self) -> u32 {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
 pub struct PanicSentinelWatcher(Arc<AtomicBool>);
 
 impl PanicSentinelWatcher {
@@ -104,6 +122,9 @@ impl Drop for PanicSentinel {
 /// in order to avoid returning too homogeneous peers.
 ///
 /// Might return one less peer than wanted since sender is filtered out.
+///
+/// Peers are converted with `peer_conversion_function`, which gets both
+/// peer map key and value.
 #[inline]
 pub fn extract_response_peers<K, V, R, F>(
     rng: &mut impl Rng,
@@ -114,7 +135,7 @@ pub fn extract_response_peers<K, V, R, F>(
 ) -> Vec<R>
 where
     K: Eq + ::std::hash::Hash,
-    F: Fn(&V) -> R,
+    F: Fn(&K, &V) -> R,
 {
     let peer_map_len = peer_map.len();
 
@@ -125,7 +146,7 @@ where
             if *k == sender_peer_map_key {
                 None
             } else {
-                Some(peer_conversion_function(v))
+                Some(peer_conversion_function(k, v))
             }
         }));
 
@@ -146,14 +167,14 @@ where
         for i in offset_first_half..end_first_half {
             if let Some((k, peer)) = peer_map.get_index(i) {
                 if *k != sender_peer_map_key {
-                    peers.push(peer_conversion_function(peer))
+                    peers.push(peer_conversion_function(k, peer))
                 }
             }
         }
         for i in offset_second_half..end_second_half {
             if let Some((k, peer)) = peer_map.get_index(i) {
                 if *k != sender_peer_map_key {
-                    peers.push(peer_conversion_function(peer))
+                    peers.push(peer_conversion_function(k, peer))
                 }
             }
         }
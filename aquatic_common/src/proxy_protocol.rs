@@ -0,0 +1,427 @@
+//! PROXY protocol header parsing
+//!
+//! Supports the human-readable v1 format and the binary v2 format. See
+//! <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::Deserialize;
+
+use crate::ip_list::IpList;
+use crate::CanonicalSocketAddr;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Maximum length of v1 header, including CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Length of v2 header excluding addresses and TLVs
+const V2_FIXED_LEN: usize = 16;
+/// Maximum time to wait for complete header at start of TCP stream
+#[cfg(feature = "glommio")]
+const READ_TIMEOUT: ::std::time::Duration = ::std::time::Duration::from_secs(5);
+
+#[derive(Clone, Debug, Default, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    /// IPv4 or IPv6 CIDR ranges or single addresses of proxies (e.g., load
+    /// balancers) sending PROXY protocol headers (e.g., ["10.0.0.0/8"])
+    ///
+    /// TCP connections from these addresses must start with a v1 or v2
+    /// header and UDP datagrams with a v2 header. The client address in the
+    /// header is then used instead of the proxy address. Leave empty to
+    /// disable.
+    ///
+    /// UDP responses are sent to the proxy address and port without a
+    /// header, so UDP proxies must use a separate source port for each
+    /// client.
+    pub trusted_sources: Vec<String>,
+}
+
+/// Addresses allowed to send PROXY protocol headers
+#[derive(Clone, Default)]
+pub struct TrustedSources(IpList);
+
+impl TrustedSources {
+    pub fn new(config: &ProxyProtocolConfig) -> anyhow::Result<Self> {
+        IpList::create_from_lines(&config.trusted_sources)
+            .map(Self)
+            .with_context(|| "parse PROXY protocol trusted source addresses")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.len() == 0
+    }
+
+    pub fn contains(&self, addr: CanonicalSocketAddr) -> bool {
+        !self.is_empty() && self.0.contains(addr.get().ip())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// Traffic was proxied on behalf of client with this address
+    Proxied(SocketAddr),
+    /// Traffic was not proxied on behalf of a client (e.g., health checks)
+    /// or address family is unsupported. Proxy address should be used.
+    Local,
+}
+
+impl ProxyHeader {
+    /// Get client address, falling back to proxy address
+    pub fn client_addr(&self, proxy_addr: CanonicalSocketAddr) -> CanonicalSocketAddr {
+        match self {
+            Self::Proxied(addr) => CanonicalSocketAddr::new(*addr),
+            Self::Local => proxy_addr,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    NeedMoreData,
+    Invalid(anyhow::Error),
+}
+
+impl ParseError {
+    fn invalid(message: &'static str) -> Self {
+        Self::Invalid(anyhow::anyhow!(message))
+    }
+}
+
+impl ::std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        match self {
+            Self::NeedMoreData => f.write_str("incomplete PROXY protocol header"),
+            Self::Invalid(err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+/// Parse v1 or v2 header at start of bytes. Returns header and its length.
+pub fn parse_header(bytes: &[u8]) -> Result<(ProxyHeader, usize), ParseError> {
+    if has_prefix(bytes, V2_SIGNATURE)? {
+        parse_v2_header(bytes)
+    } else if has_prefix(bytes, V1_PREFIX)? {
+        parse_v1_header(bytes)
+    } else {
+        Err(ParseError::invalid("no PROXY protocol header"))
+    }
+}
+
+/// Parse v2 header at start of bytes. Returns header and its length.
+pub fn parse_v2_header(bytes: &[u8]) -> Result<(ProxyHeader, usize), ParseError> {
+    if !has_prefix(bytes, V2_SIGNATURE)? {
+        return Err(ParseError::invalid("no PROXY protocol v2 signature"));
+    }
+    if bytes.len() < V2_FIXED_LEN {
+        return Err(ParseError::NeedMoreData);
+    }
+
+    let version = bytes[12] >> 4;
+    let command = bytes[12] & 0x0f;
+    let address_family = bytes[13] >> 4;
+    let len = V2_FIXED_LEN + usize::from(u16::from_be_bytes([bytes[14], bytes[15]]));
+
+    if version != 2 {
+        return Err(ParseError::invalid("unsupported PROXY protocol version"));
+    }
+    if bytes.len() < len {
+        return Err(ParseError::NeedMoreData);
+    }
+
+    let addresses = &bytes[V2_FIXED_LEN..len];
+
+    let header = match (command, address_family) {
+        // LOCAL command
+        (0, _) => ProxyHeader::Local,
+        // PROXY command, AF_INET
+        (1, 1) => {
+            let addresses: &[u8; 12] = addresses
+                .get(..12)
+                .and_then(|addresses| addresses.try_into().ok())
+                .ok_or_else(|| ParseError::invalid("PROXY protocol v2 header too short"))?;
+
+            let ip = Ipv4Addr::from([addresses[0], addresses[1], addresses[2], addresses[3]]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            ProxyHeader::Proxied(SocketAddr::new(ip.into(), port))
+        }
+        // PROXY command, AF_INET6
+        (1, 2) => {
+            let addresses: &[u8; 36] = addresses
+                .get(..36)
+                .and_then(|addresses| addresses.try_into().ok())
+                .ok_or_else(|| ParseError::invalid("PROXY protocol v2 header too short"))?;
+
+            let mut ip = [0; 16];
+
+            ip.copy_from_slice(&addresses[..16]);
+
+            let ip = Ipv6Addr::from(ip);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            ProxyHeader::Proxied(SocketAddr::new(ip.into(), port))
+        }
+        // PROXY command, AF_UNSPEC or AF_UNIX
+        (1, _) => ProxyHeader::Local,
+        _ => return Err(ParseError::invalid("unsupported PROXY protocol command")),
+    };
+
+    Ok((header, len))
+}
+
+fn parse_v1_header(bytes: &[u8]) -> Result<(ProxyHeader, usize), ParseError> {
+    let search_bytes = &bytes[..bytes.len().min(V1_MAX_LEN)];
+
+    let line_len = match search_bytes.windows(2).position(|window| window == b"\r\n") {
+        Some(line_len) => line_len,
+        None if bytes.len() >= V1_MAX_LEN => {
+            return Err(ParseError::invalid("PROXY protocol v1 header too long"))
+        }
+        None => return Err(ParseError::NeedMoreData),
+    };
+
+    let line = ::std::str::from_utf8(&bytes[..line_len])
+        .map_err(|_| ParseError::invalid("PROXY protocol v1 header is not valid UTF-8"))?;
+
+    let header = parse_v1_line(line).map_err(ParseError::Invalid)?;
+
+    Ok((header, line_len + 2))
+}
+
+fn parse_v1_line(line: &str) -> anyhow::Result<ProxyHeader> {
+    let mut parts = line.split(' ').skip(1);
+
+    let protocol = parts.next().with_context(|| "no protocol")?;
+
+    if protocol == "UNKNOWN" {
+        return Ok(ProxyHeader::Local);
+    }
+
+    let source_ip: IpAddr = parts
+        .next()
+        .with_context(|| "no source address")?
+        .parse()
+        .with_context(|| "parse source address")?;
+    let destination_ip: IpAddr = parts
+        .next()
+        .with_context(|| "no destination address")?
+        .parse()
+        .with_context(|| "parse destination address")?;
+    let source_port: u16 = parts
+        .next()
+        .with_context(|| "no source port")?
+        .parse()
+        .with_context(|| "parse source port")?;
+    let _destination_port: u16 = parts
+        .next()
+        .with_context(|| "no destination port")?
+        .parse()
+        .with_context(|| "parse destination port")?;
+
+    if parts.next().is_some() {
+        return Err(anyhow::anyhow!("trailing data"));
+    }
+
+    match (protocol, source_ip, destination_ip) {
+        ("TCP4", IpAddr::V4(_), IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_), IpAddr::V6(_)) => Ok(
+            ProxyHeader::Proxied(SocketAddr::new(source_ip, source_port)),
+        ),
+        _ => Err(anyhow::anyhow!(
+            "protocol {} doesn't match address families",
+            protocol
+        )),
+    }
+}
+
+/// Returns Ok(true) if bytes start with prefix, Ok(false) if they don't and
+/// Err(ParseError::NeedMoreData) if it can't be determined yet
+fn has_prefix(bytes: &[u8], prefix: &[u8]) -> Result<bool, ParseError> {
+    let len = bytes.len().min(prefix.len());
+
+    if bytes[..len] != prefix[..len] {
+        Ok(false)
+    } else if len < prefix.len() {
+        Err(ParseError::NeedMoreData)
+    } else {
+        Ok(true)
+    }
+}
+
+/// Read PROXY protocol header from start of TCP stream and return client
+/// address. Only header bytes are consumed.
+///
+/// Returns an error if the complete header hasn't been received within a few
+/// seconds.
+#[cfg(feature = "glommio")]
+pub async fn read_client_addr(
+    stream: &mut glommio::net::TcpStream,
+    connection_addr: CanonicalSocketAddr,
+) -> anyhow::Result<CanonicalSocketAddr> {
+    let result = glommio::timer::timeout(READ_TIMEOUT, async {
+        Ok(read_client_addr_inner(stream, connection_addr).await)
+    })
+    .await;
+
+    match result {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!("timed out reading PROXY protocol header")),
+    }
+}
+
+#[cfg(feature = "glommio")]
+async fn read_client_addr_inner(
+    stream: &mut glommio::net::TcpStream,
+    connection_addr: CanonicalSocketAddr,
+) -> anyhow::Result<CanonicalSocketAddr> {
+    use futures_lite::AsyncReadExt;
+
+    // Large enough for any v1 header and v2 headers with common TLVs
+    let mut buffer = [0u8; 1024];
+
+    loop {
+        let bytes_peeked = stream
+            .peek(&mut buffer)
+            .await
+            .map_err(|err| anyhow::anyhow!("peek PROXY protocol header: {:#}", err))?;
+
+        if bytes_peeked == 0 {
+            return Err(anyhow::anyhow!("peer closed connection"));
+        }
+
+        match parse_header(&buffer[..bytes_peeked]) {
+            Ok((header, len)) => {
+                stream
+                    .read_exact(&mut buffer[..len])
+                    .await
+                    .with_context(|| "read PROXY protocol header")?;
+
+                return Ok(header.client_addr(connection_addr));
+            }
+            Err(ParseError::NeedMoreData) if bytes_peeked < buffer.len() => {
+                // Peeking returns immediately when there is unread data, so
+                // wait a bit for rest of header to arrive
+                glommio::timer::sleep(::std::time::Duration::from_millis(1)).await;
+            }
+            Err(ParseError::NeedMoreData) => {
+                return Err(anyhow::anyhow!("PROXY protocol header too long"));
+            }
+            Err(ParseError::Invalid(err)) => {
+                return Err(err.context("parse PROXY protocol header"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut bytes = V2_SIGNATURE.to_vec();
+
+        bytes.push(0x20 | command);
+        bytes.push(family << 4 | 1);
+        bytes.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(addresses);
+
+        bytes
+    }
+
+    #[test]
+    fn test_parse_v1_header() {
+        let bytes = b"PROXY TCP4 1.2.3.4 10.0.0.1 5000 443\r\nGET /";
+
+        assert_eq!(
+            parse_header(bytes).unwrap(),
+            (
+                ProxyHeader::Proxied("1.2.3.4:5000".parse().unwrap()),
+                bytes.len() - 5
+            )
+        );
+
+        let bytes = b"PROXY TCP6 2001:db8::1 2001:db8::2 5000 443\r\n";
+
+        assert_eq!(
+            parse_header(bytes).unwrap(),
+            (
+                ProxyHeader::Proxied("[2001:db8::1]:5000".parse().unwrap()),
+                bytes.len()
+            )
+        );
+
+        assert_eq!(
+            parse_header(b"PROXY UNKNOWN\r\n").unwrap(),
+            (ProxyHeader::Local, 15)
+        );
+
+        assert!(matches!(
+            parse_header(b"PROXY TCP4 1.2.3.4"),
+            Err(ParseError::NeedMoreData)
+        ));
+        assert!(matches!(
+            parse_header(b"PRO"),
+            Err(ParseError::NeedMoreData)
+        ));
+        assert!(matches!(
+            parse_header(b"PROXY TCP4 1.2.3.4 2001:db8::2 5000 443\r\n"),
+            Err(ParseError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse_header(b"GET / HTTP/1.1\r\n"),
+            Err(ParseError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_v2_header() {
+        let mut addresses = vec![1, 2, 3, 4, 10, 0, 0, 1];
+        addresses.extend_from_slice(&5000u16.to_be_bytes());
+        addresses.extend_from_slice(&6969u16.to_be_bytes());
+        // TLV, ignored
+        addresses.extend_from_slice(&[0x04, 0, 1, 0]);
+
+        let mut bytes = v2_header(1, 1, &addresses);
+        let header_len = bytes.len();
+
+        bytes.extend_from_slice(b"payload");
+
+        assert_eq!(
+            parse_v2_header(&bytes).unwrap(),
+            (
+                ProxyHeader::Proxied("1.2.3.4:5000".parse().unwrap()),
+                header_len
+            )
+        );
+        assert!(matches!(
+            parse_v2_header(&bytes[..header_len - 1]),
+            Err(ParseError::NeedMoreData)
+        ));
+
+        let source_ip: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut addresses = source_ip.octets().to_vec();
+        addresses.extend_from_slice(&[0; 16]);
+        addresses.extend_from_slice(&5000u16.to_be_bytes());
+        addresses.extend_from_slice(&6969u16.to_be_bytes());
+
+        assert_eq!(
+            parse_header(&v2_header(1, 2, &addresses)).unwrap().0,
+            ProxyHeader::Proxied("[2001:db8::1]:5000".parse().unwrap())
+        );
+
+        assert_eq!(
+            parse_header(&v2_header(0, 0, &[])).unwrap(),
+            (ProxyHeader::Local, V2_FIXED_LEN)
+        );
+
+        assert!(matches!(
+            parse_v2_header(&v2_header(1, 1, &[1, 2, 3, 4])),
+            Err(ParseError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse_v2_header(b"PROXY UNKNOWN\r\n"),
+            Err(ParseError::Invalid(_))
+        ));
+    }
+}
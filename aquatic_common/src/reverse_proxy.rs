@@ -0,0 +1,287 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::ip_list::IpList;
+use crate::CanonicalSocketAddr;
+
+/// Header to take client IP address from. Available headers are
+/// x-forwarded-for, x-real-ip and forwarded.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpHeader {
+    /// Use rightmost address in X-Forwarded-For header that doesn't belong
+    /// to a trusted proxy
+    XForwardedFor,
+    /// Use address in X-Real-IP header
+    XRealIp,
+    /// Use rightmost address in `for` parameters of Forwarded header (RFC
+    /// 7239) that doesn't belong to a trusted proxy
+    Forwarded,
+}
+
+impl ClientIpHeader {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::XForwardedFor => "X-Forwarded-For",
+            Self::XRealIp => "X-Real-IP",
+            Self::Forwarded => "Forwarded",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReverseProxyConfig {
+    /// IPv4 or IPv6 CIDR ranges or single addresses of trusted reverse
+    /// proxies (e.g., ["10.0.0.0/8", "::1"])
+    ///
+    /// For connections from these addresses, the client IP address is taken
+    /// from the header set in `client_ip_header`. If the header is missing or
+    /// invalid, the connection address is used. Leave empty to disable.
+    pub trusted_proxies: Vec<String>,
+    pub client_ip_header: ClientIpHeader,
+}
+
+impl Default for ReverseProxyConfig {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: Vec::new(),
+            client_ip_header: ClientIpHeader::XForwardedFor,
+        }
+    }
+}
+
+/// Trusted reverse proxy addresses and logic for extracting client IP
+/// addresses from headers set by them
+#[derive(Clone)]
+pub struct TrustedProxies {
+    ip_list: IpList,
+    header: ClientIpHeader,
+}
+
+impl Default for TrustedProxies {
+    fn default() -> Self {
+        Self {
+            ip_list: IpList::default(),
+            header: ClientIpHeader::XForwardedFor,
+        }
+    }
+}
+
+impl TrustedProxies {
+    pub fn new(config: &ReverseProxyConfig) -> anyhow::Result<Self> {
+        let ip_list = IpList::create_from_lines(&config.trusted_proxies)
+            .with_context(|| "parse trusted proxy addresses")?;
+
+        Ok(Self {
+            ip_list,
+            header: config.client_ip_header,
+        })
+    }
+
+    /// Check if connection address belongs to a trusted proxy
+    pub fn is_trusted(&self, addr: CanonicalSocketAddr) -> bool {
+        self.ip_list.len() != 0 && self.ip_list.contains(addr.get().ip())
+    }
+
+    /// Name of header to take client IP address from
+    pub fn header_name(&self) -> &'static str {
+        self.header.name()
+    }
+
+    /// If connection is from a trusted proxy, return address with IP replaced
+    /// by client IP address found in header values (in order of appearance).
+    /// Otherwise, or if no valid client IP address is found, return
+    /// connection address.
+    pub fn client_addr<'a, I>(
+        &self,
+        connection_addr: CanonicalSocketAddr,
+        header_values: I,
+    ) -> CanonicalSocketAddr
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        if !self.is_trusted(connection_addr) {
+            return connection_addr;
+        }
+
+        match self.client_ip(header_values) {
+            Some(ip) => CanonicalSocketAddr::new(SocketAddr::new(ip, connection_addr.get().port())),
+            None => {
+                ::log::debug!(
+                    "No valid {} header in request from trusted proxy {}",
+                    self.header_name(),
+                    connection_addr.get().ip()
+                );
+
+                connection_addr
+            }
+        }
+    }
+
+    fn client_ip<'a, I>(&self, header_values: I) -> Option<IpAddr>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let header_values = header_values
+            .into_iter()
+            .map(::std::str::from_utf8)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+
+        match self.header {
+            ClientIpHeader::XRealIp => header_values.last().and_then(|value| parse_ip(value)),
+            ClientIpHeader::XForwardedFor => {
+                let ips = header_values
+                    .iter()
+                    .flat_map(|value| value.split(','))
+                    .map(parse_ip);
+
+                self.rightmost_untrusted(ips)
+            }
+            ClientIpHeader::Forwarded => {
+                let ips = header_values
+                    .iter()
+                    .flat_map(|value| value.split(','))
+                    .map(parse_forwarded_element);
+
+                self.rightmost_untrusted(ips)
+            }
+        }
+    }
+
+    /// Walk addresses from right to left (i.e., starting with the one added
+    /// by the proxy closest to us) and return the first one not belonging to
+    /// a trusted proxy. If all addresses are trusted, return the leftmost
+    /// one. Stop at the first invalid address, since addresses to the left
+    /// of it can't be trusted.
+    fn rightmost_untrusted<I>(&self, ips: I) -> Option<IpAddr>
+    where
+        I: DoubleEndedIterator<Item = Option<IpAddr>>,
+    {
+        let mut opt_leftmost = None;
+
+        for opt_ip in ips.rev() {
+            let ip = opt_ip?;
+
+            if !self.ip_list.contains(ip) {
+                return Some(ip);
+            }
+
+            opt_leftmost = Some(ip);
+        }
+
+        opt_leftmost
+    }
+}
+
+/// Parse IP address, optionally with port and/or IPv6 brackets
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+
+    let ip = value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|value| value.strip_suffix(']'))
+                .and_then(|value| value.parse::<IpAddr>().ok())
+        })?;
+
+    Some(CanonicalSocketAddr::new(SocketAddr::new(ip, 0)).get().ip())
+}
+
+/// Parse IP address in `for` parameter of Forwarded header element, e.g.,
+/// `for="[2001:db8::17]:4711";proto=http`
+fn parse_forwarded_element(element: &str) -> Option<IpAddr> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+
+        if key.trim().eq_ignore_ascii_case("for") {
+            parse_ip(value.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted_proxies(header: ClientIpHeader) -> TrustedProxies {
+        TrustedProxies::new(&ReverseProxyConfig {
+            trusted_proxies: vec!["10.0.0.0/8".into(), "::1".into()],
+            client_ip_header: header,
+        })
+        .unwrap()
+    }
+
+    fn client_ip(proxies: &TrustedProxies, values: &[&str]) -> Option<IpAddr> {
+        let addr = CanonicalSocketAddr::new("10.0.0.1:3000".parse().unwrap());
+        let client_addr = proxies.client_addr(addr, values.iter().map(|value| value.as_bytes()));
+
+        (client_addr != addr).then(|| client_addr.get().ip())
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let proxies = trusted_proxies(ClientIpHeader::XForwardedFor);
+        let f = |values: &[&str]| client_ip(&proxies, values);
+
+        assert_eq!(f(&["1.2.3.4"]), ip("1.2.3.4"));
+        assert_eq!(f(&["5.6.7.8, 1.2.3.4"]), ip("1.2.3.4"));
+        assert_eq!(f(&["5.6.7.8, 1.2.3.4, 10.1.1.1"]), ip("1.2.3.4"));
+        assert_eq!(f(&["5.6.7.8", "1.2.3.4,::1"]), ip("1.2.3.4"));
+        assert_eq!(f(&["10.1.1.1, ::1"]), ip("10.1.1.1"));
+        assert_eq!(f(&["::ffff:1.2.3.4"]), ip("1.2.3.4"));
+        assert_eq!(f(&["[2001:db8::1]:8080"]), ip("2001:db8::1"));
+        assert_eq!(f(&["1.2.3.4, abc, 10.1.1.1"]), None);
+        assert_eq!(f(&[]), None);
+    }
+
+    #[test]
+    fn test_x_real_ip() {
+        let proxies = trusted_proxies(ClientIpHeader::XRealIp);
+        let f = |values: &[&str]| client_ip(&proxies, values);
+
+        assert_eq!(f(&[" 1.2.3.4 "]), ip("1.2.3.4"));
+        assert_eq!(f(&["2001:db8::1"]), ip("2001:db8::1"));
+        assert_eq!(f(&["1.2.3.4, 5.6.7.8"]), None);
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let proxies = trusted_proxies(ClientIpHeader::Forwarded);
+        let f = |values: &[&str]| client_ip(&proxies, values);
+
+        assert_eq!(f(&["for=1.2.3.4"]), ip("1.2.3.4"));
+        assert_eq!(
+            f(&["for=5.6.7.8, For=\"[2001:db8::17]:4711\";proto=https"]),
+            ip("2001:db8::17")
+        );
+        assert_eq!(
+            f(&["for=1.2.3.4;by=10.0.0.1", "for=10.1.1.1"]),
+            ip("1.2.3.4")
+        );
+        assert_eq!(f(&["for=unknown"]), None);
+        assert_eq!(f(&["proto=http"]), None);
+    }
+
+    #[test]
+    fn test_untrusted_connection() {
+        let proxies = trusted_proxies(ClientIpHeader::XForwardedFor);
+        let addr = CanonicalSocketAddr::new("1.1.1.1:3000".parse().unwrap());
+
+        assert_eq!(proxies.client_addr(addr, [&b"1.2.3.4"[..]]), addr);
+    }
+}
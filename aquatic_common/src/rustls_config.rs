@@ -1,35 +1,384 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use hashbrown::HashMap;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde::{Deserialize, Serialize};
 
 pub type RustlsConfig = rustls::ServerConfig;
 
+/// TLS config that can be replaced while running. New connections should
+/// load it when starting TLS handshakes.
+pub type RustlsConfigArcSwap = ArcSwap<RustlsConfig>;
+
+/// Certificate to use for TLS connections to a specific server name (SNI)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertificateConfig {
+    /// Server name, e.g., "tracker.example.com", or wildcard name matching
+    /// a single label, e.g., "*.example.com"
+    pub server_name: String,
+    pub certificate_path: PathBuf,
+    pub private_key_path: PathBuf,
+}
+
+/// Create TLS config using certificate in `tls_certificate_path` by default
+/// and certificates in `sni_certificates` for connections to their server
+/// names
+///
+/// Certificate files should contain PEM-encoded certificate chains. Private
+/// key files should contain PEM-encoded PKCS#8, PKCS#1 (RSA) or SEC1 (EC)
+/// keys. Only the first key in each file is used.
 pub fn create_rustls_config(
     tls_certificate_path: &Path,
     tls_private_key_path: &Path,
+    sni_certificates: &[SniCertificateConfig],
 ) -> anyhow::Result<RustlsConfig> {
-    let certs = {
-        let f = File::open(tls_certificate_path)?;
-        let mut f = BufReader::new(f);
+    let default = load_certified_key(tls_certificate_path, tls_private_key_path)?;
 
-        rustls_pemfile::certs(&mut f)?
-            .into_iter()
-            .map(|bytes| rustls::Certificate(bytes))
-            .collect()
-    };
+    let mut by_server_name = HashMap::new();
+
+    for sni_certificate in sni_certificates {
+        let certified_key = load_certified_key(
+            &sni_certificate.certificate_path,
+            &sni_certificate.private_key_path,
+        )
+        .with_context(|| format!("server name {}", sni_certificate.server_name))?;
 
-    let private_key = {
-        let f = File::open(tls_private_key_path)?;
-        let mut f = BufReader::new(f);
+        by_server_name.insert(
+            sni_certificate.server_name.to_ascii_lowercase(),
+            Arc::new(certified_key),
+        );
+    }
 
-        rustls_pemfile::pkcs8_private_keys(&mut f)?
-            .first()
-            .map(|bytes| rustls::PrivateKey(bytes.clone()))
-            .ok_or(anyhow::anyhow!("No private keys in file"))?
+    let resolver = SniCertResolver {
+        default: Arc::new(default),
+        by_server_name,
     };
 
     let tls_config = rustls::ServerConfig::builder()
         .with_safe_defaults()
         .with_no_client_auth()
-        .with_single_cert(certs, private_key)?;
+        .with_cert_resolver(Arc::new(resolver));
 
     Ok(tls_config)
 }
+
+/// Reload TLS config from files. Only affects connections established
+/// after the update.
+pub fn update_rustls_config(
+    tls_certificate_path: &Path,
+    tls_private_key_path: &Path,
+    sni_certificates: &[SniCertificateConfig],
+    tls_config: &RustlsConfigArcSwap,
+) -> anyhow::Result<()> {
+    match create_rustls_config(tls_certificate_path, tls_private_key_path, sni_certificates) {
+        Ok(new_config) => {
+            tls_config.store(Arc::new(new_config));
+
+            ::log::info!("TLS config updated");
+
+            Ok(())
+        }
+        Err(err) => {
+            ::log::error!("Updating TLS config failed: {:#}", err);
+
+            Err(err)
+        }
+    }
+}
+
+/// Spawn thread checking modification times of all certificate and private
+/// key files every `interval`, reloading TLS config when any has changed
+///
+/// Reloading waits until files have been left unchanged for a full interval,
+/// so that certificates and keys that are replaced one at a time are not
+/// mixed up. If loading fails, e.g., because a key doesn't match its
+/// certificate, the current config is kept.
+pub fn spawn_rustls_config_file_watcher(
+    tls_certificate_path: PathBuf,
+    tls_private_key_path: PathBuf,
+    sni_certificates: Vec<SniCertificateConfig>,
+    interval: Duration,
+    tls_config: Arc<RustlsConfigArcSwap>,
+) -> anyhow::Result<()> {
+    let mut paths = vec![tls_certificate_path.clone(), tls_private_key_path.clone()];
+
+    for sni_certificate in sni_certificates.iter() {
+        paths.push(sni_certificate.certificate_path.clone());
+        paths.push(sni_certificate.private_key_path.clone());
+    }
+
+    let modified = move || -> Vec<Option<SystemTime>> {
+        paths
+            .iter()
+            .map(|path| {
+                ::std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect()
+    };
+
+    let mut loaded_modified = modified();
+    let mut previous_modified = loaded_modified.clone();
+
+    ::std::thread::Builder::new()
+        .name("tls-watcher".into())
+        .spawn(move || loop {
+            ::std::thread::sleep(interval);
+
+            let current_modified = modified();
+
+            // Don't reload while any file is missing or still changing,
+            // e.g., when it is being replaced
+            if current_modified != loaded_modified
+                && current_modified == previous_modified
+                && current_modified.iter().all(Option::is_some)
+            {
+                let _ = update_rustls_config(
+                    &tls_certificate_path,
+                    &tls_private_key_path,
+                    &sni_certificates,
+                    &tls_config,
+                );
+
+                loaded_modified = current_modified.clone();
+            }
+
+            previous_modified = current_modified;
+        })?;
+
+    Ok(())
+}
+
+/// Selects certificate by server name sent by client, falling back to
+/// default certificate
+struct SniCertResolver {
+    default: Arc<CertifiedKey>,
+    /// Keys are lowercase
+    by_server_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniCertResolver {
+    fn get(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+
+        if let Some(certified_key) = self.by_server_name.get(&server_name) {
+            return Some(certified_key.clone());
+        }
+
+        let (_, parent) = server_name.split_once('.')?;
+
+        self.by_server_name.get(&format!("*.{}", parent)).cloned()
+    }
+
+    fn get_or_default(&self, opt_server_name: Option<&str>) -> Arc<CertifiedKey> {
+        opt_server_name
+            .and_then(|server_name| self.get(server_name))
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.get_or_default(client_hello.server_name()))
+    }
+}
+
+fn load_certified_key(
+    certificate_path: &Path,
+    private_key_path: &Path,
+) -> anyhow::Result<CertifiedKey> {
+    let certs = load_certificates(certificate_path)
+        .with_context(|| format!("load certificates from {:?}", certificate_path))?;
+    let private_key = load_private_key(private_key_path)
+        .with_context(|| format!("load private key from {:?}", private_key_path))?;
+
+    let signing_key = rustls::sign::any_supported_type(&private_key)
+        .map_err(|_| anyhow::anyhow!("Unsupported private key type in {:?}", private_key_path))?;
+
+    let certified_key = CertifiedKey::new(certs, signing_key);
+
+    check_key_matches_certificate(&certified_key).with_context(|| {
+        format!(
+            "private key in {:?} doesn't match certificate in {:?}",
+            private_key_path, certificate_path
+        )
+    })?;
+
+    Ok(certified_key)
+}
+
+/// Check that private key belongs to end-entity certificate by signing a
+/// message with it and verifying the signature with the certificate
+fn check_key_matches_certificate(certified_key: &CertifiedKey) -> anyhow::Result<()> {
+    use rustls::SignatureScheme;
+
+    const MESSAGE: &[u8] = b"aquatic TLS key check";
+
+    let signer = certified_key
+        .key
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PSS_SHA256,
+        ])
+        .ok_or_else(|| anyhow::anyhow!("no supported signature scheme for private key"))?;
+
+    let algorithm: &webpki::SignatureAlgorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        scheme => return Err(anyhow::anyhow!("unexpected signature scheme {:?}", scheme)),
+    };
+
+    let signature = signer
+        .sign(MESSAGE)
+        .map_err(|err| anyhow::anyhow!("sign message: {}", err))?;
+
+    let certificate =
+        webpki::EndEntityCert::try_from(certified_key.end_entity_cert()?.0.as_slice())
+            .map_err(|err| anyhow::anyhow!("parse certificate: {}", err))?;
+
+    certificate
+        .verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|err| anyhow::anyhow!("verify signature: {}", err))
+}
+
+fn load_certificates(path: &Path) -> anyhow::Result<Vec<rustls::Certificate>> {
+    let mut f = BufReader::new(File::open(path)?);
+
+    let certs = rustls_pemfile::certs(&mut f)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+
+    if certs.is_empty() {
+        Err(anyhow::anyhow!("No certificates in file"))
+    } else {
+        Ok(certs)
+    }
+}
+
+/// Read first PKCS#8, PKCS#1 or SEC1 private key in file
+fn load_private_key(path: &Path) -> anyhow::Result<rustls::PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let mut f = BufReader::new(File::open(path)?);
+
+    loop {
+        match rustls_pemfile::read_one(&mut f)? {
+            Some(Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der)) => {
+                return Ok(rustls::PrivateKey(der));
+            }
+            Some(_) => (),
+            None => return Err(anyhow::anyhow!("No private keys in file")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::SignatureAlgorithm;
+
+    use super::*;
+
+    fn test_data_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/tls")
+            .join(name)
+    }
+
+    fn load_signing_key(name: &str) -> Arc<dyn rustls::sign::SigningKey> {
+        let private_key = load_private_key(&test_data_path(name)).unwrap();
+
+        rustls::sign::any_supported_type(&private_key).unwrap()
+    }
+
+    #[test]
+    fn test_load_private_key() {
+        for (name, algorithm) in [
+            ("key-pkcs8.pem", SignatureAlgorithm::ECDSA),
+            ("key-rsa.pem", SignatureAlgorithm::RSA),
+            ("key-ec.pem", SignatureAlgorithm::ECDSA),
+        ] {
+            assert_eq!(load_signing_key(name).algorithm(), algorithm, "{}", name);
+        }
+
+        assert!(load_private_key(&test_data_path("certificate.pem")).is_err());
+        assert!(load_private_key(&test_data_path("missing.pem")).is_err());
+    }
+
+    #[test]
+    fn test_load_certified_key() {
+        let certified_key = load_certified_key(
+            &test_data_path("certificate.pem"),
+            &test_data_path("key-pkcs8.pem"),
+        )
+        .unwrap();
+
+        assert_eq!(certified_key.cert.len(), 1);
+
+        // Private key doesn't belong to certificate
+        assert!(load_certified_key(
+            &test_data_path("certificate.pem"),
+            &test_data_path("key-rsa.pem"),
+        )
+        .is_err());
+
+        // Private key file doesn't contain certificates
+        assert!(load_certified_key(
+            &test_data_path("key-pkcs8.pem"),
+            &test_data_path("key-pkcs8.pem"),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sni_cert_resolver() {
+        let signing_key = load_signing_key("key-pkcs8.pem");
+
+        // Certificates only differ in contents, which identify them in test
+        let certified_key = |id: u8| {
+            Arc::new(CertifiedKey::new(
+                vec![rustls::Certificate(vec![id])],
+                signing_key.clone(),
+            ))
+        };
+
+        let resolver = SniCertResolver {
+            default: certified_key(0),
+            by_server_name: [
+                ("tracker.example.com".to_string(), certified_key(1)),
+                ("*.example.com".to_string(), certified_key(2)),
+                ("*.example.org".to_string(), certified_key(3)),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        let get = |server_name: &str| resolver.get_or_default(Some(server_name)).cert[0].0[0];
+
+        // Exact match takes precedence over wildcard
+        assert_eq!(get("tracker.example.com"), 1);
+        // Server names are case-insensitive
+        assert_eq!(get("Tracker.EXAMPLE.com"), 1);
+        // Wildcards match a single label
+        assert_eq!(get("other.example.com"), 2);
+        assert_eq!(get("OTHER.example.org"), 3);
+        // Default certificate is used for other server names
+        assert_eq!(get("a.b.example.org"), 0);
+        assert_eq!(get("example.org"), 0);
+        assert_eq!(get("example.net"), 0);
+        assert_eq!(resolver.get_or_default(None).cert[0].0[0], 0);
+    }
+}
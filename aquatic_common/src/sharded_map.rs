@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use crate::AmortizedIndexMap;
+
+/// Maximum number of shards in a [ShardedMap]
+pub const MAX_NUM_SHARDS: usize = 1 << 16;
+
+/// Map from info hash bytes to torrent data, stored in a power-of-two number
+/// of shards indexed by info hash bits
+///
+/// Shards are cleaned one at a time, spread evenly over the torrent cleaning
+/// interval, which avoids long pauses in request handling when there are
+/// many torrents.
+pub struct ShardedMap<V> {
+    shards: Vec<Shard<V>>,
+    next_shard_to_clean: usize,
+}
+
+struct Shard<V> {
+    torrents: AmortizedIndexMap<[u8; 20], V>,
+    /// Number of peers at time of last cleaning
+    num_peers: usize,
+}
+
+impl<V> Default for Shard<V> {
+    fn default() -> Self {
+        Self {
+            torrents: Default::default(),
+            num_peers: 0,
+        }
+    }
+}
+
+impl<V> ShardedMap<V> {
+    /// Create map with `num_shards` rounded up to nearest power of two
+    /// shards. Values larger than [MAX_NUM_SHARDS] are clamped (see
+    /// [validate_num_shards]).
+    pub fn new(num_shards: usize) -> Self {
+        let num_shards = num_shards.clamp(1, MAX_NUM_SHARDS).next_power_of_two();
+
+        Self {
+            shards: ::std::iter::repeat_with(Default::default)
+                .take(num_shards)
+                .collect(),
+            next_shard_to_clean: 0,
+        }
+    }
+
+    /// Get shard index from info hash bits. The first byte is skipped since
+    /// it is used to select the swarm worker.
+    fn shard_index(&self, info_hash: &[u8; 20]) -> usize {
+        let bits = u32::from_be_bytes([info_hash[1], info_hash[2], info_hash[3], info_hash[4]]);
+
+        (bits as usize) & (self.shards.len() - 1)
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<&V> {
+        self.shards[self.shard_index(info_hash)]
+            .torrents
+            .get(info_hash)
+    }
+
+    pub fn get_mut(&mut self, info_hash: &[u8; 20]) -> Option<&mut V> {
+        let shard_index = self.shard_index(info_hash);
+
+        self.shards[shard_index].torrents.get_mut(info_hash)
+    }
+
+    pub fn get_or_insert_default(&mut self, info_hash: [u8; 20]) -> &mut V
+    where
+        V: Default,
+    {
+        let shard_index = self.shard_index(&info_hash);
+
+        self.shards[shard_index]
+            .torrents
+            .entry(info_hash)
+            .or_default()
+    }
+
+    pub fn remove(&mut self, info_hash: &[u8; 20]) -> Option<V> {
+        let shard_index = self.shard_index(info_hash);
+
+        self.shards[shard_index].torrents.remove(info_hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8; 20], &V)> {
+        self.shards.iter().flat_map(|shard| shard.torrents.iter())
+    }
+
+    pub fn num_torrents(&self) -> usize {
+        self.shards.iter().map(|shard| shard.torrents.len()).sum()
+    }
+
+    /// Number of peers in all shards as of their last cleaning
+    pub fn num_peers(&self) -> usize {
+        self.shards.iter().map(|shard| shard.num_peers).sum()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Time between cleaning shards for every shard to be cleaned once
+    /// per `cleaning_interval`
+    pub fn shard_cleaning_interval(&self, cleaning_interval: Duration) -> Duration {
+        // Number of shards is at most MAX_NUM_SHARDS, so it fits in a u32
+        cleaning_interval / self.shards.len() as u32
+    }
+
+    /// Clean next shard and reclaim space
+    ///
+    /// `clean` is called for each torrent in shard. It should return the
+    /// number of peers remaining in the torrent, or None if the torrent
+    /// should be removed.
+    ///
+    /// Returns true if the last shard was cleaned, completing a full round
+    /// of cleaning.
+    pub fn clean_next_shard<F>(&mut self, mut clean: F) -> bool
+    where
+        F: FnMut(&[u8; 20], &mut V) -> Option<usize>,
+    {
+        let shard = &mut self.shards[self.next_shard_to_clean];
+
+        let mut num_peers = 0;
+
+        shard.torrents.retain(|info_hash, torrent| {
+            if let Some(n) = clean(info_hash, torrent) {
+                num_peers += n;
+
+                true
+            } else {
+                false
+            }
+        });
+
+        shard.torrents.shrink_to_fit();
+        shard.num_peers = num_peers;
+
+        self.next_shard_to_clean = (self.next_shard_to_clean + 1) % self.shards.len();
+
+        self.next_shard_to_clean == 0
+    }
+}
+
+/// Check configured number of shards at startup. Returns an error if it
+/// exceeds [MAX_NUM_SHARDS].
+pub fn validate_num_shards(configured: usize) -> anyhow::Result<()> {
+    if configured > MAX_NUM_SHARDS {
+        Err(anyhow::anyhow!(
+            "cleaning.torrent_map_shards can be at most {}, got {}",
+            MAX_NUM_SHARDS,
+            configured
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_num_shards() {
+        assert_eq!(ShardedMap::<()>::new(0).num_shards(), 1);
+        assert_eq!(ShardedMap::<()>::new(3).num_shards(), 4);
+
+        assert!(validate_num_shards(MAX_NUM_SHARDS).is_ok());
+        assert!(validate_num_shards(MAX_NUM_SHARDS + 1).is_err());
+        assert!(validate_num_shards(usize::MAX).is_err());
+
+        // Values that are too large are clamped if not validated
+        let map = ShardedMap::<()>::new(usize::MAX);
+
+        assert_eq!(map.num_shards(), MAX_NUM_SHARDS);
+        assert_eq!(
+            map.shard_cleaning_interval(Duration::from_secs(1 << 16)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_clean_next_shard() {
+        let mut map = ShardedMap::<usize>::new(2);
+
+        for i in 0..8u8 {
+            *map.get_or_insert_default([i; 20]) = i as usize;
+        }
+
+        assert_eq!(map.num_torrents(), 8);
+
+        // Remove torrents with odd values, counting values as peers
+        let keep_even = |_: &[u8; 20], n: &mut usize| (*n & 1 == 0).then_some(*n);
+
+        assert!(!map.clean_next_shard(keep_even));
+        assert!(map.clean_next_shard(keep_even));
+
+        assert_eq!(map.num_torrents(), 4);
+        assert_eq!(map.num_peers(), 2 + 4 + 6);
+        assert_eq!(map.get(&[2; 20]), Some(&2));
+        assert_eq!(map.get(&[3; 20]), None);
+    }
+}
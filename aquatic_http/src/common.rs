@@ -1,12 +1,17 @@
 use std::sync::Arc;
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::connection_limits::ConnectionLimiter;
+use aquatic_common::ip_list::IpListArcSwap;
+use aquatic_common::key_list::KeyListArcSwap;
+use aquatic_common::proxy_protocol::TrustedSources;
+use aquatic_common::reverse_proxy::TrustedProxies;
 use aquatic_common::CanonicalSocketAddr;
 
 pub use aquatic_common::ValidUntil;
 
 use aquatic_http_protocol::{
-    request::{AnnounceRequest, ScrapeRequest},
+    request::{AnnounceRequest, RequestPaths, ScrapeRequest},
     response::{AnnounceResponse, ScrapeResponse},
 };
 use glommio::channels::shared_channel::SharedSender;
@@ -34,4 +39,10 @@ pub enum ChannelRequest {
 #[derive(Default, Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub ip_list: Arc<IpListArcSwap>,
+    pub key_list: Arc<KeyListArcSwap>,
+    pub request_paths: Arc<RequestPaths>,
+    pub trusted_proxies: Arc<TrustedProxies>,
+    pub proxy_protocol_sources: Arc<TrustedSources>,
+    pub connection_limiter: Arc<ConnectionLimiter>,
 }
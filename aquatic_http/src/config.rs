@@ -1,9 +1,13 @@
 use std::{net::SocketAddr, path::PathBuf};
 
+use anyhow::Context;
 use aquatic_common::{
-    access_list::AccessListConfig, cpu_pinning::asc::CpuPinningConfigAsc,
-    privileges::PrivilegeConfig,
+    access_list::AccessListConfig, connection_limits::ConnectionLimitConfig,
+    cpu_pinning::asc::CpuPinningConfigAsc, ip_list::IpListConfig, key_list::KeyListConfig,
+    privileges::PrivilegeConfig, proxy_protocol::ProxyProtocolConfig,
+    reverse_proxy::ReverseProxyConfig, rustls_config::SniCertificateConfig,
 };
+use aquatic_http_protocol::request::{PathTemplate, RequestPaths};
 use aquatic_toml_config::TomlConfig;
 use serde::Deserialize;
 
@@ -11,7 +15,10 @@ use aquatic_common::cli::LogLevel;
 
 /// aquatic_http configuration
 ///
-/// Does not support running behind a reverse proxy.
+/// Running behind a reverse proxy is supported. Configure the proxy
+/// addresses in `reverse_proxy.trusted_proxies` to have client IP addresses
+/// taken from request headers, or in `proxy_protocol.trusted_sources` to have
+/// them taken from PROXY protocol headers.
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
@@ -28,6 +35,13 @@ pub struct Config {
     pub cleaning: CleaningConfig,
     pub privileges: PrivilegeConfig,
     pub access_list: AccessListConfig,
+    pub ip_list: IpListConfig,
+    /// Check keys captured from announce paths (see
+    /// `protocol.announce_paths`) against a list
+    pub key_list: KeyListConfig,
+    pub reverse_proxy: ReverseProxyConfig,
+    pub proxy_protocol: ProxyProtocolConfig,
+    pub connection_limits: ConnectionLimitConfig,
     pub cpu_pinning: CpuPinningConfigAsc,
     #[cfg(feature = "metrics")]
     pub metrics: MetricsConfig,
@@ -44,6 +58,11 @@ impl Default for Config {
             cleaning: CleaningConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            ip_list: IpListConfig::default(),
+            key_list: KeyListConfig::default(),
+            reverse_proxy: ReverseProxyConfig::default(),
+            proxy_protocol: ProxyProtocolConfig::default(),
+            connection_limits: ConnectionLimitConfig::default(),
             cpu_pinning: Default::default(),
             #[cfg(feature = "metrics")]
             metrics: Default::default(),
@@ -66,23 +85,49 @@ pub struct NetworkConfig {
     pub only_ipv6: bool,
     /// Maximum number of pending TCP connections
     pub tcp_backlog: i32,
-    /// Path to TLS certificate (DER-encoded X.509)
+    /// Use TLS for connections to `address`
+    pub enable_tls: bool,
+    /// Path to TLS certificate chain (PEM-encoded X.509)
     pub tls_certificate_path: PathBuf,
-    /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
+    /// Path to TLS private key (PEM-encoded PKCS#8, PKCS#1 or SEC1 key)
     pub tls_private_key_path: PathBuf,
+    /// Additional certificates for TLS connections to specific server names
+    /// (SNI), as tables with keys `server_name`, `certificate_path` and
+    /// `private_key_path`. Server names may be wildcards matching a single
+    /// label (e.g., "*.example.com"). The certificate in
+    /// `tls_certificate_path` is used for other connections.
+    pub tls_sni_certificates: Vec<SniCertificateConfig>,
+    /// Check TLS certificate and private key files for changes this often
+    /// (seconds) and reload them once they have been left unmodified for a
+    /// full interval. Set to 0 to disable. Files are also reloaded on
+    /// SIGUSR1.
+    pub tls_file_check_interval: u64,
     /// Keep connections alive after sending a response
     pub keep_alive: bool,
+    /// Additionally accept plaintext (non-TLS) connections on
+    /// `plaintext_listener_address`
+    ///
+    /// Useful for accepting TLS connections directly while also accepting
+    /// connections from a load balancer that terminates TLS.
+    pub enable_plaintext_listener: bool,
+    /// Bind plaintext listener to this address
+    pub plaintext_listener_address: SocketAddr,
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
             address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            enable_tls: true,
             tls_certificate_path: "".into(),
             tls_private_key_path: "".into(),
+            tls_sni_certificates: Vec::new(),
+            tls_file_check_interval: 0,
             only_ipv6: false,
             tcp_backlog: 1024,
             keep_alive: true,
+            enable_plaintext_listener: false,
+            plaintext_listener_address: SocketAddr::from(([0, 0, 0, 0], 3080)),
         }
     }
 }
@@ -96,6 +141,41 @@ pub struct ProtocolConfig {
     pub max_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Minimum number of seconds between announces by a peer, sent to
+    /// clients as `min interval`. Set to 0 to disable.
+    ///
+    /// Peers announcing again sooner without an event get a response with
+    /// seeder and leecher counts but without any peers.
+    pub min_announce_interval: usize,
+    /// Count completed downloads for each torrent and include the numbers
+    /// in scrape responses
+    ///
+    /// Each peer id is counted at most once per torrent, so ids of counted
+    /// peers are stored for as long as the torrent has peers.
+    pub track_completed_downloads: bool,
+    /// Share peers between IPv4 and IPv6 swarms of the same torrent
+    ///
+    /// Announce responses then include both IPv4 and IPv6 peers, and
+    /// seeder/leecher counts in announce and scrape responses cover both
+    /// swarms.
+    pub share_peers_across_ip_versions: bool,
+    /// Hand out addresses of the other IP version sent in `ipv4` and `ipv6`
+    /// announce parameters (BEP 7) to other peers. Only used if
+    /// `share_peers_across_ip_versions` is set.
+    ///
+    /// These addresses are unauthenticated: clients can make the tracker
+    /// hand out any globally reachable address, e.g., to direct connection
+    /// attempts from other peers to a third party.
+    pub accept_other_ip_version_addresses: bool,
+    /// Paths to accept announce requests on
+    ///
+    /// Paths may contain a single `{key}` placeholder spanning a whole path
+    /// segment, e.g., "/announce/{key}" or "/{key}/announce". Captured keys
+    /// can be checked against `key_list`.
+    pub announce_paths: Vec<String>,
+    /// Paths to accept scrape requests on. Same format as `announce_paths`,
+    /// but captured keys are not checked.
+    pub scrape_paths: Vec<String>,
 }
 
 impl Default for ProtocolConfig {
@@ -104,15 +184,42 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 100,
             max_peers: 50,
             peer_announce_interval: 120,
+            min_announce_interval: 0,
+            track_completed_downloads: false,
+            share_peers_across_ip_versions: false,
+            accept_other_ip_version_addresses: false,
+            announce_paths: vec!["/announce".into()],
+            scrape_paths: vec!["/scrape".into()],
         }
     }
 }
 
+impl ProtocolConfig {
+    pub fn request_paths(&self) -> anyhow::Result<RequestPaths> {
+        fn parse(paths: &[String]) -> anyhow::Result<Vec<PathTemplate>> {
+            paths
+                .iter()
+                .map(|path| path.parse().map_err(|err| anyhow::anyhow!("{}", err)))
+                .collect()
+        }
+
+        Ok(RequestPaths {
+            announce: parse(&self.announce_paths)
+                .with_context(|| "parse protocol.announce_paths")?,
+            scrape: parse(&self.scrape_paths).with_context(|| "parse protocol.scrape_paths")?,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct CleaningConfig {
     /// Clean peers this often (seconds)
     pub torrent_cleaning_interval: u64,
+    /// Split torrent maps of each swarm worker into this many shards, which
+    /// are cleaned one at a time (rounded up to nearest power of two,
+    /// maximum 65536)
+    pub torrent_map_shards: usize,
     /// Clean connections this often (seconds)
     pub connection_cleaning_interval: u64,
     /// Remove peers that have not announced for this long (seconds)
@@ -125,6 +232,7 @@ impl Default for CleaningConfig {
     fn default() -> Self {
         Self {
             torrent_cleaning_interval: 30,
+            torrent_map_shards: 16,
             connection_cleaning_interval: 60,
             max_peer_age: 1800,
             max_connection_idle: 180,
@@ -160,4 +268,19 @@ mod tests {
     use super::Config;
 
     ::aquatic_toml_config::gen_serialize_deserialize_test!(Config);
+
+    #[test]
+    fn test_request_paths() {
+        let mut config = Config::default();
+
+        assert_eq!(config.protocol.request_paths().unwrap(), Default::default());
+
+        config.protocol.announce_paths = vec!["/announce".into(), "/{key}/announce".into()];
+
+        assert_eq!(config.protocol.request_paths().unwrap().announce.len(), 2);
+
+        config.protocol.scrape_paths = vec!["scrape".into()];
+
+        assert!(config.protocol.request_paths().is_err());
+    }
 }
@@ -1,12 +1,21 @@
 use anyhow::Context;
 use aquatic_common::{
     access_list::update_access_list,
+    connection_limits::ConnectionLimiter,
     cpu_pinning::{
         glommio::{get_worker_placement, set_affinity_for_util_worker},
         WorkerIndex,
     },
+    ip_list::update_ip_list,
+    key_list::update_key_list,
     privileges::PrivilegeDropper,
-    rustls_config::create_rustls_config,
+    proxy_protocol::TrustedSources,
+    reverse_proxy::TrustedProxies,
+    rustls_config::{
+        create_rustls_config, spawn_rustls_config_file_watcher, update_rustls_config,
+        RustlsConfigArcSwap,
+    },
+    sharded_map::validate_num_shards,
     PanicSentinelWatcher, ServerStartInstant,
 };
 use common::State;
@@ -16,6 +25,7 @@ use signal_hook::{
     iterator::Signals,
 };
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::config::Config;
 
@@ -23,12 +33,14 @@ mod common;
 pub mod config;
 mod workers;
 
-pub const APP_NAME: &str = "aquatic_http: BitTorrent tracker (HTTP over TLS)";
+pub const APP_NAME: &str = "aquatic_http: HTTP BitTorrent tracker";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const SHARED_CHANNEL_SIZE: usize = 1024;
 
 pub fn run(config: Config) -> ::anyhow::Result<()> {
+    validate_num_shards(config.cleaning.torrent_map_shards)?;
+
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
     #[cfg(feature = "prometheus")]
@@ -46,9 +58,17 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             })?;
     }
 
-    let state = State::default();
+    let state = State {
+        trusted_proxies: Arc::new(TrustedProxies::new(&config.reverse_proxy)?),
+        proxy_protocol_sources: Arc::new(TrustedSources::new(&config.proxy_protocol)?),
+        request_paths: Arc::new(config.protocol.request_paths()?),
+        connection_limiter: Arc::new(ConnectionLimiter::new(&config.connection_limits)?),
+        ..Default::default()
+    };
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_ip_list(&config.ip_list, &state.ip_list)?;
+    update_key_list(&config.key_list, &state.key_list)?;
 
     let num_peers = config.socket_workers + config.swarm_workers;
 
@@ -57,10 +77,30 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
     let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
 
-    let tls_config = Arc::new(create_rustls_config(
-        &config.network.tls_certificate_path,
-        &config.network.tls_private_key_path,
-    )?);
+    let opt_tls_config = if config.network.enable_tls {
+        let tls_config = Arc::new(RustlsConfigArcSwap::from_pointee(
+            create_rustls_config(
+                &config.network.tls_certificate_path,
+                &config.network.tls_private_key_path,
+                &config.network.tls_sni_certificates,
+            )
+            .with_context(|| "create rustls config")?,
+        ));
+
+        if config.network.tls_file_check_interval != 0 {
+            spawn_rustls_config_file_watcher(
+                config.network.tls_certificate_path.clone(),
+                config.network.tls_private_key_path.clone(),
+                config.network.tls_sni_certificates.clone(),
+                Duration::from_secs(config.network.tls_file_check_interval),
+                tls_config.clone(),
+            )?;
+        }
+
+        Some(tls_config)
+    } else {
+        None
+    };
 
     let server_start_instant = ServerStartInstant::new();
 
@@ -70,7 +110,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let sentinel = sentinel.clone();
         let config = config.clone();
         let state = state.clone();
-        let tls_config = tls_config.clone();
+        let opt_tls_config = opt_tls_config.clone();
         let request_mesh_builder = request_mesh_builder.clone();
         let priv_dropper = priv_dropper.clone();
 
@@ -88,7 +128,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     sentinel,
                     config,
                     state,
-                    tls_config,
+                    opt_tls_config,
                     request_mesh_builder,
                     priv_dropper,
                     server_start_instant,
@@ -144,6 +184,17 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         match signal {
             SIGUSR1 => {
                 let _ = update_access_list(&config.access_list, &state.access_list);
+                let _ = update_ip_list(&config.ip_list, &state.ip_list);
+                let _ = update_key_list(&config.key_list, &state.key_list);
+
+                if let Some(tls_config) = opt_tls_config.as_ref() {
+                    let _ = update_rustls_config(
+                        &config.network.tls_certificate_path,
+                        &config.network.tls_private_key_path,
+                        &config.network.tls_sni_certificates,
+                        tls_config,
+                    );
+                }
             }
             SIGTERM => {
                 if sentinel_watcher.panic_was_triggered() {
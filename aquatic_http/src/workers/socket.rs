@@ -1,24 +1,29 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
-use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::access_list::{create_access_list_cache, AccessListCache};
+use aquatic_common::connection_limits::{ConnectionLimitExceeded, ConnectionPermit};
+use aquatic_common::ip_list::{create_ip_list_cache, IpListCache};
+use aquatic_common::key_list::{create_key_list_cache, KeyListCache};
 use aquatic_common::privileges::PrivilegeDropper;
-use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::proxy_protocol::read_client_addr;
+use aquatic_common::reverse_proxy::TrustedProxies;
+use aquatic_common::rustls_config::RustlsConfigArcSwap;
 use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ServerStartInstant};
 use aquatic_http_protocol::common::InfoHash;
-use aquatic_http_protocol::request::{Request, RequestParseError, ScrapeRequest};
+use aquatic_http_protocol::request::{Request, RequestParseError, RequestPaths, ScrapeRequest};
 use aquatic_http_protocol::response::{
     FailureResponse, Response, ScrapeResponse, ScrapeStatistics,
 };
 use either::Either;
 use futures::stream::FuturesUnordered;
 use futures_lite::{AsyncReadExt, AsyncWriteExt, StreamExt};
-use futures_rustls::server::TlsStream;
 use futures_rustls::TlsAcceptor;
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role, Senders};
 use glommio::channels::shared_channel::{self, SharedReceiver};
@@ -59,7 +64,7 @@ pub async fn run_socket_worker(
     _sentinel: PanicSentinel,
     config: Config,
     state: State,
-    tls_config: Arc<RustlsConfig>,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
     request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
     priv_dropper: PrivilegeDropper,
     server_start_instant: ServerStartInstant,
@@ -69,9 +74,22 @@ pub async fn run_socket_worker(
     WORKER_INDEX.with(|index| index.set(worker_index));
 
     let config = Rc::new(config);
-    let access_list = state.access_list;
+    let mut ip_list_cache = create_ip_list_cache(&state.ip_list);
+
+    let listener =
+        create_tcp_listener(&config, config.network.address).expect("create tcp listener");
+    let opt_plaintext_listener = if config.network.enable_plaintext_listener {
+        Some(
+            create_tcp_listener(&config, config.network.plaintext_listener_address)
+                .expect("create plaintext tcp listener"),
+        )
+    } else {
+        None
+    };
 
-    let listener = create_tcp_listener(&config, priv_dropper).expect("create tcp listener");
+    priv_dropper
+        .after_socket_creation()
+        .expect("drop privileges");
 
     let (request_senders, _) = request_mesh_builder.join(Role::Producer).await.unwrap();
     let request_senders = Rc::new(request_senders);
@@ -86,11 +104,61 @@ pub async fn run_socket_worker(
         )
     }));
 
-    let mut incoming = listener.incoming();
+    // Periodically update connection limiter metrics, which are shared by
+    // all socket workers
+    #[cfg(feature = "metrics")]
+    if worker_index == 0 {
+        TimerActionRepeat::repeat(enclose!((state) move || {
+            enclose!((state) move || async move {
+                update_connection_limiter_metrics(&state);
+
+                Some(Duration::from_secs(1))
+            })()
+        }));
+    }
+
+    // Accept connections from both listeners, passing on TLS config to use
+    let incoming = listener
+        .incoming()
+        .map(|stream| (stream, opt_tls_config.clone()));
+    let plaintext_incoming = futures::stream::iter(opt_plaintext_listener.as_ref())
+        .flat_map(|listener| listener.incoming())
+        .map(|stream| (stream, None));
 
-    while let Some(stream) = incoming.next().await {
+    let mut incoming = Box::pin(futures::stream::select(incoming, plaintext_incoming));
+
+    while let Some((stream, opt_tls_config)) = incoming.next().await {
         match stream {
             Ok(stream) => {
+                if config.ip_list.mode.is_on() {
+                    if let Ok(peer_addr) = stream.peer_addr() {
+                        let peer_addr = CanonicalSocketAddr::new(peer_addr);
+                        let ip = peer_addr.get().ip();
+
+                        // Connections from PROXY protocol sources are
+                        // checked after reading client address
+                        if !state.proxy_protocol_sources.contains(peer_addr)
+                            && !ip_list_cache.load().allows(config.ip_list.mode, ip)
+                        {
+                            ::log::debug!("Closed connection from {} because of IP list", ip);
+
+                            continue;
+                        }
+                    }
+                }
+
+                let connection_permit = match acquire_connection_permit(&state, &stream) {
+                    Ok(connection_permit) => connection_permit,
+                    Err(err) => {
+                        ::log::debug!("Closed connection: {}", err);
+
+                        #[cfg(feature = "metrics")]
+                        record_rejected_connection(err);
+
+                        continue;
+                    }
+                };
+
                 let key = connection_slab.borrow_mut().insert(ConnectionReference {
                     task_handle: None,
                     valid_until: ValidUntil::new(
@@ -99,7 +167,7 @@ pub async fn run_socket_worker(
                     ),
                 });
 
-                let task_handle = spawn_local(enclose!((config, access_list, request_senders, tls_config, connection_slab) async move {
+                let task_handle = spawn_local(enclose!((config, state, request_senders, opt_tls_config, connection_slab) async move {
                     let result = match stream.peer_addr() {
                         Ok(peer_addr) => {
                             let peer_addr = CanonicalSocketAddr::new(peer_addr);
@@ -115,14 +183,15 @@ pub async fn run_socket_worker(
                                 "worker_index" => worker_index.to_string(),
                             );
 
-                            let result = Connection::run(
+                            let result = run_connection(
                                 config,
-                                access_list,
+                                state,
                                 request_senders,
                                 server_start_instant,
                                 ConnectionId(key),
-                                tls_config,
+                                opt_tls_config,
                                 connection_slab.clone(),
+                                connection_permit,
                                 stream,
                                 peer_addr
                             ).await;
@@ -161,6 +230,58 @@ pub async fn run_socket_worker(
     }
 }
 
+/// Register connection with connection limiter. Per-IP limits don't apply to
+/// connections from proxies, since they are shared by many clients. For
+/// PROXY protocol sources, they are applied once client address is known
+/// (see [limit_client_ip]).
+fn acquire_connection_permit(
+    state: &State,
+    stream: &TcpStream,
+) -> Result<ConnectionPermit, ConnectionLimitExceeded> {
+    let opt_ip = stream
+        .peer_addr()
+        .ok()
+        .map(CanonicalSocketAddr::new)
+        .filter(|addr| {
+            !(state.proxy_protocol_sources.contains(*addr)
+                || state.trusted_proxies.is_trusted(*addr))
+        })
+        .map(|addr| addr.get().ip());
+
+    state.connection_limiter.try_acquire(opt_ip)
+}
+
+/// Apply per-IP connection limit to client address of connection from proxy
+fn limit_client_ip(connection_permit: &mut ConnectionPermit, ip: IpAddr) -> anyhow::Result<()> {
+    connection_permit.try_limit_ip(ip).map_err(|err| {
+        #[cfg(feature = "metrics")]
+        record_rejected_connection(err);
+
+        anyhow::anyhow!("closed connection from {}: {}", ip, err)
+    })
+}
+
+#[cfg(feature = "metrics")]
+fn record_rejected_connection(err: ConnectionLimitExceeded) {
+    ::metrics::increment_counter!(
+        "aquatic_rejected_connections_total",
+        "reason" => err.kind(),
+        "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+    );
+}
+
+#[cfg(feature = "metrics")]
+fn update_connection_limiter_metrics(state: &State) {
+    ::metrics::gauge!(
+        "aquatic_connection_limiter_connections",
+        state.connection_limiter.num_connections() as f64,
+    );
+    ::metrics::gauge!(
+        "aquatic_connection_limiter_ip_prefixes",
+        state.connection_limiter.num_ip_prefixes() as f64,
+    );
+}
+
 async fn clean_connections(
     config: Rc<Config>,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
@@ -187,46 +308,119 @@ async fn clean_connections(
     ))
 }
 
-struct Connection {
+async fn run_connection(
+    config: Rc<Config>,
+    state: State,
+    request_senders: Rc<Senders<ChannelRequest>>,
+    server_start_instant: ServerStartInstant,
+    connection_id: ConnectionId,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
+    connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
+    // Keeps connection counted until it is closed or task is cancelled
+    mut connection_permit: ConnectionPermit,
+    mut stream: TcpStream,
+    peer_addr: CanonicalSocketAddr,
+) -> anyhow::Result<()> {
+    let peer_addr = if state.proxy_protocol_sources.contains(peer_addr) {
+        let client_addr = read_client_addr(&mut stream, peer_addr).await?;
+        let ip = client_addr.get().ip();
+
+        if !state.ip_list.load().allows(config.ip_list.mode, ip) {
+            return Err(anyhow::anyhow!(
+                "closed connection from {} because of IP list",
+                ip
+            ));
+        }
+
+        limit_client_ip(&mut connection_permit, ip)?;
+
+        client_addr
+    } else {
+        peer_addr
+    };
+
+    if let Some(tls_config) = opt_tls_config {
+        // Use latest config, since it may have been reloaded
+        let tls_acceptor: TlsAcceptor = tls_config.load_full().into();
+
+        let stream = tls_acceptor.accept(stream).await?;
+
+        Connection::run(
+            config,
+            state,
+            request_senders,
+            server_start_instant,
+            connection_id,
+            connection_slab,
+            stream,
+            peer_addr,
+        )
+        .await
+    } else {
+        Connection::run(
+            config,
+            state,
+            request_senders,
+            server_start_instant,
+            connection_id,
+            connection_slab,
+            stream,
+            peer_addr,
+        )
+        .await
+    }
+}
+
+struct Connection<S> {
     config: Rc<Config>,
     access_list_cache: AccessListCache,
+    key_list_cache: KeyListCache,
+    ip_list_cache: IpListCache,
+    trusted_proxies: Arc<TrustedProxies>,
+    request_paths: Arc<RequestPaths>,
     request_senders: Rc<Senders<ChannelRequest>>,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
     server_start_instant: ServerStartInstant,
-    stream: TlsStream<TcpStream>,
+    stream: S,
+    /// Address of TCP connection peer
+    connection_addr: CanonicalSocketAddr,
+    /// Address of client making current request. Differs from
+    /// `connection_addr` if connection is from a trusted reverse proxy.
     peer_addr: CanonicalSocketAddr,
     connection_id: ConnectionId,
     request_buffer: [u8; REQUEST_BUFFER_SIZE],
     request_buffer_position: usize,
-    response_buffer: [u8; RESPONSE_BUFFER_SIZE],
+    /// Starts with response header. Grows to fit largest response body.
+    response_buffer: Vec<u8>,
 }
 
-impl Connection {
+impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin + 'static> Connection<S> {
     async fn run(
         config: Rc<Config>,
-        access_list: Arc<AccessListArcSwap>,
+        state: State,
         request_senders: Rc<Senders<ChannelRequest>>,
         server_start_instant: ServerStartInstant,
         connection_id: ConnectionId,
-        tls_config: Arc<RustlsConfig>,
         connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
-        stream: TcpStream,
+        stream: S,
         peer_addr: CanonicalSocketAddr,
     ) -> anyhow::Result<()> {
-        let tls_acceptor: TlsAcceptor = tls_config.into();
-        let stream = tls_acceptor.accept(stream).await?;
-
-        let mut response_buffer = [0; RESPONSE_BUFFER_SIZE];
+        let mut response_buffer = Vec::with_capacity(RESPONSE_BUFFER_SIZE);
 
-        response_buffer[..RESPONSE_HEADER.len()].copy_from_slice(&RESPONSE_HEADER);
+        response_buffer.extend_from_slice(&RESPONSE_HEADER);
 
         let mut conn = Connection {
             config: config.clone(),
-            access_list_cache: create_access_list_cache(&access_list),
+            access_list_cache: create_access_list_cache(&state.access_list),
+            key_list_cache: create_key_list_cache(&state.key_list),
+            ip_list_cache: create_ip_list_cache(&state.ip_list),
+            trusted_proxies: state.trusted_proxies,
+            request_paths: state.request_paths,
             request_senders: request_senders.clone(),
             connection_slab,
             server_start_instant,
             stream,
+            connection_addr: peer_addr,
             peer_addr,
             connection_id,
             request_buffer: [0; REQUEST_BUFFER_SIZE],
@@ -248,13 +442,10 @@ impl Connection {
 
             self.write_response(&response).await?;
 
-            if matches!(response, Response::Failure(_)) || !self.config.network.keep_alive {
-                let _ = self
-                    .stream
-                    .get_ref()
-                    .0
-                    .shutdown(std::net::Shutdown::Both)
-                    .await;
+            // Responses to denied requests are fine to send on kept-alive
+            // connections, which trusted reverse proxies rely on
+            if !self.config.network.keep_alive {
+                let _ = self.stream.close().await;
 
                 break;
             }
@@ -263,6 +454,9 @@ impl Connection {
         Ok(())
     }
 
+    /// Read request from stream. Returns failure response if request is not
+    /// allowed. If request is invalid, responds to it and closes connection,
+    /// since the rest of the data on it can't be parsed reliably.
     async fn read_request(&mut self) -> anyhow::Result<Either<FailureResponse, Request>> {
         self.request_buffer_position = 0;
 
@@ -282,18 +476,67 @@ impl Connection {
 
             self.request_buffer_position += bytes_read;
 
-            match Request::from_bytes(&self.request_buffer[..self.request_buffer_position]) {
+            let bytes = &self.request_buffer[..self.request_buffer_position];
+
+            let opt_header_name = self
+                .trusted_proxies
+                .is_trusted(self.connection_addr)
+                .then(|| self.trusted_proxies.header_name());
+
+            let parse_result =
+                Request::from_bytes_with_paths(bytes, &self.request_paths, opt_header_name).map(
+                    |(request, header_values)| {
+                        if opt_header_name.is_some() {
+                            self.peer_addr = self
+                                .trusted_proxies
+                                .client_addr(self.connection_addr, header_values);
+                        }
+
+                        request
+                    },
+                );
+
+            match parse_result {
                 Ok(request) => {
+                    // Connection address was checked when accepting
+                    // connection, but client address wasn't
+                    if opt_header_name.is_some() {
+                        let ip = self.peer_addr.get().ip();
+
+                        if !self
+                            .ip_list_cache
+                            .load()
+                            .allows(self.config.ip_list.mode, ip)
+                        {
+                            ::log::debug!("Denied request from {} because of IP list", ip);
+
+                            return Ok(Either::Left(FailureResponse::new("IP not allowed")));
+                        }
+                    }
+
                     return Ok(Either::Right(request));
                 }
                 Err(RequestParseError::Invalid(err)) => {
-                    let response = FailureResponse {
-                        failure_reason: "Invalid request".into(),
-                    };
+                    ::log::debug!("Invalid request: {}", err);
+
+                    #[cfg(feature = "metrics")]
+                    ::metrics::increment_counter!(
+                        "aquatic_invalid_requests_total",
+                        "kind" => err.kind(),
+                        "ip_version" => peer_addr_to_ip_version_str(&self.peer_addr),
+                        "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+                    );
 
-                    ::log::debug!("Invalid request: {:#}", err);
+                    let response = Response::Failure(FailureResponse::new(err.message()));
 
-                    return Ok(Either::Left(response));
+                    self.write_response(&response).await?;
+
+                    let _ = self.stream.close().await;
+
+                    return Err(anyhow::anyhow!(
+                        "closed connection after invalid request: {}",
+                        err.message()
+                    ));
                 }
                 Err(RequestParseError::NeedMoreData) => {
                     ::log::debug!(
@@ -334,6 +577,18 @@ impl Connection {
 
                 let info_hash = request.info_hash;
 
+                if !self
+                    .key_list_cache
+                    .load()
+                    .allows(self.config.key_list.mode, request.path_key.as_deref())
+                {
+                    let response = Response::Failure(FailureResponse {
+                        failure_reason: "Key not allowed".into(),
+                    });
+
+                    return Ok(response);
+                }
+
                 if self
                     .access_list_cache
                     .load()
@@ -462,21 +717,11 @@ impl Connection {
     async fn write_response(&mut self, response: &Response) -> anyhow::Result<()> {
         // Write body and final newline to response buffer
 
-        let mut position = RESPONSE_HEADER.len();
-
-        let body_len = response.write(&mut &mut self.response_buffer[position..])?;
+        self.response_buffer.truncate(RESPONSE_HEADER.len());
 
-        position += body_len;
+        let body_len = response.write(&mut self.response_buffer)?;
 
-        if position + 2 > self.response_buffer.len() {
-            ::log::error!("Response buffer is too short for response");
-
-            return Err(anyhow::anyhow!("Response buffer is too short for response"));
-        }
-
-        (&mut self.response_buffer[position..position + 2]).copy_from_slice(b"\r\n");
-
-        position += 2;
+        self.response_buffer.extend_from_slice(b"\r\n");
 
         let content_len = body_len + 2;
 
@@ -503,7 +748,7 @@ impl Connection {
 
         // Write buffer to stream
 
-        self.stream.write(&self.response_buffer[..position]).await?;
+        self.stream.write_all(&self.response_buffer).await?;
         self.stream.flush().await?;
 
         #[cfg(feature = "metrics")]
@@ -530,11 +775,8 @@ fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usi
     (info_hash.0[0] as usize) % config.swarm_workers
 }
 
-fn create_tcp_listener(
-    config: &Config,
-    priv_dropper: PrivilegeDropper,
-) -> anyhow::Result<TcpListener> {
-    let domain = if config.network.address.is_ipv4() {
+fn create_tcp_listener(config: &Config, address: SocketAddr) -> anyhow::Result<TcpListener> {
+    let domain = if address.is_ipv4() {
         socket2::Domain::IPV4
     } else {
         socket2::Domain::IPV6
@@ -553,14 +795,12 @@ fn create_tcp_listener(
         .with_context(|| "socket: set reuse port")?;
 
     socket
-        .bind(&config.network.address.into())
-        .with_context(|| format!("socket: bind to {}", config.network.address))?;
+        .bind(&address.into())
+        .with_context(|| format!("socket: bind to {}", address))?;
 
     socket
         .listen(config.network.tcp_backlog)
-        .with_context(|| format!("socket: listen on {}", config.network.address))?;
-
-    priv_dropper.after_socket_creation()?;
+        .with_context(|| format!("socket: listen on {}", address))?;
 
     Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) })
 }
@@ -1,5 +1,5 @@
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::rc::Rc;
 use std::sync::Arc;
@@ -16,8 +16,9 @@ use rand::SeedableRng;
 use smartstring::{LazyCompact, SmartString};
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
-use aquatic_common::{extract_response_peers, IndexMap, PanicSentinel};
-use aquatic_common::{AmortizedIndexMap, CanonicalSocketAddr};
+use aquatic_common::completed_downloads::CompletedDownloads;
+use aquatic_common::sharded_map::ShardedMap;
+use aquatic_common::{extract_response_peers, CanonicalSocketAddr, IndexMap, PanicSentinel};
 use aquatic_common::{SecondsSinceServerStart, ServerStartInstant, ValidUntil};
 use aquatic_http_protocol::common::*;
 use aquatic_http_protocol::request::*;
@@ -71,18 +72,22 @@ impl PeerStatus {
     }
 }
 
+/// Peer id is not stored here, since it is part of the peer map key
 #[derive(Debug, Clone, Copy)]
 pub struct Peer<I: Ip> {
     pub ip_address: I,
     pub port: u16,
     pub valid_until: ValidUntil,
     pub seeder: bool,
+    /// Time of last announce that got a response with peers
+    pub last_announce: SecondsSinceServerStart,
 }
 
 impl<I: Ip> Peer<I> {
-    pub fn to_response_peer(&self) -> ResponsePeer<I> {
+    pub fn to_response_peer(&self, opt_peer_id: Option<PeerId>) -> ResponsePeer<I> {
         ResponsePeer {
             ip_address: self.ip_address,
+            peer_id: opt_peer_id,
             port: self.port,
         }
     }
@@ -98,7 +103,14 @@ pub type PeerMap<I> = IndexMap<PeerMapKey<I>, Peer<I>>;
 
 pub struct TorrentData<I: Ip> {
     pub peers: PeerMap<I>,
+    /// Peers connecting over the other IP version that sent an address of
+    /// this IP version in their announce requests (BEP 7). Not included in
+    /// counts. Only used if `config.protocol.share_peers_across_ip_versions`
+    /// is set.
+    pub secondary_peers: PeerMap<I>,
     pub num_seeders: usize,
+    /// Only updated if `config.protocol.track_completed_downloads` is set
+    pub completed_downloads: CompletedDownloads<PeerId>,
 }
 
 impl<I: Ip> Default for TorrentData<I> {
@@ -106,7 +118,9 @@ impl<I: Ip> Default for TorrentData<I> {
     fn default() -> Self {
         Self {
             peers: Default::default(),
+            secondary_peers: Default::default(),
             num_seeders: 0,
+            completed_downloads: Default::default(),
         }
     }
 }
@@ -115,18 +129,134 @@ impl<I: Ip> TorrentData<I> {
     fn num_leechers(&self) -> usize {
         self.peers.len() - self.num_seeders
     }
+
+    fn scrape_statistics(&self) -> ScrapeStatistics {
+        ScrapeStatistics {
+            complete: self.num_seeders,
+            downloaded: self.completed_downloads.count(),
+            incomplete: self.num_leechers(),
+        }
+    }
+
+    /// Extract response peers, filling up with secondary peers if there are
+    /// too few regular ones. Secondary peers with the same peer id as a
+    /// regular peer (e.g., clients announcing over both IP versions) are
+    /// skipped.
+    fn extract_response_peers(
+        &self,
+        rng: &mut impl Rng,
+        max_num_peers_to_take: usize,
+        sender_peer_map_key: PeerMapKey<I>,
+        include_peer_id: bool,
+    ) -> Vec<ResponsePeer<I>> {
+        let mut response_peers = extract_response_peers(
+            rng,
+            &self.peers,
+            max_num_peers_to_take,
+            sender_peer_map_key.clone(),
+            |key, peer| peer.to_response_peer(include_peer_id.then_some(key.peer_id)),
+        );
+
+        let num_remaining = max_num_peers_to_take.saturating_sub(response_peers.len());
+
+        if num_remaining > 0 && !self.secondary_peers.is_empty() {
+            // Since there is room left, there are usually at most
+            // `max_num_peers_to_take + 1` regular peers, so collecting their
+            // ids is cheap
+            let peer_ids: HashSet<PeerId> = self.peers.keys().map(|key| key.peer_id).collect();
+
+            response_peers.extend(
+                extract_response_peers(
+                    rng,
+                    &self.secondary_peers,
+                    num_remaining,
+                    sender_peer_map_key,
+                    |key, peer| {
+                        (!peer_ids.contains(&key.peer_id))
+                            .then(|| peer.to_response_peer(include_peer_id.then_some(key.peer_id)))
+                    },
+                )
+                .into_iter()
+                .flatten(),
+            );
+        }
+
+        response_peers
+    }
 }
 
-pub type TorrentMap<I> = AmortizedIndexMap<InfoHash, TorrentData<I>>;
+/// Torrents indexed by info hash bytes
+pub type TorrentMap<I> = ShardedMap<TorrentData<I>>;
+
+/// Remove forbidden or inactive torrents and reclaim space in next shard
+fn clean_next_shard<I: Ip>(
+    config: &Config,
+    torrent_map: &mut TorrentMap<I>,
+    access_list_cache: &mut AccessListCache,
+    now: SecondsSinceServerStart,
+) {
+    torrent_map.clean_next_shard(|info_hash, torrent_data| {
+        if !access_list_cache
+            .load()
+            .allows(config.access_list.mode, info_hash)
+        {
+            return None;
+        }
+
+        let num_seeders = &mut torrent_data.num_seeders;
+
+        torrent_data.peers.retain(|_, peer| {
+            let keep = peer.valid_until.valid(now);
+
+            if (!keep) & peer.seeder {
+                *num_seeders -= 1;
+            }
+
+            keep
+        });
+
+        torrent_data
+            .secondary_peers
+            .retain(|_, peer| peer.valid_until.valid(now));
+
+        if torrent_data.peers.is_empty() && torrent_data.secondary_peers.is_empty() {
+            None
+        } else {
+            Some(torrent_data.peers.len())
+        }
+    });
+
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(
+        "aquatic_peers",
+        torrent_map.num_peers() as f64,
+        "ip_version" => I::ip_version_str(),
+        "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+    );
+}
 
-#[derive(Default)]
 pub struct TorrentMaps {
     pub ipv4: TorrentMap<Ipv4Addr>,
     pub ipv6: TorrentMap<Ipv6Addr>,
 }
 
 impl TorrentMaps {
-    pub fn clean(
+    pub fn new(config: &Config) -> Self {
+        Self {
+            ipv4: TorrentMap::new(config.cleaning.torrent_map_shards),
+            ipv6: TorrentMap::new(config.cleaning.torrent_map_shards),
+        }
+    }
+
+    /// Time between cleaning shards of each torrent map
+    pub fn shard_cleaning_interval(&self, config: &Config) -> Duration {
+        self.ipv4.shard_cleaning_interval(Duration::from_secs(
+            config.cleaning.torrent_cleaning_interval,
+        ))
+    }
+
+    /// Clean next shard of each torrent map
+    pub fn clean_next_shard(
         &mut self,
         config: &Config,
         access_list: &Arc<AccessListArcSwap>,
@@ -136,54 +266,8 @@ impl TorrentMaps {
 
         let now = server_start_instant.seconds_elapsed();
 
-        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv4, now);
-        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6, now);
-    }
-
-    fn clean_torrent_map<I: Ip>(
-        config: &Config,
-        access_list_cache: &mut AccessListCache,
-        torrent_map: &mut TorrentMap<I>,
-        now: SecondsSinceServerStart,
-    ) {
-        let mut total_num_peers = 0;
-
-        torrent_map.retain(|info_hash, torrent_data| {
-            if !access_list_cache
-                .load()
-                .allows(config.access_list.mode, &info_hash.0)
-            {
-                return false;
-            }
-
-            let num_seeders = &mut torrent_data.num_seeders;
-
-            torrent_data.peers.retain(|_, peer| {
-                let keep = peer.valid_until.valid(now);
-
-                if (!keep) & peer.seeder {
-                    *num_seeders -= 1;
-                }
-
-                keep
-            });
-
-            total_num_peers += torrent_data.peers.len() as u64;
-
-            !torrent_data.peers.is_empty()
-        });
-
-        let total_num_peers = total_num_peers as f64;
-
-        #[cfg(feature = "metrics")]
-        ::metrics::gauge!(
-            "aquatic_peers",
-            total_num_peers,
-            "ip_version" => I::ip_version_str(),
-            "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
-        );
-
-        torrent_map.shrink_to_fit();
+        clean_next_shard(config, &mut self.ipv4, &mut access_list_cache, now);
+        clean_next_shard(config, &mut self.ipv6, &mut access_list_cache, now);
     }
 }
 
@@ -200,15 +284,18 @@ pub async fn run_swarm_worker(
 
     let (_, mut request_receivers) = request_mesh_builder.join(Role::Consumer).await.unwrap();
 
-    let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
+    let torrents = Rc::new(RefCell::new(TorrentMaps::new(&config)));
     let access_list = state.access_list;
 
-    // Periodically clean torrents
+    // Periodically clean torrents, one shard at a time, spreading cleaning
+    // work evenly over the cleaning interval
+    let shard_cleaning_interval = torrents.borrow().shard_cleaning_interval(&config);
+
     TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
         enclose!((config, torrents, access_list) move || async move {
-            torrents.borrow_mut().clean(&config, &access_list, server_start_instant);
+            torrents.borrow_mut().clean_next_shard(&config, &access_list, server_start_instant);
 
-            Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
+            Some(shard_cleaning_interval)
         })()
     }));
 
@@ -217,11 +304,14 @@ pub async fn run_swarm_worker(
         server_start_instant,
         max_peer_age,
     )));
+    let now = Rc::new(Cell::new(server_start_instant.seconds_elapsed()));
+
+    // Periodically update peer_valid_until and now
+    TimerActionRepeat::repeat(enclose!((peer_valid_until, now) move || {
+        enclose!((peer_valid_until, now) move || async move {
+            now.set(server_start_instant.seconds_elapsed());
 
-    // Periodically update peer_valid_until
-    TimerActionRepeat::repeat(enclose!((peer_valid_until) move || {
-        enclose!((peer_valid_until) move || async move {
-            *peer_valid_until.borrow_mut() = ValidUntil::new(server_start_instant, max_peer_age);
+            *peer_valid_until.borrow_mut() = ValidUntil::new_with_now(now.get(), max_peer_age);
 
             Some(Duration::from_secs(1))
         })()
@@ -235,13 +325,13 @@ pub async fn run_swarm_worker(
 
             ::metrics::gauge!(
                 "aquatic_torrents",
-                torrents.ipv4.len() as f64,
+                torrents.ipv4.num_torrents() as f64,
                 "ip_version" => "4",
                 "worker_index" => worker_index.to_string(),
             );
             ::metrics::gauge!(
                 "aquatic_torrents",
-                torrents.ipv6.len() as f64,
+                torrents.ipv6.num_torrents() as f64,
                 "ip_version" => "6",
                 "worker_index" => worker_index.to_string(),
             );
@@ -257,6 +347,7 @@ pub async fn run_swarm_worker(
             config.clone(),
             torrents.clone(),
             peer_valid_until.clone(),
+            now.clone(),
             receiver,
         ))
         .detach();
@@ -273,6 +364,7 @@ async fn handle_request_stream<S>(
     config: Config,
     torrents: Rc<RefCell<TorrentMaps>>,
     peer_valid_until: Rc<RefCell<ValidUntil>>,
+    now: Rc<Cell<SecondsSinceServerStart>>,
     mut stream: S,
 ) where
     S: Stream<Item = ChannelRequest> + ::std::marker::Unpin,
@@ -291,6 +383,7 @@ async fn handle_request_stream<S>(
                     &mut rng,
                     &mut torrents.borrow_mut(),
                     peer_valid_until.borrow().to_owned(),
+                    now.get(),
                     peer_addr,
                     request,
                 );
@@ -320,70 +413,139 @@ pub fn handle_announce_request(
     rng: &mut impl Rng,
     torrent_maps: &mut TorrentMaps,
     valid_until: ValidUntil,
+    now: SecondsSinceServerStart,
     peer_addr: CanonicalSocketAddr,
     request: AnnounceRequest,
 ) -> AnnounceResponse {
+    let max_num_peers_to_take = match request.numwant {
+        Some(0) | None => config.protocol.max_peers,
+        Some(numwant) => numwant.min(config.protocol.max_peers),
+    };
+
+    let mut response = AnnounceResponse {
+        complete: 0,
+        incomplete: 0,
+        announce_interval: config.protocol.peer_announce_interval,
+        min_announce_interval: (config.protocol.min_announce_interval != 0)
+            .then_some(config.protocol.min_announce_interval),
+        peers: ResponsePeerListV4(vec![]),
+        peers6: ResponsePeerListV6(vec![]),
+        warning_message: None,
+        non_compact: !request.compact,
+    };
+
     match peer_addr.get().ip() {
         IpAddr::V4(peer_ip_address) => {
             let torrent_data: &mut TorrentData<Ipv4Addr> =
-                torrent_maps.ipv4.entry(request.info_hash).or_default();
+                torrent_maps.ipv4.get_or_insert_default(request.info_hash.0);
 
-            let (seeders, leechers, response_peers) = upsert_peer_and_get_response_peers(
+            let (seeders, leechers, opt_response_peers) = upsert_peer_and_get_response_peers(
                 config,
                 rng,
                 peer_ip_address,
                 torrent_data,
-                request,
+                &request,
                 valid_until,
+                now,
+                max_num_peers_to_take,
             );
 
-            let response = AnnounceResponse {
-                complete: seeders,
-                incomplete: leechers,
-                announce_interval: config.protocol.peer_announce_interval,
-                peers: ResponsePeerListV4(response_peers),
-                peers6: ResponsePeerListV6(vec![]),
-                warning_message: None,
-            };
+            let early_announce = opt_response_peers.is_none();
+
+            response.complete = seeders;
+            response.incomplete = leechers;
+            response.peers.0 = opt_response_peers.unwrap_or_default();
 
-            response
+            if config.protocol.share_peers_across_ip_versions {
+                let max_num_secondary_peers_to_take = if early_announce {
+                    0
+                } else {
+                    max_num_peers_to_take.saturating_sub(response.peers.0.len())
+                };
+
+                let (seeders, leechers, response_peers) =
+                    upsert_secondary_peer_and_get_response_peers(
+                        rng,
+                        request
+                            .ipv6
+                            .filter(|_| config.protocol.accept_other_ip_version_addresses),
+                        &mut torrent_maps.ipv6,
+                        &request,
+                        valid_until,
+                        now,
+                        max_num_secondary_peers_to_take,
+                    );
+
+                response.complete += seeders;
+                response.incomplete += leechers;
+                response.peers6.0 = response_peers;
+            }
         }
         IpAddr::V6(peer_ip_address) => {
             let torrent_data: &mut TorrentData<Ipv6Addr> =
-                torrent_maps.ipv6.entry(request.info_hash).or_default();
+                torrent_maps.ipv6.get_or_insert_default(request.info_hash.0);
 
-            let (seeders, leechers, response_peers) = upsert_peer_and_get_response_peers(
+            let (seeders, leechers, opt_response_peers) = upsert_peer_and_get_response_peers(
                 config,
                 rng,
                 peer_ip_address,
                 torrent_data,
-                request,
+                &request,
                 valid_until,
+                now,
+                max_num_peers_to_take,
             );
 
-            let response = AnnounceResponse {
-                complete: seeders,
-                incomplete: leechers,
-                announce_interval: config.protocol.peer_announce_interval,
-                peers: ResponsePeerListV4(vec![]),
-                peers6: ResponsePeerListV6(response_peers),
-                warning_message: None,
-            };
+            let early_announce = opt_response_peers.is_none();
 
-            response
+            response.complete = seeders;
+            response.incomplete = leechers;
+            response.peers6.0 = opt_response_peers.unwrap_or_default();
+
+            if config.protocol.share_peers_across_ip_versions {
+                let max_num_secondary_peers_to_take = if early_announce {
+                    0
+                } else {
+                    max_num_peers_to_take.saturating_sub(response.peers6.0.len())
+                };
+
+                let (seeders, leechers, response_peers) =
+                    upsert_secondary_peer_and_get_response_peers(
+                        rng,
+                        request
+                            .ipv4
+                            .filter(|_| config.protocol.accept_other_ip_version_addresses),
+                        &mut torrent_maps.ipv4,
+                        &request,
+                        valid_until,
+                        now,
+                        max_num_secondary_peers_to_take,
+                    );
+
+                response.complete += seeders;
+                response.incomplete += leechers;
+                response.peers.0 = response_peers;
+            }
         }
     }
+
+    response
 }
 
-/// Insert/update peer. Return num_seeders, num_leechers and response peers
+/// Insert/update peer. Return num_seeders, num_leechers and response peers,
+/// or None instead of response peers if the peer announced again too soon
+/// (see `config.protocol.min_announce_interval`)
+#[allow(clippy::too_many_arguments)]
 pub fn upsert_peer_and_get_response_peers<I: Ip>(
     config: &Config,
     rng: &mut impl Rng,
     peer_ip_address: I,
     torrent_data: &mut TorrentData<I>,
-    request: AnnounceRequest,
+    request: &AnnounceRequest,
     valid_until: ValidUntil,
-) -> (usize, usize, Vec<ResponsePeer<I>>) {
+    now: SecondsSinceServerStart,
+    max_num_peers_to_take: usize,
+) -> (usize, usize, Option<Vec<ResponsePeer<I>>>) {
     // Insert/update/remove peer who sent this request
 
     let peer_status =
@@ -391,6 +553,7 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
 
     let ip_or_key = request
         .key
+        .clone()
         .map(Either::Right)
         .unwrap_or_else(|| Either::Left(peer_ip_address));
 
@@ -399,6 +562,26 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         ip_or_key,
     };
 
+    // Announces without events are early if they come sooner than the
+    // minimum interval after the last announce that got peers. Early
+    // announces still update peer state, but the last announce time is kept.
+    let min_announce_interval = config.protocol.min_announce_interval;
+
+    let opt_early_last_announce =
+        if min_announce_interval != 0 && request.event == AnnounceEvent::Empty {
+            torrent_data
+                .peers
+                .get(&peer_map_key)
+                .map(|peer| peer.last_announce)
+                .filter(|last_announce| {
+                    (now.seconds_since(*last_announce) as usize) < min_announce_interval
+                })
+        } else {
+            None
+        };
+
+    let last_announce = opt_early_last_announce.unwrap_or(now);
+
     let opt_removed_peer = match peer_status {
         PeerStatus::Leeching => {
             let peer = Peer {
@@ -406,6 +589,7 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
                 port: request.port,
                 valid_until,
                 seeder: false,
+                last_announce,
             };
 
             torrent_data.peers.insert(peer_map_key.clone(), peer)
@@ -418,6 +602,7 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
                 port: request.port,
                 valid_until,
                 seeder: true,
+                last_announce,
             };
 
             torrent_data.peers.insert(peer_map_key.clone(), peer)
@@ -429,6 +614,15 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         torrent_data.num_seeders -= 1;
     }
 
+    if config.protocol.track_completed_downloads {
+        torrent_data.completed_downloads.update(
+            request.peer_id,
+            peer_status == PeerStatus::Seeding,
+            opt_removed_peer.as_ref().map(|peer| peer.seeder),
+            request.event == AnnounceEvent::Completed,
+        );
+    }
+
     #[cfg(feature = "metrics")]
     match peer_status {
         PeerStatus::Stopped if opt_removed_peer.is_some() => {
@@ -450,20 +644,108 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         _ => {}
     }
 
+    if opt_early_last_announce.is_some() {
+        #[cfg(feature = "metrics")]
+        ::metrics::increment_counter!(
+            "aquatic_early_announces_total",
+            "ip_version" => I::ip_version_str(),
+            "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+        );
+
+        return (torrent_data.num_seeders, torrent_data.num_leechers(), None);
+    }
+
     let response_peers = if let PeerStatus::Stopped = peer_status {
         Vec::new()
     } else {
-        let max_num_peers_to_take = match request.numwant {
-            Some(0) | None => config.protocol.max_peers,
-            Some(numwant) => numwant.min(config.protocol.max_peers),
-        };
-
-        extract_response_peers(
+        torrent_data.extract_response_peers(
             rng,
-            &torrent_data.peers,
             max_num_peers_to_take,
             peer_map_key,
-            Peer::to_response_peer,
+            include_peer_id(request),
+        )
+    };
+
+    (
+        torrent_data.num_seeders,
+        torrent_data.num_leechers(),
+        Some(response_peers),
+    )
+}
+
+/// Insert/update/remove peer in secondary peer map of swarm of IP version
+/// other than that of connection, using address of that IP version sent in
+/// request (BEP 7). Return num_seeders, num_leechers and response peers.
+fn upsert_secondary_peer_and_get_response_peers<I: Ip>(
+    rng: &mut impl Rng,
+    opt_peer_ip_address: Option<I>,
+    torrent_map: &mut TorrentMap<I>,
+    request: &AnnounceRequest,
+    valid_until: ValidUntil,
+    now: SecondsSinceServerStart,
+    max_num_peers_to_take: usize,
+) -> (usize, usize, Vec<ResponsePeer<I>>) {
+    let peer_status =
+        PeerStatus::from_event_and_bytes_left(request.event, Some(request.bytes_left));
+
+    let opt_ip_or_key = match (request.key.as_ref(), opt_peer_ip_address) {
+        (Some(key), _) => Some(Either::Right(key.clone())),
+        (None, Some(peer_ip_address)) => Some(Either::Left(peer_ip_address)),
+        (None, None) => None,
+    };
+    let opt_peer_map_key = opt_ip_or_key.map(|ip_or_key| PeerMapKey {
+        peer_id: request.peer_id,
+        ip_or_key,
+    });
+
+    let opt_torrent_data = match (peer_status, opt_peer_ip_address) {
+        (PeerStatus::Leeching | PeerStatus::Seeding, Some(_)) => {
+            Some(torrent_map.get_or_insert_default(request.info_hash.0))
+        }
+        _ => torrent_map.get_mut(&request.info_hash.0),
+    };
+
+    let torrent_data = if let Some(torrent_data) = opt_torrent_data {
+        torrent_data
+    } else {
+        return (0, 0, Vec::new());
+    };
+
+    match (peer_status, opt_peer_ip_address, opt_peer_map_key.as_ref()) {
+        (PeerStatus::Stopped, _, Some(peer_map_key)) => {
+            torrent_data.secondary_peers.remove(peer_map_key);
+        }
+        (PeerStatus::Leeching | PeerStatus::Seeding, Some(peer_ip_address), Some(peer_map_key)) => {
+            let peer = Peer {
+                ip_address: peer_ip_address,
+                port: request.port,
+                valid_until,
+                seeder: peer_status == PeerStatus::Seeding,
+                last_announce: now,
+            };
+
+            torrent_data
+                .secondary_peers
+                .insert(peer_map_key.clone(), peer);
+        }
+        _ => (),
+    }
+
+    let response_peers = if peer_status == PeerStatus::Stopped || max_num_peers_to_take == 0 {
+        Vec::new()
+    } else {
+        // Without key or address, the sender can't be identified in this
+        // swarm, so use a key that will practically never match
+        let sender_peer_map_key = opt_peer_map_key.unwrap_or_else(|| PeerMapKey {
+            peer_id: request.peer_id,
+            ip_or_key: Either::Right(Default::default()),
+        });
+
+        torrent_data.extract_response_peers(
+            rng,
+            max_num_peers_to_take,
+            sender_peer_map_key,
+            include_peer_id(request),
         )
     };
 
@@ -474,6 +756,11 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
     )
 }
 
+/// Only include peer ids in non-compact responses if not asked not to
+fn include_peer_id(request: &AnnounceRequest) -> bool {
+    !(request.compact || request.no_peer_id)
+}
+
 pub fn handle_scrape_request(
     config: &Config,
     torrent_maps: &mut TorrentMaps,
@@ -490,34 +777,36 @@ pub fn handle_scrape_request(
     };
 
     let peer_ip = peer_addr.get().ip();
+    let share_peers = config.protocol.share_peers_across_ip_versions;
+
+    let include_ipv4 = share_peers || peer_ip.is_ipv4();
+    let include_ipv6 = share_peers || peer_ip.is_ipv6();
 
     // If request.info_hashes is empty, don't return scrape for all
     // torrents, even though reference server does it. It is too expensive.
-    if peer_ip.is_ipv4() {
-        for info_hash in request.info_hashes.into_iter().take(num_to_take) {
-            if let Some(torrent_data) = torrent_maps.ipv4.get(&info_hash) {
-                let stats = ScrapeStatistics {
-                    complete: torrent_data.num_seeders,
-                    downloaded: 0, // No implementation planned
-                    incomplete: torrent_data.num_leechers(),
-                };
-
-                response.files.insert(info_hash, stats);
-            }
-        }
-    } else {
-        for info_hash in request.info_hashes.into_iter().take(num_to_take) {
-            if let Some(torrent_data) = torrent_maps.ipv6.get(&info_hash) {
-                let stats = ScrapeStatistics {
-                    complete: torrent_data.num_seeders,
-                    downloaded: 0, // No implementation planned
-                    incomplete: torrent_data.num_leechers(),
-                };
+    for info_hash in request.info_hashes.into_iter().take(num_to_take) {
+        let opt_ipv4_stats = include_ipv4
+            .then(|| torrent_maps.ipv4.get(&info_hash.0))
+            .flatten()
+            .map(TorrentData::scrape_statistics);
+        let opt_ipv6_stats = include_ipv6
+            .then(|| torrent_maps.ipv6.get(&info_hash.0))
+            .flatten()
+            .map(TorrentData::scrape_statistics);
+
+        let opt_stats = [opt_ipv4_stats, opt_ipv6_stats]
+            .into_iter()
+            .flatten()
+            .reduce(|a, b| ScrapeStatistics {
+                complete: a.complete + b.complete,
+                downloaded: a.downloaded + b.downloaded,
+                incomplete: a.incomplete + b.incomplete,
+            });
 
-                response.files.insert(info_hash, stats);
-            }
+        if let Some(stats) = opt_stats {
+            response.files.insert(info_hash, stats);
         }
-    };
+    }
 
     response
 }
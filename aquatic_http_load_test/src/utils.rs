@@ -49,6 +49,11 @@ fn create_announce_request(config: &Config, state: &LoadTestState, rng: &mut imp
         port: rng.gen(),
         bytes_uploaded: 0,
         bytes_downloaded: 0,
+        compact: true,
+        no_peer_id: false,
+        ipv4: None,
+        ipv6: None,
+        path_key: None,
     })
 }
 
@@ -1,6 +1,6 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::privileges::PrivilegeConfig;
+use aquatic_common::{privileges::PrivilegeConfig, rustls_config::SniCertificateConfig};
 use aquatic_toml_config::TomlConfig;
 use serde::Deserialize;
 
@@ -54,10 +54,21 @@ impl aquatic_common::cli::Config for Config {
 pub struct NetworkConfig {
     /// Bind to this address
     pub address: SocketAddr,
-    /// Path to TLS certificate (DER-encoded X.509)
+    /// Path to TLS certificate chain (PEM-encoded X.509)
     pub tls_certificate_path: PathBuf,
-    /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
+    /// Path to TLS private key (PEM-encoded PKCS#8, PKCS#1 or SEC1 key)
     pub tls_private_key_path: PathBuf,
+    /// Additional certificates for TLS connections to specific server names
+    /// (SNI), as tables with keys `server_name`, `certificate_path` and
+    /// `private_key_path`. Server names may be wildcards matching a single
+    /// label (e.g., "*.example.com"). The certificate in
+    /// `tls_certificate_path` is used for other connections.
+    pub tls_sni_certificates: Vec<SniCertificateConfig>,
+    /// Check TLS certificate and private key files for changes this often
+    /// (seconds) and reload them once they have been left unmodified for a
+    /// full interval. Set to 0 to disable. Files are also reloaded on
+    /// SIGUSR1.
+    pub tls_file_check_interval: u64,
     pub keep_alive: bool,
 }
 
@@ -67,6 +78,8 @@ impl Default for NetworkConfig {
             address: SocketAddr::from(([0, 0, 0, 0], 3000)),
             tls_certificate_path: "".into(),
             tls_private_key_path: "".into(),
+            tls_sni_certificates: Vec::new(),
+            tls_file_check_interval: 0,
             keep_alive: true,
         }
     }
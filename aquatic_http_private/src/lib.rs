@@ -2,15 +2,22 @@ mod common;
 pub mod config;
 mod workers;
 
-use std::{collections::VecDeque, sync::Arc};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
 
 use aquatic_common::{
-    privileges::PrivilegeDropper, rustls_config::create_rustls_config, PanicSentinelWatcher,
-    ServerStartInstant,
+    privileges::PrivilegeDropper,
+    rustls_config::{
+        create_rustls_config, spawn_rustls_config_file_watcher, update_rustls_config,
+        RustlsConfigArcSwap,
+    },
+    PanicSentinelWatcher, ServerStartInstant,
 };
 use common::ChannelRequestSender;
 use dotenv::dotenv;
-use signal_hook::{consts::SIGTERM, iterator::Signals};
+use signal_hook::{
+    consts::{SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
 use tokio::sync::mpsc::channel;
 
 use config::Config;
@@ -19,14 +26,25 @@ pub const APP_NAME: &str = "aquatic_http_private: private HTTP/TLS BitTorrent tr
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub fn run(config: Config) -> anyhow::Result<()> {
-    let mut signals = Signals::new([SIGTERM])?;
+    let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
     dotenv().ok();
 
-    let tls_config = Arc::new(create_rustls_config(
+    let tls_config = Arc::new(RustlsConfigArcSwap::from_pointee(create_rustls_config(
         &config.network.tls_certificate_path,
         &config.network.tls_private_key_path,
-    )?);
+        &config.network.tls_sni_certificates,
+    )?));
+
+    if config.network.tls_file_check_interval != 0 {
+        spawn_rustls_config_file_watcher(
+            config.network.tls_certificate_path.clone(),
+            config.network.tls_private_key_path.clone(),
+            config.network.tls_sni_certificates.clone(),
+            Duration::from_secs(config.network.tls_file_check_interval),
+            tls_config.clone(),
+        )?;
+    }
 
     let mut request_senders = Vec::new();
     let mut request_receivers = VecDeque::new();
@@ -88,6 +106,14 @@ pub fn run(config: Config) -> anyhow::Result<()> {
 
     for signal in &mut signals {
         match signal {
+            SIGUSR1 => {
+                let _ = update_rustls_config(
+                    &config.network.tls_certificate_path,
+                    &config.network.tls_private_key_path,
+                    &config.network.tls_sni_certificates,
+                    &tls_config,
+                );
+            }
             SIGTERM => {
                 if sentinel_watcher.panic_was_triggered() {
                     return Err(anyhow::anyhow!("worker thread panicked"));
@@ -8,7 +8,9 @@ use std::{
 };
 
 use anyhow::Context;
-use aquatic_common::{privileges::PrivilegeDropper, rustls_config::RustlsConfig, PanicSentinel};
+use aquatic_common::{
+    privileges::PrivilegeDropper, rustls_config::RustlsConfigArcSwap, PanicSentinel,
+};
 use axum::{extract::connect_info::Connected, routing::get, Extension, Router};
 use hyper::server::conn::AddrIncoming;
 use sqlx::mysql::MySqlPoolOptions;
@@ -25,7 +27,7 @@ impl<'a> Connected<&'a tls::TlsStream> for SocketAddr {
 pub fn run_socket_worker(
     _sentinel: PanicSentinel,
     config: Config,
-    tls_config: Arc<RustlsConfig>,
+    tls_config: Arc<RustlsConfigArcSwap>,
     request_sender: ChannelRequestSender,
     priv_dropper: PrivilegeDropper,
 ) -> anyhow::Result<()> {
@@ -42,7 +44,7 @@ pub fn run_socket_worker(
 
 async fn run_app(
     config: Config,
-    tls_config: Arc<RustlsConfig>,
+    tls_config: Arc<RustlsConfigArcSwap>,
     tcp_listener: TcpListener,
     request_sender: ChannelRequestSender,
 ) -> anyhow::Result<()> {
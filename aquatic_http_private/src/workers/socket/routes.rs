@@ -31,7 +31,11 @@ pub async fn announce(
     let query = query.ok_or_else(|| FailureResponse::new("Empty query string"))?;
 
     let request = AnnounceRequest::from_query_string(&query)
-        .map_err(|_| FailureResponse::new("Malformed request"))?;
+        .map_err(|err| FailureResponse::new(err.message()))?;
+
+    if !request.compact {
+        return Err(FailureResponse::new("Only compact responses are supported"));
+    }
 
     let swarm_worker_index = RequestWorkerIndex::from_info_hash(&config, request.info_hash);
     let opt_user_agent = opt_user_agent.map(|header| header.as_str().to_owned());
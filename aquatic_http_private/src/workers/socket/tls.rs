@@ -21,6 +21,7 @@
 // ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF
 // THIS SOFTWARE.
 
+use aquatic_common::rustls_config::RustlsConfigArcSwap;
 use core::task::{Context, Poll};
 use futures_util::ready;
 use hyper::server::accept::Accept;
@@ -123,12 +124,13 @@ impl AsyncWrite for TlsStream {
 }
 
 pub struct TlsAcceptor {
-    config: Arc<ServerConfig>,
+    /// Loaded for each new connection, since it may be reloaded
+    config: Arc<RustlsConfigArcSwap>,
     incoming: AddrIncoming,
 }
 
 impl TlsAcceptor {
-    pub fn new(config: Arc<ServerConfig>, incoming: AddrIncoming) -> TlsAcceptor {
+    pub fn new(config: Arc<RustlsConfigArcSwap>, incoming: AddrIncoming) -> TlsAcceptor {
         TlsAcceptor { config, incoming }
     }
 }
@@ -143,7 +145,7 @@ impl Accept for TlsAcceptor {
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
         let pin = self.get_mut();
         match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
-            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.clone())))),
+            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.load_full())))),
             Some(Err(e)) => Poll::Ready(Some(Err(e))),
             None => Poll::Ready(None),
         }
@@ -46,6 +46,7 @@ impl<I: Ip> Peer<I> {
     pub fn to_response_peer(&self) -> ResponsePeer<I> {
         ResponsePeer {
             ip_address: self.ip_address,
+            peer_id: None,
             port: self.port,
         }
     }
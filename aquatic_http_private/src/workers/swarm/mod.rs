@@ -115,9 +115,11 @@ fn handle_announce_request(
                 complete: seeders,
                 incomplete: leechers,
                 announce_interval: config.protocol.peer_announce_interval,
+                min_announce_interval: None,
                 peers: ResponsePeerListV4(response_peers),
                 peers6: ResponsePeerListV6(vec![]),
                 warning_message: None,
+                non_compact: false,
             };
 
             response
@@ -139,9 +141,11 @@ fn handle_announce_request(
                 complete: seeders,
                 incomplete: leechers,
                 announce_interval: config.protocol.peer_announce_interval,
+                min_announce_interval: None,
                 peers: ResponsePeerListV4(vec![]),
                 peers6: ResponsePeerListV6(response_peers),
                 warning_message: None,
+                non_compact: false,
             };
 
             response
@@ -209,7 +213,7 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         &torrent_data.peers,
         max_num_peers_to_take,
         peer_map_key,
-        Peer::to_response_peer,
+        |_, peer| peer.to_response_peer(),
     );
 
     (
@@ -11,17 +11,20 @@ pub fn bench(c: &mut Criterion) {
     for i in 0..100 {
         peers.push(ResponsePeer {
             ip_address: Ipv4Addr::new(127, 0, 0, i),
+            peer_id: None,
             port: i as u16,
         })
     }
 
     let announce_response = AnnounceResponse {
         announce_interval: 120,
+        min_announce_interval: None,
         complete: 100,
         incomplete: 500,
         peers: ResponsePeerListV4(peers),
         peers6: ResponsePeerListV6(Vec::new()),
         warning_message: None,
+        non_compact: false,
     };
 
     let response = Response::Announce(announce_response);
@@ -1,6 +1,8 @@
+use std::borrow::Cow;
 use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
 
-use anyhow::Context;
 use smartstring::{LazyCompact, SmartString};
 
 use super::common::*;
@@ -18,6 +20,18 @@ pub struct AnnounceRequest {
     /// Number of response peers wanted
     pub numwant: Option<usize>,
     pub key: Option<SmartString<LazyCompact>>,
+    /// Compact (BEP 23) response peer lists wanted. Defaults to true if not
+    /// sent.
+    pub compact: bool,
+    /// Peer ids should be left out of non-compact response peer lists
+    pub no_peer_id: bool,
+    /// IPv4 address sent in `ipv4` parameter (BEP 7)
+    pub ipv4: Option<Ipv4Addr>,
+    /// IPv6 address sent in `ipv6` parameter (BEP 7)
+    pub ipv6: Option<Ipv6Addr>,
+    /// Key captured from request path (see [`PathTemplate`]). Not included
+    /// when writing request, use `url_suffix` instead.
+    pub path_key: Option<SmartString<LazyCompact>>,
 }
 
 impl AnnounceRequest {
@@ -59,15 +73,32 @@ impl AnnounceRequest {
             output.write_all(::urlencoding::encode(key.as_str()).as_bytes())?;
         }
 
-        // Always ask for compact responses to ease load testing of non-aquatic trackers
-        output.write_all(b"&compact=1")?;
+        if self.compact {
+            output.write_all(b"&compact=1")?;
+        } else {
+            output.write_all(b"&compact=0")?;
+        }
+
+        if self.no_peer_id {
+            output.write_all(b"&no_peer_id=1")?;
+        }
+
+        if let Some(ipv4) = self.ipv4 {
+            output.write_all(b"&ipv4=")?;
+            output.write_all(ipv4.to_string().as_bytes())?;
+        }
+
+        if let Some(ipv6) = self.ipv6 {
+            output.write_all(b"&ipv6=")?;
+            output.write_all(::urlencoding::encode(&ipv6.to_string()).as_bytes())?;
+        }
 
         output.write_all(b" HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
 
         Ok(())
     }
 
-    pub fn from_query_string(query_string: &str) -> anyhow::Result<Self> {
+    pub fn from_query_string(query_string: &str) -> Result<Self, InvalidRequest> {
         // -- Parse key-value pairs
 
         let mut opt_info_hash = None;
@@ -79,6 +110,10 @@ impl AnnounceRequest {
         let mut event = AnnounceEvent::default();
         let mut opt_numwant = None;
         let mut opt_key = None;
+        let mut compact = true;
+        let mut no_peer_id = false;
+        let mut opt_ipv4 = None;
+        let mut opt_ipv6 = None;
 
         let query_string_bytes = query_string.as_bytes();
 
@@ -90,56 +125,63 @@ impl AnnounceRequest {
 
             let key = query_string
                 .get(position..equal_sign_index)
-                .with_context(|| format!("no key at {}..{}", position, equal_sign_index))?;
+                .ok_or(InvalidRequest::MalformedQueryString)?;
             let value = query_string
                 .get(equal_sign_index + 1..segment_end)
-                .with_context(|| {
-                    format!("no value at {}..{}", equal_sign_index + 1, segment_end)
-                })?;
+                .ok_or(InvalidRequest::MalformedQueryString)?;
 
             match key {
                 "info_hash" => {
-                    let value = urldecode_20_bytes(value)?;
+                    let value = decode_20_bytes(value, "info_hash")?;
 
                     opt_info_hash = Some(InfoHash(value));
                 }
                 "peer_id" => {
-                    let value = urldecode_20_bytes(value)?;
+                    let value = decode_20_bytes(value, "peer_id")?;
 
                     opt_peer_id = Some(PeerId(value));
                 }
                 "port" => {
-                    opt_port = Some(value.parse::<u16>().with_context(|| "parse port")?);
+                    opt_port = Some(parse_number(value, "port")?);
                 }
                 "left" => {
-                    opt_bytes_left = Some(value.parse::<usize>().with_context(|| "parse left")?);
+                    opt_bytes_left = Some(parse_number(value, "left")?);
                 }
                 "uploaded" => {
-                    opt_bytes_uploaded =
-                        Some(value.parse::<usize>().with_context(|| "parse uploaded")?);
+                    opt_bytes_uploaded = Some(parse_number(value, "uploaded")?);
                 }
                 "downloaded" => {
-                    opt_bytes_downloaded =
-                        Some(value.parse::<usize>().with_context(|| "parse downloaded")?);
+                    opt_bytes_downloaded = Some(parse_number(value, "downloaded")?);
                 }
                 "event" => {
                     event = value
                         .parse::<AnnounceEvent>()
-                        .map_err(|err| anyhow::anyhow!("invalid event: {}", err))?;
+                        .map_err(|_| InvalidRequest::InvalidEvent)?;
                 }
                 "compact" => {
-                    if value != "1" {
-                        return Err(anyhow::anyhow!("compact set, but not to 1"));
-                    }
+                    compact = parse_bool(value, "compact")?;
+                }
+                "no_peer_id" => {
+                    no_peer_id = parse_bool(value, "no_peer_id")?;
                 }
                 "numwant" => {
-                    opt_numwant = Some(value.parse::<usize>().with_context(|| "parse numwant")?);
+                    opt_numwant = Some(parse_number(value, "numwant")?);
+                }
+                "ipv4" => {
+                    opt_ipv4 = parse_ipv4(value);
+                }
+                "ipv6" => {
+                    opt_ipv6 = parse_ipv6(value);
                 }
                 "key" => {
                     if value.len() > 100 {
-                        return Err(anyhow::anyhow!("'key' is too long"));
+                        return Err(InvalidRequest::InvalidParameter("key"));
                     }
-                    opt_key = Some(::urlencoding::decode(value)?.into());
+                    opt_key = Some(
+                        ::urlencoding::decode(value)
+                            .map_err(|_| InvalidRequest::InvalidPercentEncoding("key"))?
+                            .into(),
+                    );
                 }
                 k => {
                     ::log::debug!("ignored unrecognized key: {}", k)
@@ -154,19 +196,106 @@ impl AnnounceRequest {
         }
 
         Ok(AnnounceRequest {
-            info_hash: opt_info_hash.with_context(|| "no info_hash")?,
-            peer_id: opt_peer_id.with_context(|| "no peer_id")?,
-            port: opt_port.with_context(|| "no port")?,
-            bytes_uploaded: opt_bytes_uploaded.with_context(|| "no uploaded")?,
-            bytes_downloaded: opt_bytes_downloaded.with_context(|| "no downloaded")?,
-            bytes_left: opt_bytes_left.with_context(|| "no left")?,
+            info_hash: opt_info_hash.ok_or(InvalidRequest::MissingParameter("info_hash"))?,
+            peer_id: opt_peer_id.ok_or(InvalidRequest::MissingParameter("peer_id"))?,
+            port: opt_port.ok_or(InvalidRequest::MissingParameter("port"))?,
+            bytes_uploaded: opt_bytes_uploaded
+                .ok_or(InvalidRequest::MissingParameter("uploaded"))?,
+            bytes_downloaded: opt_bytes_downloaded
+                .ok_or(InvalidRequest::MissingParameter("downloaded"))?,
+            bytes_left: opt_bytes_left.ok_or(InvalidRequest::MissingParameter("left"))?,
             event,
             numwant: opt_numwant,
             key: opt_key,
+            compact,
+            no_peer_id,
+            ipv4: opt_ipv4,
+            ipv6: opt_ipv6,
+            path_key: None,
         })
     }
 }
 
+fn parse_bool(value: &str, name: &'static str) -> Result<bool, InvalidRequest> {
+    match value {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        _ => Err(InvalidRequest::InvalidParameter(name)),
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str, name: &'static str) -> Result<T, InvalidRequest> {
+    value
+        .parse::<T>()
+        .map_err(|_| InvalidRequest::InvalidParameter(name))
+}
+
+fn decode_20_bytes(value: &str, name: &'static str) -> Result<[u8; 20], InvalidRequest> {
+    urldecode_20_bytes(value).map_err(|err| match err {
+        UrlDecodeError::InvalidPercentEncoding => InvalidRequest::InvalidPercentEncoding(name),
+        UrlDecodeError::InvalidLength => InvalidRequest::InvalidLength(name),
+    })
+}
+
+/// Parse BEP 7 `ipv4` parameter value, optionally including port. Invalid
+/// values are ignored, since some clients send hostnames. Addresses that are
+/// not globally reachable are ignored too.
+fn parse_ipv4(value: &str) -> Option<Ipv4Addr> {
+    let value = ::urlencoding::decode(value).ok()?;
+
+    value
+        .parse::<Ipv4Addr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddrV4>().ok().map(|addr| *addr.ip()))
+        .filter(|ip| is_global_ipv4(*ip))
+}
+
+/// Parse BEP 7 `ipv6` parameter value, optionally including port. Invalid
+/// values are ignored, since some clients send hostnames. Addresses that are
+/// not globally reachable are ignored too.
+fn parse_ipv6(value: &str) -> Option<Ipv6Addr> {
+    let value = ::urlencoding::decode(value).ok()?;
+
+    value
+        .parse::<Ipv6Addr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddrV6>().ok().map(|addr| *addr.ip()))
+        .filter(|ip| is_global_ipv6(*ip))
+}
+
+/// Check if IPv4 address is globally reachable unicast address
+fn is_global_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, _, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network" (0.0.0.0/8)
+        || a == 0
+        // Shared address space (100.64.0.0/10)
+        || (a == 100 && (b & 0b1100_0000) == 64)
+        // Benchmarking (198.18.0.0/15)
+        || (a == 198 && (b & 0b1111_1110) == 18)
+        // Reserved (240.0.0.0/4), including broadcast address
+        || a >= 240)
+}
+
+/// Check if IPv6 address is globally reachable unicast address
+///
+/// Only addresses in 2000::/3 are accepted, which excludes unspecified,
+/// loopback, multicast, link-local, unique local and IPv4-mapped addresses.
+fn is_global_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    let global_unicast = (segments[0] & 0xe000) == 0x2000;
+    let documentation = segments[0] == 0x2001 && segments[1] == 0x0db8;
+
+    global_unicast && !documentation
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ScrapeRequest {
     pub info_hashes: Vec<InfoHash>,
@@ -196,7 +325,7 @@ impl ScrapeRequest {
         Ok(())
     }
 
-    pub fn from_query_string(query_string: &str) -> anyhow::Result<Self> {
+    pub fn from_query_string(query_string: &str) -> Result<Self, InvalidRequest> {
         // -- Parse key-value pairs
 
         let mut info_hashes = Vec::new();
@@ -211,16 +340,14 @@ impl ScrapeRequest {
 
             let key = query_string
                 .get(position..equal_sign_index)
-                .with_context(|| format!("no key at {}..{}", position, equal_sign_index))?;
+                .ok_or(InvalidRequest::MalformedQueryString)?;
             let value = query_string
                 .get(equal_sign_index + 1..segment_end)
-                .with_context(|| {
-                    format!("no value at {}..{}", equal_sign_index + 1, segment_end)
-                })?;
+                .ok_or(InvalidRequest::MalformedQueryString)?;
 
             match key {
                 "info_hash" => {
-                    let value = urldecode_20_bytes(value)?;
+                    let value = decode_20_bytes(value, "info_hash")?;
 
                     info_hashes.push(InfoHash(value));
                 }
@@ -237,30 +364,208 @@ impl ScrapeRequest {
         }
 
         if info_hashes.is_empty() {
-            return Err(anyhow::anyhow!("No info hashes sent"));
+            return Err(InvalidRequest::FullScrapeNotSupported);
         }
 
         Ok(ScrapeRequest { info_hashes })
     }
 }
 
+/// Reason for a request being invalid
+///
+/// The messages returned by [`InvalidRequest::message`] are stable and are
+/// meant to be sent to clients in failure responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidRequest {
+    /// Not a valid HTTP request
+    MalformedHttp,
+    /// Path doesn't match any announce or scrape path
+    UnsupportedPath,
+    MissingQueryString,
+    MalformedQueryString,
+    /// Required query string parameter is missing
+    MissingParameter(&'static str),
+    /// Query string parameter value couldn't be parsed
+    InvalidParameter(&'static str),
+    /// Query string parameter value is not properly percent-encoded
+    InvalidPercentEncoding(&'static str),
+    /// Info hash or peer id doesn't decode to exactly 20 bytes
+    InvalidLength(&'static str),
+    InvalidEvent,
+    /// Scrape request doesn't contain any info hashes
+    FullScrapeNotSupported,
+}
+
+impl InvalidRequest {
+    /// Client-facing description of error
+    pub fn message(&self) -> Cow<'static, str> {
+        match self {
+            Self::MalformedHttp => "malformed HTTP request".into(),
+            Self::UnsupportedPath => "unsupported path".into(),
+            Self::MissingQueryString => "missing query string".into(),
+            Self::MalformedQueryString => "malformed query string".into(),
+            Self::MissingParameter(name) => format!("missing {}", name).into(),
+            Self::InvalidParameter(name) => format!("invalid {}", name).into(),
+            Self::InvalidPercentEncoding(name) => {
+                format!("invalid percent-encoding in {}", name).into()
+            }
+            Self::InvalidLength(name) => format!("{} must be 20 bytes long", name).into(),
+            Self::InvalidEvent => "invalid event".into(),
+            Self::FullScrapeNotSupported => "full scrapes are not supported".into(),
+        }
+    }
+
+    /// Short identifier of variant, e.g., for use as metrics label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MalformedHttp => "malformed_http",
+            Self::UnsupportedPath => "unsupported_path",
+            Self::MissingQueryString => "missing_query_string",
+            Self::MalformedQueryString => "malformed_query_string",
+            Self::MissingParameter(_) => "missing_parameter",
+            Self::InvalidParameter(_) => "invalid_parameter",
+            Self::InvalidPercentEncoding(_) => "invalid_percent_encoding",
+            Self::InvalidLength(_) => "invalid_length",
+            Self::InvalidEvent => "invalid_event",
+            Self::FullScrapeNotSupported => "full_scrape_not_supported",
+        }
+    }
+}
+
+impl ::std::fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message())
+    }
+}
+
+impl ::std::error::Error for InvalidRequest {}
+
 #[derive(Debug)]
 pub enum RequestParseError {
     NeedMoreData,
-    Invalid(anyhow::Error),
+    Invalid(InvalidRequest),
 }
 
 impl ::std::fmt::Display for RequestParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::NeedMoreData => write!(f, "Incomplete request, more data needed"),
-            Self::Invalid(err) => write!(f, "Invalid request: {:#}", err),
+            Self::Invalid(err) => write!(f, "Invalid request: {}", err),
         }
     }
 }
 
 impl ::std::error::Error for RequestParseError {}
 
+const PATH_KEY_PLACEHOLDER: &str = "{key}";
+const MAX_PATH_KEY_LEN: usize = 100;
+
+/// Request path template, e.g., `/announce`, `/announce/{key}` or
+/// `/{key}/announce`
+///
+/// Templates may contain a single `{key}` placeholder, which must span a
+/// whole path segment. It matches non-empty segments of up to 100 bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTemplate {
+    prefix: String,
+    /// Rest of template after key placeholder, if template contains one
+    opt_suffix: Option<String>,
+}
+
+impl PathTemplate {
+    /// If path matches template, return captured key, if any
+    fn matches<'a>(&self, path: &'a str) -> Option<Option<&'a str>> {
+        match self.opt_suffix {
+            None => (path == self.prefix).then_some(None),
+            Some(ref suffix) => {
+                let key = path
+                    .strip_prefix(self.prefix.as_str())?
+                    .strip_suffix(suffix.as_str())?;
+
+                if key.is_empty() || key.len() > MAX_PATH_KEY_LEN || key.contains('/') {
+                    None
+                } else {
+                    Some(Some(key))
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        if !value.starts_with('/') || value.contains('?') {
+            return Err(format!(
+                "Path must start with / and not contain ?: {}",
+                value
+            ));
+        }
+
+        match value.split_once(PATH_KEY_PLACEHOLDER) {
+            None => Ok(Self {
+                prefix: value.into(),
+                opt_suffix: None,
+            }),
+            Some((prefix, suffix)) => {
+                if !prefix.ends_with('/') || !(suffix.is_empty() || suffix.starts_with('/')) {
+                    return Err(format!(
+                        "{} must span a whole path segment: {}",
+                        PATH_KEY_PLACEHOLDER, value
+                    ));
+                }
+                if suffix.contains(PATH_KEY_PLACEHOLDER) {
+                    return Err(format!(
+                        "Path may only contain a single {}: {}",
+                        PATH_KEY_PLACEHOLDER, value
+                    ));
+                }
+
+                Ok(Self {
+                    prefix: prefix.into(),
+                    opt_suffix: Some(suffix.into()),
+                })
+            }
+        }
+    }
+}
+
+/// Paths to accept announce and scrape requests on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestPaths {
+    pub announce: Vec<PathTemplate>,
+    pub scrape: Vec<PathTemplate>,
+}
+
+impl Default for RequestPaths {
+    fn default() -> Self {
+        Self {
+            announce: vec!["/announce".parse().unwrap()],
+            scrape: vec!["/scrape".parse().unwrap()],
+        }
+    }
+}
+
+impl RequestPaths {
+    /// Find type of request that path is for, along with captured key
+    fn match_path<'a>(&self, path: &'a str) -> Option<(RequestType, Option<&'a str>)> {
+        let f = |templates: &[PathTemplate]| {
+            templates.iter().find_map(|template| template.matches(path))
+        };
+
+        f(&self.announce)
+            .map(|opt_key| (RequestType::Announce, opt_key))
+            .or_else(|| f(&self.scrape).map(|opt_key| (RequestType::Scrape, opt_key)))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RequestType {
+    Announce,
+    Scrape,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Request {
     Announce(AnnounceRequest),
@@ -270,19 +575,56 @@ pub enum Request {
 impl Request {
     /// Parse Request from HTTP request bytes
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, RequestParseError> {
+        Self::from_bytes_inner(bytes, None, None).map(|(request, _)| request)
+    }
+
+    /// Parse Request from http request bytes, accepting announce and scrape
+    /// requests on given paths. If `opt_header_name` is set, also return
+    /// values of all headers with that name (case-insensitive) in order of
+    /// appearance.
+    pub fn from_bytes_with_paths<'a>(
+        bytes: &'a [u8],
+        paths: &RequestPaths,
+        opt_header_name: Option<&str>,
+    ) -> Result<(Self, Vec<&'a [u8]>), RequestParseError> {
+        Self::from_bytes_inner(bytes, Some(paths), opt_header_name)
+    }
+
+    fn from_bytes_inner<'a>(
+        bytes: &'a [u8],
+        opt_paths: Option<&RequestPaths>,
+        opt_header_name: Option<&str>,
+    ) -> Result<(Self, Vec<&'a [u8]>), RequestParseError> {
         let mut headers = [httparse::EMPTY_HEADER; 16];
         let mut http_request = httparse::Request::new(&mut headers);
 
         match http_request.parse(bytes) {
             Ok(httparse::Status::Complete(_)) => {
                 if let Some(path) = http_request.path {
-                    Self::from_http_get_path(path).map_err(RequestParseError::Invalid)
+                    let request = Self::from_http_get_path_inner(path, opt_paths)
+                        .map_err(RequestParseError::Invalid)?;
+
+                    let header_values = match opt_header_name {
+                        Some(header_name) => http_request
+                            .headers
+                            .iter()
+                            .filter(|header| header.name.eq_ignore_ascii_case(header_name))
+                            .map(|header| header.value)
+                            .collect(),
+                        None => Vec::new(),
+                    };
+
+                    Ok((request, header_values))
                 } else {
-                    Err(RequestParseError::Invalid(anyhow::anyhow!("no http path")))
+                    Err(RequestParseError::Invalid(InvalidRequest::MalformedHttp))
                 }
             }
             Ok(httparse::Status::Partial) => Err(RequestParseError::NeedMoreData),
-            Err(err) => Err(RequestParseError::Invalid(anyhow::Error::from(err))),
+            Err(err) => {
+                ::log::debug!("invalid http request: {}", err);
+
+                Err(RequestParseError::Invalid(InvalidRequest::MalformedHttp))
+            }
         }
     }
 
@@ -299,24 +641,55 @@ impl Request {
     /// UTF-8 string, meaning that non-ascii bytes are invalid characters.
     /// Therefore, these bytes must be converted to their equivalent multi-byte
     /// UTF-8 encodings.
-    pub fn from_http_get_path(path: &str) -> anyhow::Result<Self> {
+    pub fn from_http_get_path(path: &str) -> Result<Self, InvalidRequest> {
+        Self::from_http_get_path_inner(path, None)
+    }
+
+    /// Parse Request from http GET path, accepting announce and scrape
+    /// requests on given paths. Keys captured from announce paths are
+    /// stored in [`AnnounceRequest::path_key`].
+    pub fn from_http_get_path_with_paths(
+        path: &str,
+        paths: &RequestPaths,
+    ) -> Result<Self, InvalidRequest> {
+        Self::from_http_get_path_inner(path, Some(paths))
+    }
+
+    fn from_http_get_path_inner(
+        path: &str,
+        opt_paths: Option<&RequestPaths>,
+    ) -> Result<Self, InvalidRequest> {
         ::log::debug!("request GET path: {}", path);
 
-        let mut split_parts = path.splitn(2, '?');
+        let (location, opt_query_string) = match path.split_once('?') {
+            Some((location, query_string)) => (location, Some(query_string)),
+            None => (path, None),
+        };
 
-        let location = split_parts.next().with_context(|| "no location")?;
-        let query_string = split_parts.next().with_context(|| "no query string")?;
+        // Avoid constructing default paths when none are passed
+        let (request_type, opt_path_key) = match opt_paths {
+            Some(paths) => paths.match_path(location),
+            None => match location {
+                "/announce" => Some((RequestType::Announce, None)),
+                "/scrape" => Some((RequestType::Scrape, None)),
+                _ => None,
+            },
+        }
+        .ok_or(InvalidRequest::UnsupportedPath)?;
 
-        if location == "/announce" {
-            Ok(Request::Announce(AnnounceRequest::from_query_string(
-                query_string,
-            )?))
-        } else if location == "/scrape" {
-            Ok(Request::Scrape(ScrapeRequest::from_query_string(
-                query_string,
-            )?))
-        } else {
-            Err(anyhow::anyhow!("Path must be /announce or /scrape"))
+        match (request_type, opt_query_string) {
+            (RequestType::Announce, Some(query_string)) => {
+                let mut request = AnnounceRequest::from_query_string(query_string)?;
+
+                request.path_key = opt_path_key.map(Into::into);
+
+                Ok(Request::Announce(request))
+            }
+            (RequestType::Scrape, Some(query_string)) => Ok(Request::Scrape(
+                ScrapeRequest::from_query_string(query_string)?,
+            )),
+            (RequestType::Announce, None) => Err(InvalidRequest::MissingQueryString),
+            (RequestType::Scrape, None) => Err(InvalidRequest::FullScrapeNotSupported),
         }
     }
 
@@ -357,6 +730,11 @@ mod tests {
             event: AnnounceEvent::Started,
             numwant: Some(0),
             key: Some("4ab4b877".into()),
+            compact: true,
+            no_peer_id: false,
+            ipv4: None,
+            ipv6: None,
+            path_key: None,
         })
     }
 
@@ -390,6 +768,183 @@ mod tests {
         assert_eq!(parsed_request, reference_request);
     }
 
+    #[test]
+    fn test_request_from_bytes_with_header_values() {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(b"GET ");
+        bytes.extend_from_slice(&SCRAPE_REQUEST_PATH.as_bytes());
+        bytes.extend_from_slice(b" HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n");
+        bytes.extend_from_slice(b"Host: example.com\r\nx-forwarded-for: 5.6.7.8\r\n\r\n");
+
+        let (parsed_request, header_values) = Request::from_bytes_with_paths(
+            &bytes[..],
+            &Default::default(),
+            Some("X-Forwarded-For"),
+        )
+        .unwrap();
+
+        assert!(matches!(parsed_request, Request::Scrape(_)));
+        assert_eq!(header_values, vec![&b"1.2.3.4"[..], &b"5.6.7.8"[..]]);
+    }
+
+    #[test]
+    fn test_announce_request_compact_flags() {
+        let path = ANNOUNCE_REQUEST_PATH.replace("compact=1", "compact=0&no_peer_id=1");
+
+        match Request::from_http_get_path(&path).unwrap() {
+            Request::Announce(request) => {
+                assert!(!request.compact);
+                assert!(request.no_peer_id);
+            }
+            Request::Scrape(_) => panic!("not an announce request"),
+        }
+
+        let path = ANNOUNCE_REQUEST_PATH.replace("&compact=1", "");
+
+        match Request::from_http_get_path(&path).unwrap() {
+            Request::Announce(request) => {
+                assert!(request.compact);
+                assert!(!request.no_peer_id);
+            }
+            Request::Scrape(_) => panic!("not an announce request"),
+        }
+
+        let path = ANNOUNCE_REQUEST_PATH.replace("compact=1", "compact=2");
+
+        assert!(Request::from_http_get_path(&path).is_err());
+    }
+
+    #[test]
+    fn test_announce_request_ip_params() {
+        let f = |params: &str| match Request::from_http_get_path(&format!(
+            "{}&{}",
+            ANNOUNCE_REQUEST_PATH, params
+        ))
+        .unwrap()
+        {
+            Request::Announce(request) => (request.ipv4, request.ipv6),
+            Request::Scrape(_) => panic!("not an announce request"),
+        };
+
+        assert_eq!(
+            f("ipv4=1.2.3.4&ipv6=2a00:1::1"),
+            (
+                Some([1, 2, 3, 4].into()),
+                Some("2a00:1::1".parse().unwrap())
+            )
+        );
+        assert_eq!(
+            f("ipv4=1.2.3.4%3A6881&ipv6=%5B2a00%3A1%3A%3A1%5D%3A6881"),
+            (
+                Some([1, 2, 3, 4].into()),
+                Some("2a00:1::1".parse().unwrap())
+            )
+        );
+        assert_eq!(f("ipv4=example.com&ipv6=1.2.3.4"), (None, None));
+
+        // Addresses that are not globally reachable are ignored
+        assert_eq!(f("ipv4=0.0.0.0&ipv6=::"), (None, None));
+        assert_eq!(f("ipv4=127.0.0.1&ipv6=::1"), (None, None));
+        assert_eq!(f("ipv4=10.1.2.3&ipv6=fd00::1"), (None, None));
+        assert_eq!(f("ipv4=169.254.0.1&ipv6=fe80::1"), (None, None));
+        assert_eq!(f("ipv4=224.0.0.1&ipv6=ff02::1"), (None, None));
+        assert_eq!(f("ipv4=100.64.0.1&ipv6=2001:db8::1"), (None, None));
+        assert_eq!(f("ipv4=255.255.255.255&ipv6=::ffff:1.2.3.4"), (None, None));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let f = |path: &str| Request::from_http_get_path(path).unwrap_err();
+
+        assert_eq!(
+            f(&ANNOUNCE_REQUEST_PATH.replace("info_hash=", "a=")),
+            InvalidRequest::MissingParameter("info_hash")
+        );
+        assert_eq!(
+            f(&ANNOUNCE_REQUEST_PATH.replace("%04%0b", "%04")),
+            InvalidRequest::InvalidLength("info_hash")
+        );
+        assert_eq!(
+            f(&ANNOUNCE_REQUEST_PATH.replace("%04%0b", "%04%zz")),
+            InvalidRequest::InvalidPercentEncoding("info_hash")
+        );
+        assert_eq!(
+            f(&ANNOUNCE_REQUEST_PATH.replace("peer_id=-", "peer_id=--")),
+            InvalidRequest::InvalidLength("peer_id")
+        );
+        assert_eq!(
+            f(&ANNOUNCE_REQUEST_PATH.replace("port=12345", "port=123456")),
+            InvalidRequest::InvalidParameter("port")
+        );
+        assert_eq!(
+            f(&ANNOUNCE_REQUEST_PATH.replace("event=started", "event=paused")),
+            InvalidRequest::InvalidEvent
+        );
+        assert_eq!(
+            f(&ANNOUNCE_REQUEST_PATH.replace("/announce", "/announces")),
+            InvalidRequest::UnsupportedPath
+        );
+        assert_eq!(f("/announce"), InvalidRequest::MissingQueryString);
+        assert_eq!(f("/scrape"), InvalidRequest::FullScrapeNotSupported);
+        assert_eq!(f("/scrape?a=b"), InvalidRequest::FullScrapeNotSupported);
+
+        assert_eq!(
+            InvalidRequest::MissingParameter("info_hash").message(),
+            "missing info_hash"
+        );
+
+        assert!(matches!(
+            Request::from_bytes(b"GET\0 / HTTP/1.1\r\n\r\n"),
+            Err(RequestParseError::Invalid(InvalidRequest::MalformedHttp))
+        ));
+    }
+
+    #[test]
+    fn test_path_templates() {
+        let paths = RequestPaths {
+            announce: vec![
+                "/announce/{key}".parse().unwrap(),
+                "/{key}/announce".parse().unwrap(),
+                "/tracker/announce.php".parse().unwrap(),
+            ],
+            scrape: vec!["/{key}/scrape".parse().unwrap()],
+        };
+        let query_string = ANNOUNCE_REQUEST_PATH.split_once('?').unwrap().1;
+
+        let f = |location: &str| {
+            Request::from_http_get_path_with_paths(
+                &format!("{}?{}", location, query_string),
+                &paths,
+            )
+        };
+        let path_key = |location: &str| match f(location).unwrap() {
+            Request::Announce(request) => request.path_key,
+            Request::Scrape(_) => panic!("not an announce request"),
+        };
+
+        assert_eq!(path_key("/announce/abc123"), Some("abc123".into()));
+        assert_eq!(path_key("/abc123/announce"), Some("abc123".into()));
+        assert_eq!(path_key("/tracker/announce.php"), None);
+        assert!(matches!(f("/abc123/scrape"), Ok(Request::Scrape(_))));
+
+        for location in [
+            "/announce",
+            "/announce/",
+            "/announce/a/b",
+            "/scrape",
+            "/tracker/announce.phps",
+        ] {
+            assert_eq!(f(location), Err(InvalidRequest::UnsupportedPath));
+        }
+
+        assert!("announce".parse::<PathTemplate>().is_err());
+        assert!("/announce?a={key}".parse::<PathTemplate>().is_err());
+        assert!("/announce{key}".parse::<PathTemplate>().is_err());
+        assert!("/{key}x/announce".parse::<PathTemplate>().is_err());
+        assert!("/{key}/{key}".parse::<PathTemplate>().is_err());
+    }
+
     impl Arbitrary for AnnounceRequest {
         fn arbitrary(g: &mut Gen) -> Self {
             let key: Option<String> = Arbitrary::arbitrary(g);
@@ -404,6 +959,11 @@ mod tests {
                 event: Arbitrary::arbitrary(g),
                 numwant: Arbitrary::arbitrary(g),
                 key: key.map(|key| key.into()),
+                compact: Arbitrary::arbitrary(g),
+                no_peer_id: Arbitrary::arbitrary(g),
+                ipv4: Option::<Ipv4Addr>::arbitrary(g).filter(|ip| is_global_ipv4(*ip)),
+                ipv6: Option::<Ipv6Addr>::arbitrary(g).filter(|ip| is_global_ipv6(*ip)),
+                path_key: None,
             }
         }
     }
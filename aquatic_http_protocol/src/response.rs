@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 use std::io::Write;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -11,9 +11,37 @@ use super::utils::*;
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ResponsePeer<I: Eq> {
     pub ip_address: I,
+    /// Only included in non-compact peer lists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<PeerId>,
     pub port: u16,
 }
 
+impl<I: Eq + Copy + Into<IpAddr>> ResponsePeer<I> {
+    /// Write peer as dictionary for non-compact (BEP 3) peer list
+    fn write_non_compact<W: Write>(&self, output: &mut W) -> ::std::io::Result<usize> {
+        let mut bytes_written = 0usize;
+
+        let ip = self.ip_address.into().to_string();
+
+        bytes_written += write_bytes(output, b"d2:ip")?;
+        bytes_written += write_bytes(output, itoa::Buffer::new().format(ip.len()).as_bytes())?;
+        bytes_written += write_bytes(output, b":")?;
+        bytes_written += write_bytes(output, ip.as_bytes())?;
+
+        if let Some(peer_id) = self.peer_id {
+            bytes_written += write_bytes(output, b"7:peer id20:")?;
+            bytes_written += write_bytes(output, &peer_id.0)?;
+        }
+
+        bytes_written += write_bytes(output, b"4:porti")?;
+        bytes_written += write_bytes(output, itoa::Buffer::new().format(self.port).as_bytes())?;
+        bytes_written += write_bytes(output, b"ee")?;
+
+        Ok(bytes_written)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(transparent)]
 pub struct ResponsePeerListV4(
@@ -45,6 +73,13 @@ pub struct ScrapeStatistics {
 pub struct AnnounceResponse {
     #[serde(rename = "interval")]
     pub announce_interval: usize,
+    /// Minimum interval between announces. Only included if Some.
+    #[serde(
+        rename = "min interval",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub min_announce_interval: Option<usize>,
     pub complete: usize,
     pub incomplete: usize,
     #[serde(default)]
@@ -58,60 +93,91 @@ pub struct AnnounceResponse {
         serialize_with = "serialize_optional_string"
     )]
     pub warning_message: Option<String>,
+    /// Write IPv4 and IPv6 peers as a single list of dictionaries (BEP 3)
+    /// instead of compact strings. Only respected by `write`.
+    #[serde(skip)]
+    pub non_compact: bool,
 }
 
 impl AnnounceResponse {
     pub fn write<W: Write>(&self, output: &mut W) -> ::std::io::Result<usize> {
         let mut bytes_written = 0usize;
 
-        bytes_written += output.write(b"d8:completei")?;
-        bytes_written += output.write(itoa::Buffer::new().format(self.complete).as_bytes())?;
+        bytes_written += write_bytes(output, b"d8:completei")?;
+        bytes_written += write_bytes(output, itoa::Buffer::new().format(self.complete).as_bytes())?;
 
-        bytes_written += output.write(b"e10:incompletei")?;
-        bytes_written += output.write(itoa::Buffer::new().format(self.incomplete).as_bytes())?;
+        bytes_written += write_bytes(output, b"e10:incompletei")?;
+        bytes_written += write_bytes(
+            output,
+            itoa::Buffer::new().format(self.incomplete).as_bytes(),
+        )?;
 
-        bytes_written += output.write(b"e8:intervali")?;
-        bytes_written += output.write(
+        bytes_written += write_bytes(output, b"e8:intervali")?;
+        bytes_written += write_bytes(
+            output,
             itoa::Buffer::new()
                 .format(self.announce_interval)
                 .as_bytes(),
         )?;
 
-        bytes_written += output.write(b"e5:peers")?;
-        bytes_written += output.write(
-            itoa::Buffer::new()
-                .format(self.peers.0.len() * 6)
-                .as_bytes(),
-        )?;
-        bytes_written += output.write(b":")?;
-        for peer in self.peers.0.iter() {
-            bytes_written += output.write(&u32::from(peer.ip_address).to_be_bytes())?;
-            bytes_written += output.write(&peer.port.to_be_bytes())?;
+        if let Some(min_announce_interval) = self.min_announce_interval {
+            bytes_written += write_bytes(output, b"e12:min intervali")?;
+            bytes_written += write_bytes(
+                output,
+                itoa::Buffer::new().format(min_announce_interval).as_bytes(),
+            )?;
         }
 
-        bytes_written += output.write(b"6:peers6")?;
-        bytes_written += output.write(
-            itoa::Buffer::new()
-                .format(self.peers6.0.len() * 18)
-                .as_bytes(),
-        )?;
-        bytes_written += output.write(b":")?;
-        for peer in self.peers6.0.iter() {
-            bytes_written += output.write(&u128::from(peer.ip_address).to_be_bytes())?;
-            bytes_written += output.write(&peer.port.to_be_bytes())?;
+        if self.non_compact {
+            bytes_written += write_bytes(output, b"e5:peersl")?;
+            for peer in self.peers.0.iter() {
+                bytes_written += peer.write_non_compact(output)?;
+            }
+            for peer in self.peers6.0.iter() {
+                bytes_written += peer.write_non_compact(output)?;
+            }
+            bytes_written += write_bytes(output, b"e")?;
+        } else {
+            bytes_written += write_bytes(output, b"e5:peers")?;
+            bytes_written += write_bytes(
+                output,
+                itoa::Buffer::new()
+                    .format(self.peers.0.len() * 6)
+                    .as_bytes(),
+            )?;
+            bytes_written += write_bytes(output, b":")?;
+            for peer in self.peers.0.iter() {
+                bytes_written += write_bytes(output, &u32::from(peer.ip_address).to_be_bytes())?;
+                bytes_written += write_bytes(output, &peer.port.to_be_bytes())?;
+            }
+
+            bytes_written += write_bytes(output, b"6:peers6")?;
+            bytes_written += write_bytes(
+                output,
+                itoa::Buffer::new()
+                    .format(self.peers6.0.len() * 18)
+                    .as_bytes(),
+            )?;
+            bytes_written += write_bytes(output, b":")?;
+            for peer in self.peers6.0.iter() {
+                bytes_written += write_bytes(output, &u128::from(peer.ip_address).to_be_bytes())?;
+                bytes_written += write_bytes(output, &peer.port.to_be_bytes())?;
+            }
         }
 
         if let Some(ref warning_message) = self.warning_message {
             let message_bytes = warning_message.as_bytes();
 
-            bytes_written += output.write(b"15:warning message")?;
-            bytes_written +=
-                output.write(itoa::Buffer::new().format(message_bytes.len()).as_bytes())?;
-            bytes_written += output.write(b":")?;
-            bytes_written += output.write(message_bytes)?;
+            bytes_written += write_bytes(output, b"15:warning message")?;
+            bytes_written += write_bytes(
+                output,
+                itoa::Buffer::new().format(message_bytes.len()).as_bytes(),
+            )?;
+            bytes_written += write_bytes(output, b":")?;
+            bytes_written += write_bytes(output, message_bytes)?;
         }
 
-        bytes_written += output.write(b"e")?;
+        bytes_written += write_bytes(output, b"e")?;
 
         Ok(bytes_written)
     }
@@ -138,21 +204,25 @@ impl ScrapeResponse {
     pub fn write<W: Write>(&self, output: &mut W) -> ::std::io::Result<usize> {
         let mut bytes_written = 0usize;
 
-        bytes_written += output.write(b"d5:filesd")?;
+        bytes_written += write_bytes(output, b"d5:filesd")?;
 
         for (info_hash, statistics) in self.files.iter() {
-            bytes_written += output.write(b"20:")?;
-            bytes_written += output.write(&info_hash.0)?;
-            bytes_written += output.write(b"d8:completei")?;
-            bytes_written +=
-                output.write(itoa::Buffer::new().format(statistics.complete).as_bytes())?;
-            bytes_written += output.write(b"e10:downloadedi0e10:incompletei")?;
-            bytes_written +=
-                output.write(itoa::Buffer::new().format(statistics.incomplete).as_bytes())?;
-            bytes_written += output.write(b"ee")?;
+            bytes_written += write_bytes(output, b"20:")?;
+            bytes_written += write_bytes(output, &info_hash.0)?;
+            bytes_written += write_bytes(output, b"d8:completei")?;
+            bytes_written += write_bytes(
+                output,
+                itoa::Buffer::new().format(statistics.complete).as_bytes(),
+            )?;
+            bytes_written += write_bytes(output, b"e10:downloadedi0e10:incompletei")?;
+            bytes_written += write_bytes(
+                output,
+                itoa::Buffer::new().format(statistics.incomplete).as_bytes(),
+            )?;
+            bytes_written += write_bytes(output, b"ee")?;
         }
 
-        bytes_written += output.write(b"ee")?;
+        bytes_written += write_bytes(output, b"ee")?;
 
         Ok(bytes_written)
     }
@@ -187,11 +257,14 @@ impl FailureResponse {
 
         let reason_bytes = self.failure_reason.as_bytes();
 
-        bytes_written += output.write(b"d14:failure reason")?;
-        bytes_written += output.write(itoa::Buffer::new().format(reason_bytes.len()).as_bytes())?;
-        bytes_written += output.write(b":")?;
-        bytes_written += output.write(reason_bytes)?;
-        bytes_written += output.write(b"e")?;
+        bytes_written += write_bytes(output, b"d14:failure reason")?;
+        bytes_written += write_bytes(
+            output,
+            itoa::Buffer::new().format(reason_bytes.len()).as_bytes(),
+        )?;
+        bytes_written += write_bytes(output, b":")?;
+        bytes_written += write_bytes(output, reason_bytes)?;
+        bytes_written += write_bytes(output, b"e")?;
 
         Ok(bytes_written)
     }
@@ -240,11 +313,20 @@ impl axum::response::IntoResponse for Response {
     }
 }
 
+/// Write all of `bytes` to `output` and return their count. Unlike with
+/// `Write::write`, running out of space in `output` is an error.
+fn write_bytes<W: Write>(output: &mut W, bytes: &[u8]) -> ::std::io::Result<usize> {
+    output.write_all(bytes)?;
+
+    Ok(bytes.len())
+}
+
 #[cfg(test)]
 impl quickcheck::Arbitrary for ResponsePeer<Ipv4Addr> {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         Self {
             ip_address: Ipv4Addr::arbitrary(g),
+            peer_id: None,
             port: u16::arbitrary(g),
         }
     }
@@ -255,6 +337,7 @@ impl quickcheck::Arbitrary for ResponsePeer<Ipv6Addr> {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         Self {
             ip_address: Ipv6Addr::arbitrary(g),
+            peer_id: None,
             port: u16::arbitrary(g),
         }
     }
@@ -290,11 +373,13 @@ impl quickcheck::Arbitrary for AnnounceResponse {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         Self {
             announce_interval: usize::arbitrary(g),
+            min_announce_interval: Option::arbitrary(g),
             complete: usize::arbitrary(g),
             incomplete: usize::arbitrary(g),
             peers: ResponsePeerListV4::arbitrary(g),
             peers6: ResponsePeerListV6::arbitrary(g),
             warning_message: quickcheck::Arbitrary::arbitrary(g),
+            non_compact: false,
         }
     }
 }
@@ -341,6 +426,86 @@ mod tests {
         success
     }
 
+    #[test]
+    fn test_non_compact_announce_response_to_bytes() {
+        let response = AnnounceResponse {
+            announce_interval: 120,
+            min_announce_interval: None,
+            complete: 1,
+            incomplete: 2,
+            peers: ResponsePeerListV4(vec![ResponsePeer {
+                ip_address: Ipv4Addr::new(1, 2, 3, 4),
+                peer_id: Some(PeerId(*b"-ABC123-abcdefghijkl")),
+                port: 6881,
+            }]),
+            peers6: ResponsePeerListV6(vec![ResponsePeer {
+                ip_address: "2001:db8::1".parse().unwrap(),
+                peer_id: None,
+                port: 443,
+            }]),
+            warning_message: None,
+            non_compact: true,
+        };
+
+        let mut bytes = Vec::new();
+
+        response.write(&mut bytes).unwrap();
+
+        let expected: &[u8] = b"d8:completei1e10:incompletei2e8:intervali120e5:peersl\
+            d2:ip7:1.2.3.47:peer id20:-ABC123-abcdefghijkl4:porti6881ee\
+            d2:ip11:2001:db8::14:porti443eeee";
+
+        assert_eq!(
+            String::from_utf8_lossy(&bytes),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    #[test]
+    fn test_non_compact_announce_response_max_peers() {
+        let peer_id = PeerId(*b"-ABC123-abcdefghijkl");
+
+        let response = AnnounceResponse {
+            announce_interval: 120,
+            min_announce_interval: Some(60),
+            complete: 25,
+            incomplete: 25,
+            peers: Default::default(),
+            peers6: ResponsePeerListV6(
+                (0..50u16)
+                    .map(|i| ResponsePeer {
+                        ip_address: Ipv6Addr::new(
+                            0x2a00, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, i,
+                        ),
+                        peer_id: Some(peer_id),
+                        port: 65535,
+                    })
+                    .collect(),
+            ),
+            warning_message: None,
+            non_compact: true,
+        };
+
+        let mut bytes = Vec::new();
+
+        let bytes_written = response.write(&mut bytes).unwrap();
+
+        assert_eq!(bytes_written, bytes.len());
+        assert!(bytes.len() > 4096);
+        assert_eq!(
+            bytes
+                .windows(peer_id.0.len())
+                .filter(|window| *window == peer_id.0)
+                .count(),
+            50
+        );
+
+        // Writing to a buffer that is too short fails instead of truncating
+        let mut buffer = [0u8; 4096];
+
+        assert!(response.write(&mut &mut buffer[..]).is_err());
+    }
+
     #[quickcheck]
     fn test_scrape_response_to_bytes(response: ScrapeResponse) -> bool {
         let reference = bendy::serde::to_bytes(&Response::Scrape(response.clone())).unwrap();
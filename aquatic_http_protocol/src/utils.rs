@@ -1,7 +1,6 @@
 use std::io::Write;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-use anyhow::Context;
 use serde::{de::Visitor, Deserializer, Serializer};
 
 use super::response::ResponsePeer;
@@ -18,40 +17,39 @@ pub fn urlencode_20_bytes(input: [u8; 20], output: &mut impl Write) -> ::std::io
     Ok(())
 }
 
-pub fn urldecode_20_bytes(value: &str) -> anyhow::Result<[u8; 20]> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlDecodeError {
+    InvalidPercentEncoding,
+    InvalidLength,
+}
+
+pub fn urldecode_20_bytes(value: &str) -> Result<[u8; 20], UrlDecodeError> {
     let mut out_arr = [0u8; 20];
 
     let mut chars = value.chars();
 
     for i in 0..20 {
-        let c = chars.next().with_context(|| "less than 20 chars")?;
+        let c = chars.next().ok_or(UrlDecodeError::InvalidLength)?;
 
         if c as u32 > 255 {
-            return Err(anyhow::anyhow!(
-                "character not in single byte range: {:#?}",
-                c
-            ));
+            return Err(UrlDecodeError::InvalidPercentEncoding);
         }
 
         if c == '%' {
-            let first = chars
-                .next()
-                .with_context(|| "missing first urldecode char in pair")?;
-            let second = chars
-                .next()
-                .with_context(|| "missing second urldecode char in pair")?;
+            let first = chars.next().ok_or(UrlDecodeError::InvalidPercentEncoding)?;
+            let second = chars.next().ok_or(UrlDecodeError::InvalidPercentEncoding)?;
 
             let hex = [first as u8, second as u8];
 
             hex::decode_to_slice(&hex, &mut out_arr[i..i + 1])
-                .map_err(|err| anyhow::anyhow!("hex decode error: {:?}", err))?;
+                .map_err(|_| UrlDecodeError::InvalidPercentEncoding)?;
         } else {
             out_arr[i] = c as u8;
         }
     }
 
     if chars.next().is_some() {
-        return Err(anyhow::anyhow!("more than 20 chars"));
+        return Err(UrlDecodeError::InvalidLength);
     }
 
     Ok(out_arr)
@@ -175,6 +173,7 @@ impl<'de> Visitor<'de> for ResponsePeersIpv4Visitor {
 
                 ResponsePeer {
                     ip_address: Ipv4Addr::from(u32::from_be_bytes(ip_bytes)),
+                    peer_id: None,
                     port: u16::from_be_bytes(port_bytes),
                 }
             })
@@ -225,6 +224,7 @@ impl<'de> Visitor<'de> for ResponsePeersIpv6Visitor {
 
                 ResponsePeer {
                     ip_address: Ipv6Addr::from(u128::from_be_bytes(ip_bytes)),
+                    peer_id: None,
                     port: u16::from_be_bytes(port_bytes),
                 }
             })
@@ -78,25 +78,35 @@ pub mod __private {
     use std::net::SocketAddr;
     use std::path::PathBuf;
 
+    use serde::Serialize;
+
     pub trait Private {
         fn __to_string(&self, comment: Option<String>, field_name: String) -> String;
     }
 
-    macro_rules! impl_trait {
-        ($ident:ident) => {
-            impl Private for $ident {
-                fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
-                    let mut output = String::new();
+    fn value_to_string<T: Serialize + ?Sized>(
+        value: &T,
+        comment: Option<String>,
+        field_name: String,
+    ) -> String {
+        let mut output = String::new();
 
-                    if let Some(comment) = comment {
-                        output.push_str(&comment);
-                    }
+        if let Some(comment) = comment {
+            output.push_str(&comment);
+        }
+
+        let value = crate::toml::ser::to_string(value).unwrap();
 
-                    let value = crate::toml::ser::to_string(self).unwrap();
+        output.push_str(&format!("{} = {}\n", field_name, value));
 
-                    output.push_str(&format!("{} = {}\n", field_name, value));
+        output
+    }
 
-                    output
+    macro_rules! impl_trait {
+        ($ty:ty) => {
+            impl Private for $ty {
+                fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
+                    value_to_string(self, comment, field_name)
                 }
             }
         };
@@ -123,4 +133,12 @@ pub mod __private {
 
     impl_trait!(PathBuf);
     impl_trait!(SocketAddr);
+
+    /// Note that only empty vectors of tables (structs) can be serialized
+    /// as values
+    impl<T: Serialize> Private for Vec<T> {
+        fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
+            value_to_string(self, comment, field_name)
+        }
+    }
 }
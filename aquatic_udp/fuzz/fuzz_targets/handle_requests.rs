@@ -29,6 +29,7 @@ fuzz_target!(|data: (Vec<(ConnectedRequest, CanonicalSocketAddr)>, u8)| {
                     &config,
                     &mut rng,
                     &mut torrents.ipv4,
+                    &torrents.ipv6,
                     request,
                     ip,
                     peer_valid_until,
@@ -41,6 +42,7 @@ fuzz_target!(|data: (Vec<(ConnectedRequest, CanonicalSocketAddr)>, u8)| {
                     &config,
                     &mut rng,
                     &mut torrents.ipv6,
+                    &torrents.ipv4,
                     request,
                     ip,
                     peer_valid_until,
@@ -49,10 +51,10 @@ fuzz_target!(|data: (Vec<(ConnectedRequest, CanonicalSocketAddr)>, u8)| {
                 ConnectedResponse::AnnounceIpv6(response)
             }
             (ConnectedRequest::Scrape(request), IpAddr::V4(_)) => {
-                ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv4, request))
+                ConnectedResponse::Scrape(handle_scrape_request(&config, &torrents.ipv4, &torrents.ipv6, request))
             }
             (ConnectedRequest::Scrape(request), IpAddr::V6(_)) => {
-                ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv6, request))
+                ConnectedResponse::Scrape(handle_scrape_request(&config, &torrents.ipv6, &torrents.ipv4, request))
             }
         };
     }
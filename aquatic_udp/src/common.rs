@@ -7,6 +7,8 @@ use std::sync::Arc;
 use crossbeam_channel::{Sender, TrySendError};
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::ip_list::IpListArcSwap;
+use aquatic_common::proxy_protocol::TrustedSources;
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::*;
 use hdrhistogram::Histogram;
@@ -136,13 +138,24 @@ impl PeerStatus {
     }
 }
 
+pub enum SwarmControlMessage {
+    /// Save torrent maps to snapshot file, then send on included channel
+    SaveSnapshot(Sender<()>),
+}
+
 pub enum StatisticsMessage {
     Ipv4PeerHistogram(Histogram<u64>),
     Ipv6PeerHistogram(Histogram<u64>),
 }
 
 pub struct Statistics {
-    pub requests_received: AtomicUsize,
+    // Requests not rejected by rate limiter
+    pub requests_received_connect: AtomicUsize,
+    pub requests_received_announce: AtomicUsize,
+    pub requests_received_scrape: AtomicUsize,
+    /// Requests that could not be parsed
+    pub requests_received_error: AtomicUsize,
+    pub requests_rate_limited: AtomicUsize,
     pub responses_sent_connect: AtomicUsize,
     pub responses_sent_announce: AtomicUsize,
     pub responses_sent_scrape: AtomicUsize,
@@ -156,7 +169,11 @@ pub struct Statistics {
 impl Statistics {
     pub fn new(num_swarm_workers: usize) -> Self {
         Self {
-            requests_received: Default::default(),
+            requests_received_connect: Default::default(),
+            requests_received_announce: Default::default(),
+            requests_received_scrape: Default::default(),
+            requests_received_error: Default::default(),
+            requests_rate_limited: Default::default(),
             responses_sent_connect: Default::default(),
             responses_sent_announce: Default::default(),
             responses_sent_scrape: Default::default(),
@@ -178,16 +195,20 @@ impl Statistics {
 #[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub ip_list: Arc<IpListArcSwap>,
     pub statistics_ipv4: Arc<Statistics>,
     pub statistics_ipv6: Arc<Statistics>,
+    pub proxy_protocol_sources: Arc<TrustedSources>,
 }
 
 impl State {
     pub fn new(num_swarm_workers: usize) -> Self {
         Self {
             access_list: Arc::new(AccessListArcSwap::default()),
+            ip_list: Arc::new(IpListArcSwap::default()),
             statistics_ipv4: Arc::new(Statistics::new(num_swarm_workers)),
             statistics_ipv6: Arc::new(Statistics::new(num_swarm_workers)),
+            proxy_protocol_sources: Default::default(),
         }
     }
 }
@@ -1,7 +1,10 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
-use serde::Deserialize;
+use aquatic_common::{
+    access_list::AccessListConfig, ip_list::IpListConfig, privileges::PrivilegeConfig,
+    proxy_protocol::ProxyProtocolConfig,
+};
+use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 use aquatic_toml_config::TomlConfig;
@@ -32,10 +35,17 @@ pub struct Config {
     pub protocol: ProtocolConfig,
     pub statistics: StatisticsConfig,
     pub cleaning: CleaningConfig,
+    pub snapshot: SnapshotConfig,
+    pub rate_limit: RateLimitConfig,
+    pub connection_id: ConnectionIdConfig,
     pub privileges: PrivilegeConfig,
     pub access_list: AccessListConfig,
+    pub ip_list: IpListConfig,
+    pub proxy_protocol: ProxyProtocolConfig,
     #[cfg(feature = "cpu-pinning")]
     pub cpu_pinning: aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc,
+    #[cfg(feature = "metrics")]
+    pub metrics: MetricsConfig,
 }
 
 impl Default for Config {
@@ -50,10 +60,35 @@ impl Default for Config {
             protocol: ProtocolConfig::default(),
             statistics: StatisticsConfig::default(),
             cleaning: CleaningConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            connection_id: ConnectionIdConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            ip_list: IpListConfig::default(),
+            proxy_protocol: ProxyProtocolConfig::default(),
             #[cfg(feature = "cpu-pinning")]
             cpu_pinning: Default::default(),
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "metrics")] {
+            pub fn statistics_active(&self) -> bool {
+                (self.statistics.interval != 0) &
+                    (self.statistics.print_to_stdout
+                        | self.statistics.write_html_to_file
+                        | self.metrics.run_prometheus_endpoint)
+            }
+        } else {
+            pub fn statistics_active(&self) -> bool {
+                (self.statistics.interval != 0) &
+                    (self.statistics.print_to_stdout | self.statistics.write_html_to_file)
+            }
         }
     }
 }
@@ -92,6 +127,17 @@ pub struct NetworkConfig {
     /// such as FreeBSD. Setting the value to zero disables resending
     /// functionality.
     pub resend_buffer_max_len: usize,
+    pub socket_io: SocketIo,
+    /// Maximum number of packets to receive or send per syscall when using
+    /// "mmsg" socket I/O
+    pub mmsg_batch_size: usize,
+    /// Number of buffers for sending responses when using "io_uring" socket
+    /// I/O (at most 32767, larger values are clamped)
+    ///
+    /// The io_uring submission queue has one additional entry. The number of
+    /// buffers for receiving requests is this value rounded up to the next
+    /// power of two.
+    pub io_uring_ring_size: usize,
 }
 
 impl NetworkConfig {
@@ -112,10 +158,29 @@ impl Default for NetworkConfig {
             poll_event_capacity: 4096,
             poll_timeout_ms: 50,
             resend_buffer_max_len: 0,
+            socket_io: SocketIo::Single,
+            mmsg_batch_size: 32,
+            io_uring_ring_size: 1024,
         }
     }
 }
 
+/// Socket I/O method. Available methods are single, mmsg and io_uring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketIo {
+    /// Receive and send one packet per syscall
+    Single,
+    /// Receive and send packets in batches using recvmmsg and sendmmsg
+    /// (Linux only)
+    Mmsg,
+    /// Receive packets with multishot recvmsg into buffers registered with
+    /// io_uring and send them with io_uring sendmsg operations (Linux 6.0 or
+    /// later, requires building with the io-uring feature)
+    #[serde(rename = "io_uring")]
+    IoUring,
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct ProtocolConfig {
@@ -125,6 +190,21 @@ pub struct ProtocolConfig {
     pub max_response_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: i32,
+    /// Count completed downloads for each torrent and include the numbers
+    /// in scrape responses
+    ///
+    /// Each peer id is counted at most once per torrent, so ids of counted
+    /// peers are stored for as long as the torrent has peers. They are
+    /// included in swarm snapshots.
+    pub track_completed_downloads: bool,
+    /// Count seeders and leechers in both IPv4 and IPv6 swarms of a torrent
+    /// in announce and scrape responses. Completed downloads in scrape
+    /// responses are combined too, counting peer ids present in both swarms
+    /// once, while seeders and leechers present in both are counted twice.
+    ///
+    /// Response peers are still only taken from the swarm of the request IP
+    /// version, since the protocol doesn't allow mixing them.
+    pub count_peers_across_ip_versions: bool,
 }
 
 impl Default for ProtocolConfig {
@@ -133,6 +213,8 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 70,
             max_response_peers: 50,
             peer_announce_interval: 60 * 15,
+            track_completed_downloads: false,
+            count_peers_across_ip_versions: false,
         }
     }
 }
@@ -155,12 +237,6 @@ pub struct StatisticsConfig {
     pub html_file_path: PathBuf,
 }
 
-impl StatisticsConfig {
-    pub fn active(&self) -> bool {
-        (self.interval != 0) & (self.print_to_stdout | self.write_html_to_file)
-    }
-}
-
 impl Default for StatisticsConfig {
     fn default() -> Self {
         Self {
@@ -178,6 +254,10 @@ impl Default for StatisticsConfig {
 pub struct CleaningConfig {
     /// Clean torrents this often (seconds)
     pub torrent_cleaning_interval: u64,
+    /// Split torrent maps of each swarm worker into this many shards, which
+    /// are cleaned one at a time (rounded up to nearest power of two,
+    /// maximum 65536)
+    pub torrent_map_shards: usize,
     /// Clean pending scrape responses this often (seconds)
     ///
     /// In regular operation, there should be no pending scrape responses
@@ -197,6 +277,7 @@ impl Default for CleaningConfig {
     fn default() -> Self {
         Self {
             torrent_cleaning_interval: 60 * 2,
+            torrent_map_shards: 16,
             pending_scrape_cleaning_interval: 60 * 10,
             max_connection_age: 60 * 2,
             max_peer_age: 60 * 20,
@@ -205,6 +286,158 @@ impl Default for CleaningConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Save torrent maps to snapshot files on shutdown (SIGTERM) and load
+    /// them on startup, so that peers are retained across restarts
+    pub enabled: bool,
+    /// Directory to store snapshot files in (one per swarm worker)
+    ///
+    /// Snapshots are loaded before dropping privileges but saved after,
+    /// so if using chroot mode, the path must be valid both inside and
+    /// outside of the new root (e.g., a relative path when chrooting to
+    /// the working directory).
+    pub directory: PathBuf,
+    /// Additionally save snapshots this often (seconds). Set to zero to
+    /// only save snapshots on shutdown.
+    ///
+    /// Swarm workers don't handle requests while saving snapshots.
+    pub interval: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: "./swarm-snapshots".into(),
+            interval: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limit number of requests per source IP (or subnet) using token buckets
+    ///
+    /// Buckets are kept by each socket worker separately, so if requests
+    /// from a client are spread over multiple socket workers, it will
+    /// effectively get a larger budget. Buckets that have been completely
+    /// refilled are removed every `cleaning.pending_scrape_cleaning_interval`
+    /// seconds.
+    ///
+    /// Announce and scrape requests are only counted after their connection
+    /// IDs have been validated, so that requests with spoofed source
+    /// addresses can't be used to drain the buckets of others.
+    pub enabled: bool,
+    /// Share buckets between IPv4 addresses with this many leading bits in
+    /// common. Set to 32 to rate limit each address separately.
+    pub ipv4_prefix_len: u8,
+    /// Share buckets between IPv6 addresses with this many leading bits in
+    /// common. Set to 128 to rate limit each address separately.
+    pub ipv6_prefix_len: u8,
+    /// Send error responses to rate limited announce and scrape requests
+    /// instead of just ignoring them. Rate limited connect requests are
+    /// always ignored.
+    pub send_error_responses: bool,
+    /// Allow this many connect requests per second on average. Set to zero
+    /// to not rate limit connect requests.
+    pub connect_per_second: u32,
+    /// Allow bursts of up to this many connect requests
+    pub connect_burst: u32,
+    /// Allow this many announce requests per second on average. Set to zero
+    /// to not rate limit announce requests.
+    pub announce_per_second: u32,
+    /// Allow bursts of up to this many announce requests
+    pub announce_burst: u32,
+    /// Allow this many scrape requests per second on average. Set to zero
+    /// to not rate limit scrape requests.
+    pub scrape_per_second: u32,
+    /// Allow bursts of up to this many scrape requests
+    pub scrape_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ipv4_prefix_len: 32,
+            ipv6_prefix_len: 64,
+            send_error_responses: false,
+            connect_per_second: 10,
+            connect_burst: 20,
+            announce_per_second: 10,
+            announce_burst: 20,
+            scrape_per_second: 5,
+            scrape_burst: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionIdConfig {
+    /// Load connection ID secret from file instead of generating a random
+    /// one on startup
+    ///
+    /// This keeps connection IDs valid across restarts and allows several
+    /// instances (e.g., behind the same anycast address or load balancer)
+    /// to accept each other's connection IDs. Connection ID expiration is
+    /// based on wall-clock time, so the clocks of such instances need to be
+    /// kept in sync.
+    pub load_secret_from_file: bool,
+    /// Path to secret file
+    ///
+    /// The file should contain one or two hex-encoded 32 byte keys on
+    /// separate lines. The first key is used for creating and validating
+    /// connection IDs, the optional second key only for validating them.
+    /// Lines starting with # are ignored.
+    ///
+    /// The file is reloaded on SIGUSR1. To rotate keys without rejecting
+    /// connection IDs that clients are currently using, add the new key as
+    /// the first line and keep the old one as the second. If the reloaded
+    /// file only contains a new key, the previous one is kept as second key
+    /// automatically.
+    ///
+    /// The file is loaded before dropping privileges and reloaded after, so
+    /// if using chroot mode, the path must be valid both inside and outside
+    /// of the new root (e.g., a relative path when chrooting to the working
+    /// directory).
+    pub secret_path: PathBuf,
+}
+
+impl Default for ConnectionIdConfig {
+    fn default() -> Self {
+        Self {
+            load_secret_from_file: false,
+            secret_path: "./connection-id-secret.txt".into(),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Run a prometheus endpoint
+    ///
+    /// Metrics are updated every `statistics.interval` seconds.
+    pub run_prometheus_endpoint: bool,
+    /// Address to run prometheus endpoint on
+    pub prometheus_endpoint_address: SocketAddr,
+}
+
+#[cfg(feature = "metrics")]
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            run_prometheus_endpoint: false,
+            prometheus_endpoint_address: SocketAddr::from(([0, 0, 0, 0], 9000)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Config;
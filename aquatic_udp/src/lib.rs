@@ -3,38 +3,83 @@ pub mod config;
 pub mod workers;
 
 use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::thread::Builder;
 
 use anyhow::Context;
-use crossbeam_channel::{bounded, unbounded};
+use crossbeam_channel::{bounded, unbounded, Sender};
 use signal_hook::consts::{SIGTERM, SIGUSR1};
 use signal_hook::iterator::Signals;
 
 use aquatic_common::access_list::update_access_list;
 #[cfg(feature = "cpu-pinning")]
 use aquatic_common::cpu_pinning::{pin_current_if_configured_to, WorkerIndex};
+use aquatic_common::ip_list::update_ip_list;
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::proxy_protocol::TrustedSources;
+use aquatic_common::sharded_map::validate_num_shards;
 use aquatic_common::{PanicSentinelWatcher, ServerStartInstant};
 
 use common::{
-    ConnectedRequestSender, ConnectedResponseSender, SocketWorkerIndex, State, SwarmWorkerIndex,
+    ConnectedRequestSender, ConnectedResponseSender, SocketWorkerIndex, State, SwarmControlMessage,
+    SwarmWorkerIndex,
 };
 use config::Config;
 use workers::socket::validator::ConnectionValidator;
 use workers::socket::SocketWorker;
+use workers::swarm::TorrentMaps;
 
 pub const APP_NAME: &str = "aquatic_udp: UDP BitTorrent tracker";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub fn run(config: Config) -> ::anyhow::Result<()> {
+    validate_num_shards(config.cleaning.torrent_map_shards)?;
+
+    #[cfg(not(target_os = "linux"))]
+    if config.network.socket_io == config::SocketIo::Mmsg {
+        return Err(anyhow::anyhow!(
+            "mmsg socket I/O is only supported on Linux"
+        ));
+    }
+
+    if config.network.socket_io == config::SocketIo::IoUring {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        workers::socket::uring::supported_on_current_kernel()
+            .with_context(|| "io_uring socket I/O not supported by kernel")?;
+
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        return Err(anyhow::anyhow!(
+            "io_uring socket I/O requires Linux and building with the io-uring feature"
+        ));
+    }
+
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
-    let state = State::new(config.swarm_workers);
+    #[cfg(feature = "prometheus")]
+    if config.metrics.run_prometheus_endpoint {
+        use metrics_exporter_prometheus::PrometheusBuilder;
+
+        PrometheusBuilder::new()
+            .with_http_listener(config.metrics.prometheus_endpoint_address)
+            .install()
+            .with_context(|| {
+                format!(
+                    "Install prometheus endpoint on {}",
+                    config.metrics.prometheus_endpoint_address
+                )
+            })?;
+    }
+
+    let mut state = State::new(config.swarm_workers);
+
+    state.proxy_protocol_sources = Arc::new(TrustedSources::new(&config.proxy_protocol)?);
+
     let connection_validator = ConnectionValidator::new(&config)?;
     let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_ip_list(&config.ip_list, &state.ip_list)?;
 
     let mut request_senders = Vec::new();
     let mut request_receivers = BTreeMap::new();
@@ -42,10 +87,19 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
     let mut response_senders = Vec::new();
     let mut response_receivers = BTreeMap::new();
 
+    let mut swarm_control_senders = Vec::new();
+
     let (statistics_sender, statistics_receiver) = unbounded();
 
     let server_start_instant = ServerStartInstant::new();
 
+    let mut torrent_maps = if config.snapshot.enabled {
+        workers::swarm::snapshot::load_snapshots(&config, server_start_instant)
+    } else {
+        Vec::new()
+    }
+    .into_iter();
+
     for i in 0..config.swarm_workers {
         let (request_sender, request_receiver) = if config.worker_channel_size == 0 {
             unbounded()
@@ -75,6 +129,13 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let request_receiver = request_receivers.remove(&i).unwrap().clone();
         let response_sender = ConnectedResponseSender::new(response_senders.clone());
         let statistics_sender = statistics_sender.clone();
+        let torrents = torrent_maps
+            .next()
+            .unwrap_or_else(|| TorrentMaps::new(&config));
+
+        let (control_sender, control_receiver) = unbounded();
+
+        swarm_control_senders.push(control_sender);
 
         Builder::new()
             .name(format!("swarm-{:02}", i + 1))
@@ -92,7 +153,9 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     config,
                     state,
                     server_start_instant,
+                    torrents,
                     request_receiver,
+                    control_receiver,
                     response_sender,
                     statistics_sender,
                     SwarmWorkerIndex(i),
@@ -136,7 +199,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             .with_context(|| "spawn socket worker")?;
     }
 
-    if config.statistics.active() {
+    if config.statistics_active() {
         let sentinel = sentinel.clone();
         let state = state.clone();
         let config = config.clone();
@@ -174,12 +237,18 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         match signal {
             SIGUSR1 => {
                 let _ = update_access_list(&config.access_list, &state.access_list);
+                let _ = update_ip_list(&config.ip_list, &state.ip_list);
+                let _ = connection_validator.update_keys(&config);
             }
             SIGTERM => {
                 if sentinel_watcher.panic_was_triggered() {
                     return Err(anyhow::anyhow!("worker thread panicked"));
                 }
 
+                if config.snapshot.enabled {
+                    save_snapshots(&swarm_control_senders);
+                }
+
                 break;
             }
             _ => unreachable!(),
@@ -188,3 +257,29 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Ask swarm workers to save snapshots and wait for them to finish
+fn save_snapshots(swarm_control_senders: &[Sender<SwarmControlMessage>]) {
+    let (done_sender, done_receiver) = unbounded();
+
+    let mut num_pending = 0usize;
+
+    for sender in swarm_control_senders {
+        match sender.send(SwarmControlMessage::SaveSnapshot(done_sender.clone())) {
+            Ok(()) => num_pending += 1,
+            Err(err) => ::log::error!("Couldn't request snapshot from swarm worker: {:#}", err),
+        }
+    }
+
+    drop(done_sender);
+
+    ::log::info!("Saving snapshots..");
+
+    for _ in 0..num_pending {
+        if done_receiver.recv().is_err() {
+            break;
+        }
+    }
+
+    ::log::info!("Finished saving snapshots");
+}
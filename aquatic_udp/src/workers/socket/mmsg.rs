@@ -0,0 +1,402 @@
+//! Receiving and sending packets in batches with recvmmsg and sendmmsg
+//!
+//! Buffers, addresses and message headers are allocated once and reused.
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor};
+use std::mem::size_of;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+
+use socket2::SockAddr;
+
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::Response;
+
+use crate::common::BUFFER_SIZE;
+
+pub struct RecvBatch {
+    buffers: Vec<[u8; BUFFER_SIZE]>,
+    addresses: Vec<libc::sockaddr_storage>,
+    iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+}
+
+impl RecvBatch {
+    pub fn new(batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+
+        let mut batch = Self {
+            buffers: vec![[0; BUFFER_SIZE]; batch_size],
+            addresses: vec![unsafe { ::std::mem::zeroed() }; batch_size],
+            iovecs: Vec::with_capacity(batch_size),
+            headers: Vec::with_capacity(batch_size),
+        };
+
+        for buffer in batch.buffers.iter_mut() {
+            batch.iovecs.push(libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            });
+        }
+
+        // Vectors are never resized after this point, so pointers into them
+        // remain valid
+        for (address, iovec) in batch.addresses.iter_mut().zip(batch.iovecs.iter_mut()) {
+            let mut header: libc::mmsghdr = unsafe { ::std::mem::zeroed() };
+
+            header.msg_hdr.msg_name = address as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+
+            batch.headers.push(header);
+        }
+
+        batch
+    }
+
+    /// Receive as many packets as are available (up to batch size) without
+    /// blocking. Returns number of packets received.
+    pub fn recv(&mut self, fd: RawFd) -> io::Result<usize> {
+        for header in self.headers.iter_mut() {
+            header.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_hdr.msg_flags = 0;
+            header.msg_len = 0;
+        }
+
+        let result = unsafe {
+            libc::recvmmsg(
+                fd,
+                self.headers.as_mut_ptr(),
+                self.headers.len() as _,
+                0,
+                ::std::ptr::null_mut(),
+            )
+        };
+
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result as usize)
+        }
+    }
+
+    /// Get packet contents and source address of received packet with given
+    /// index. Address is None if it is not an IPv4 or IPv6 address.
+    pub fn get(&self, index: usize) -> (&[u8], Option<SocketAddr>) {
+        let header = &self.headers[index];
+
+        let len = (header.msg_len as usize).min(BUFFER_SIZE);
+        let addr = unsafe { SockAddr::new(self.addresses[index], header.msg_hdr.msg_namelen) };
+
+        (&self.buffers[index][..len], addr.as_socket())
+    }
+}
+
+pub struct SendBatch {
+    buffers: Vec<[u8; BUFFER_SIZE]>,
+    addresses: Vec<SockAddr>,
+    iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+    /// Queued responses, kept for updating statistics and resending. The
+    /// response at index `i` is stored in buffer slot `i`.
+    responses: VecDeque<(Response, CanonicalSocketAddr)>,
+}
+
+impl SendBatch {
+    pub fn new(batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+
+        let mut batch = Self {
+            buffers: vec![[0; BUFFER_SIZE]; batch_size],
+            addresses: vec![
+                SockAddr::from(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+                batch_size
+            ],
+            iovecs: Vec::with_capacity(batch_size),
+            headers: vec![unsafe { ::std::mem::zeroed() }; batch_size],
+            responses: VecDeque::with_capacity(batch_size),
+        };
+
+        for buffer in batch.buffers.iter_mut() {
+            batch.iovecs.push(libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: 0,
+            });
+        }
+
+        batch
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.responses.len() == self.buffers.len()
+    }
+
+    /// Encode response and add it to batch. Batch must not be full.
+    pub fn push(
+        &mut self,
+        response: Response,
+        canonical_addr: CanonicalSocketAddr,
+        addr: SocketAddr,
+    ) -> io::Result<()> {
+        let index = self.responses.len();
+
+        let mut cursor = Cursor::new(&mut self.buffers[index][..]);
+
+        response.write(&mut cursor)?;
+
+        self.iovecs[index].iov_len = cursor.position() as usize;
+        self.addresses[index] = SockAddr::from(addr);
+        self.update_header(index);
+
+        self.responses.push_back((response, canonical_addr));
+
+        Ok(())
+    }
+
+    /// Send queued responses, calling `handle_result` with number of bytes
+    /// sent or error for each one, in order
+    ///
+    /// If the socket send buffer is full, sending stops and the remaining
+    /// responses stay queued, so that they can be sent as a batch when the
+    /// socket is writable again. Returns true if all responses were sent.
+    pub fn send(
+        &mut self,
+        fd: RawFd,
+        mut handle_result: impl FnMut(Response, CanonicalSocketAddr, io::Result<usize>),
+    ) -> bool {
+        let num_queued = self.responses.len();
+        let mut offset = 0;
+
+        while offset < num_queued {
+            let result = unsafe {
+                libc::sendmmsg(
+                    fd,
+                    self.headers[offset..num_queued].as_mut_ptr(),
+                    (num_queued - offset) as _,
+                    0,
+                )
+            };
+
+            if result > 0 {
+                let num_sent = result as usize;
+
+                for header in self.headers[offset..offset + num_sent].iter() {
+                    if let Some((response, addr)) = self.responses.pop_front() {
+                        handle_result(response, addr, Ok(header.msg_len as usize));
+                    }
+                }
+
+                offset += num_sent;
+            } else if result == 0 {
+                // No messages were sent and no error was reported, so try
+                // again later
+                break;
+            } else {
+                let err = io::Error::last_os_error();
+
+                match err.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    io::ErrorKind::WouldBlock => break,
+                    _ => (),
+                }
+
+                // Skip message that couldn't be sent and try sending the
+                // remaining ones
+                if let Some((response, addr)) = self.responses.pop_front() {
+                    handle_result(response, addr, Err(err));
+                }
+
+                offset += 1;
+            }
+        }
+
+        if offset > 0 && offset < num_queued {
+            self.move_to_front(offset, num_queued);
+        }
+
+        self.responses.is_empty()
+    }
+
+    /// Move contents of slots `offset..end` to start of buffers
+    fn move_to_front(&mut self, offset: usize, end: usize) {
+        self.buffers[..end].rotate_left(offset);
+        self.addresses[..end].rotate_left(offset);
+
+        for index in 0..(end - offset) {
+            self.iovecs[index].iov_len = self.iovecs[index + offset].iov_len;
+            self.update_header(index);
+        }
+    }
+
+    fn update_header(&mut self, index: usize) {
+        let header = &mut self.headers[index];
+
+        header.msg_hdr.msg_name = self.addresses[index].as_ptr() as *mut libc::c_void;
+        header.msg_hdr.msg_namelen = self.addresses[index].len();
+        header.msg_hdr.msg_iov = &mut self.iovecs[index];
+        header.msg_hdr.msg_iovlen = 1;
+        header.msg_len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv6Addr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    use aquatic_udp_protocol::{ConnectResponse, ConnectionId, TransactionId};
+
+    use super::*;
+
+    fn create_response(i: usize) -> Response {
+        Response::Connect(ConnectResponse {
+            connection_id: ConnectionId(i as i64),
+            transaction_id: TransactionId(i as i32),
+        })
+    }
+
+    fn recv_responses(
+        socket: &UdpSocket,
+        sender_addr: SocketAddr,
+        num_responses: usize,
+    ) -> Vec<Response> {
+        let mut recv_batch = RecvBatch::new(num_responses);
+        let mut responses = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while responses.len() < num_responses {
+            match recv_batch.recv(socket.as_raw_fd()) {
+                Ok(num_received) => {
+                    for i in 0..num_received {
+                        let (bytes, opt_src) = recv_batch.get(i);
+
+                        assert_eq!(opt_src, Some(sender_addr));
+
+                        responses.push(Response::from_bytes(bytes, true).unwrap());
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "timed out receiving");
+
+                    ::std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(err) => panic!("recvmmsg failed: {:#}", err),
+            }
+        }
+
+        responses
+    }
+
+    fn test_send_and_recv_batch(ip: IpAddr) {
+        let sender = UdpSocket::bind((ip, 0)).unwrap();
+        let receiver = UdpSocket::bind((ip, 0)).unwrap();
+
+        receiver.set_nonblocking(true).unwrap();
+
+        let receiver_addr = receiver.local_addr().unwrap();
+        let num_responses = 16;
+
+        let mut send_batch = SendBatch::new(num_responses);
+
+        for i in 0..num_responses {
+            send_batch
+                .push(
+                    create_response(i),
+                    CanonicalSocketAddr::new(receiver_addr),
+                    receiver_addr,
+                )
+                .unwrap();
+        }
+
+        assert!(send_batch.is_full());
+
+        let mut sent = Vec::new();
+
+        let all_sent = send_batch.send(sender.as_raw_fd(), |response, addr, result| {
+            assert_eq!(addr.get(), receiver_addr);
+            assert_eq!(result.unwrap(), 16);
+
+            sent.push(response);
+        });
+
+        assert!(all_sent);
+        assert!(send_batch.is_empty());
+
+        let expected: Vec<Response> = (0..num_responses).map(create_response).collect();
+
+        assert_eq!(sent, expected);
+        assert_eq!(
+            recv_responses(&receiver, sender.local_addr().unwrap(), num_responses),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_send_and_recv_batch_ipv4() {
+        test_send_and_recv_batch(Ipv4Addr::LOCALHOST.into());
+    }
+
+    #[test]
+    fn test_send_and_recv_batch_ipv6() {
+        if UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).is_err() {
+            eprintln!("IPv6 loopback not available, skipping test");
+
+            return;
+        }
+
+        test_send_and_recv_batch(Ipv6Addr::LOCALHOST.into());
+    }
+
+    /// Check that responses left over after a partial send are moved to
+    /// the start of the batch and sent in order
+    #[test]
+    fn test_send_unsent_tail() {
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        receiver.set_nonblocking(true).unwrap();
+
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        let mut send_batch = SendBatch::new(4);
+
+        for i in 0..4 {
+            send_batch
+                .push(
+                    create_response(i),
+                    CanonicalSocketAddr::new(receiver_addr),
+                    receiver_addr,
+                )
+                .unwrap();
+        }
+
+        // Simulate first two responses having been sent
+        send_batch.responses.drain(..2);
+        send_batch.move_to_front(2, 4);
+
+        send_batch
+            .push(
+                create_response(4),
+                CanonicalSocketAddr::new(receiver_addr),
+                receiver_addr,
+            )
+            .unwrap();
+
+        assert!(send_batch.send(sender.as_raw_fd(), |_, _, result| {
+            result.unwrap();
+        }));
+
+        let expected: Vec<Response> = (2..5).map(create_response).collect();
+
+        assert_eq!(
+            recv_responses(&receiver, sender.local_addr().unwrap(), 3),
+            expected
+        );
+    }
+}
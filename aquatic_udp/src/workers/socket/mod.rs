@@ -1,7 +1,16 @@
+#[cfg(target_os = "linux")]
+mod mmsg;
+mod proxy_protocol;
+mod rate_limiter;
 mod storage;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
 pub mod validator;
 
-use std::io::{Cursor, ErrorKind};
+use std::io::{self, Cursor, ErrorKind};
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
 use std::sync::atomic::Ordering;
 #[cfg(fuzzing)]
 pub use storage::tests::test_pending_scrape_response_slab;
@@ -10,6 +19,7 @@ use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use aquatic_common::access_list::AccessListCache;
+use aquatic_common::ip_list::{create_ip_list_cache, IpListCache};
 use aquatic_common::ServerStartInstant;
 use crossbeam_channel::Receiver;
 use mio::net::UdpSocket;
@@ -23,8 +33,12 @@ use aquatic_common::{
 use aquatic_udp_protocol::*;
 
 use crate::common::*;
-use crate::config::Config;
+use crate::config::{Config, SocketIo};
 
+#[cfg(target_os = "linux")]
+use mmsg::{RecvBatch, SendBatch};
+use proxy_protocol::ProxyProtocolHandler;
+use rate_limiter::RateLimiter;
 use storage::PendingScrapeResponseSlab;
 use validator::ConnectionValidator;
 
@@ -52,9 +66,12 @@ pub struct SocketWorker {
     request_sender: ConnectedRequestSender,
     response_receiver: Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
     access_list_cache: AccessListCache,
+    ip_list_cache: IpListCache,
     validator: ConnectionValidator,
     server_start_instant: ServerStartInstant,
     pending_scrape_responses: PendingScrapeResponseSlab,
+    opt_rate_limiter: Option<RateLimiter>,
+    proxy_protocol: ProxyProtocolHandler,
     socket: UdpSocket,
     buffer: [u8; BUFFER_SIZE],
 }
@@ -73,6 +90,12 @@ impl SocketWorker {
         let socket =
             UdpSocket::from_std(create_socket(&config, priv_dropper).expect("create socket"));
         let access_list_cache = create_access_list_cache(&shared_state.access_list);
+        let ip_list_cache = create_ip_list_cache(&shared_state.ip_list);
+        let opt_rate_limiter = config
+            .rate_limit
+            .enabled
+            .then(|| RateLimiter::new(&config.rate_limit));
+        let proxy_protocol = ProxyProtocolHandler::new(shared_state.proxy_protocol_sources.clone());
 
         let mut worker = Self {
             config,
@@ -82,11 +105,24 @@ impl SocketWorker {
             request_sender,
             response_receiver,
             access_list_cache,
+            ip_list_cache,
             pending_scrape_responses: Default::default(),
+            opt_rate_limiter,
+            proxy_protocol,
             socket,
             buffer: [0; BUFFER_SIZE],
         };
 
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if worker.config.network.socket_io == SocketIo::IoUring {
+            // Panicking triggers the sentinel, which shuts down the tracker
+            if let Err(err) = worker.run_io_uring() {
+                panic!("io_uring socket worker failed: {:#}", err);
+            }
+
+            return;
+        }
+
         worker.run_inner();
     }
 
@@ -94,6 +130,11 @@ impl SocketWorker {
         let mut local_responses = Vec::new();
         let mut opt_resend_buffer =
             (self.config.network.resend_buffer_max_len > 0).then_some(Vec::new());
+        let mut send_buffer = SendBuffer::new(&self.config);
+
+        #[cfg(target_os = "linux")]
+        let mut opt_recv_batch = (self.config.network.socket_io == SocketIo::Mmsg)
+            .then(|| RecvBatch::new(self.config.network.mmsg_batch_size));
 
         let mut events = Events::with_capacity(self.config.network.poll_event_capacity);
         let mut poll = Poll::new().expect("create poll");
@@ -102,6 +143,10 @@ impl SocketWorker {
             .register(&mut self.socket, Token(0), Interest::READABLE)
             .expect("register poll");
 
+        // Set when responses queued in send buffer couldn't be sent because
+        // socket send buffer was full
+        let mut wait_for_writable = false;
+
         let poll_timeout = Duration::from_millis(self.config.network.poll_timeout_ms);
 
         let pending_scrape_cleaning_duration =
@@ -119,8 +164,21 @@ impl SocketWorker {
             poll.poll(&mut events, Some(poll_timeout))
                 .expect("failed polling");
 
+            // Writable events need no handling of their own, since queued
+            // responses are flushed below on every iteration
             for event in events.iter() {
                 if event.is_readable() {
+                    #[cfg(target_os = "linux")]
+                    if let Some(recv_batch) = opt_recv_batch.as_mut() {
+                        self.read_and_handle_request_batches(
+                            recv_batch,
+                            &mut local_responses,
+                            pending_scrape_valid_until,
+                        );
+
+                        continue;
+                    }
+
                     self.read_and_handle_requests(&mut local_responses, pending_scrape_valid_until);
                 }
             }
@@ -132,12 +190,22 @@ impl SocketWorker {
                         &self.config,
                         &self.shared_state,
                         &mut self.socket,
-                        &mut self.buffer,
+                        &mut send_buffer,
                         &mut None,
                         response,
                         addr,
                     );
                 }
+
+                // Flush before continuing, so that responses are only resent
+                // once
+                Self::flush_send_buffer(
+                    &self.config,
+                    &self.shared_state,
+                    &mut self.socket,
+                    &mut send_buffer,
+                    &mut None,
+                );
             }
 
             // Send any connect and error responses generated by this socket worker
@@ -146,56 +214,89 @@ impl SocketWorker {
                     &self.config,
                     &self.shared_state,
                     &mut self.socket,
-                    &mut self.buffer,
+                    &mut send_buffer,
                     &mut opt_resend_buffer,
                     response,
-                    addr,
+                    self.proxy_protocol.reply_addr(addr),
                 );
             }
 
             // Check channel for any responses generated by swarm workers
             for (response, addr) in self.response_receiver.try_iter() {
-                let opt_response = match response {
-                    ConnectedResponse::Scrape(r) => self
-                        .pending_scrape_responses
-                        .add_and_get_finished(r)
-                        .map(Response::Scrape),
-                    ConnectedResponse::AnnounceIpv4(r) => Some(Response::AnnounceIpv4(r)),
-                    ConnectedResponse::AnnounceIpv6(r) => Some(Response::AnnounceIpv6(r)),
-                };
-
-                if let Some(response) = opt_response {
+                if let Some(response) =
+                    finish_connected_response(&mut self.pending_scrape_responses, response)
+                {
                     Self::send_response(
                         &self.config,
                         &self.shared_state,
                         &mut self.socket,
-                        &mut self.buffer,
+                        &mut send_buffer,
                         &mut opt_resend_buffer,
                         response,
-                        addr,
+                        self.proxy_protocol.reply_addr(addr),
                     );
                 }
             }
 
+            let all_sent = Self::flush_send_buffer(
+                &self.config,
+                &self.shared_state,
+                &mut self.socket,
+                &mut send_buffer,
+                &mut opt_resend_buffer,
+            );
+
+            if all_sent == wait_for_writable {
+                let interest = if all_sent {
+                    Interest::READABLE
+                } else {
+                    Interest::READABLE | Interest::WRITABLE
+                };
+
+                poll.registry()
+                    .reregister(&mut self.socket, Token(0), interest)
+                    .expect("reregister poll");
+
+                wait_for_writable = !all_sent;
+            }
+
             // Run periodic ValidUntil updates and state cleaning
             if iter_counter % 256 == 0 {
-                let seconds_since_start = self.server_start_instant.seconds_elapsed();
-
-                pending_scrape_valid_until = ValidUntil::new_with_now(
-                    seconds_since_start,
-                    self.config.cleaning.max_pending_scrape_age,
+                self.run_periodic_tasks(
+                    &mut pending_scrape_valid_until,
+                    &mut last_pending_scrape_cleaning,
+                    pending_scrape_cleaning_duration,
                 );
+            }
 
-                let now = Instant::now();
+            iter_counter = iter_counter.wrapping_add(1);
+        }
+    }
 
-                if now > last_pending_scrape_cleaning + pending_scrape_cleaning_duration {
-                    self.pending_scrape_responses.clean(seconds_since_start);
+    fn run_periodic_tasks(
+        &mut self,
+        pending_scrape_valid_until: &mut ValidUntil,
+        last_pending_scrape_cleaning: &mut Instant,
+        pending_scrape_cleaning_duration: Duration,
+    ) {
+        let seconds_since_start = self.server_start_instant.seconds_elapsed();
 
-                    last_pending_scrape_cleaning = now;
-                }
+        *pending_scrape_valid_until = ValidUntil::new_with_now(
+            seconds_since_start,
+            self.config.cleaning.max_pending_scrape_age,
+        );
+
+        let now = Instant::now();
+
+        if now > *last_pending_scrape_cleaning + pending_scrape_cleaning_duration {
+            self.pending_scrape_responses.clean(seconds_since_start);
+            self.proxy_protocol.clean(seconds_since_start);
+
+            if let Some(rate_limiter) = self.opt_rate_limiter.as_mut() {
+                rate_limiter.clean(now);
             }
 
-            iter_counter = iter_counter.wrapping_add(1);
+            *last_pending_scrape_cleaning = now;
         }
     }
 
@@ -204,101 +305,218 @@ impl SocketWorker {
         local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
         pending_scrape_valid_until: ValidUntil,
     ) {
-        let mut requests_received_ipv4: usize = 0;
-        let mut requests_received_ipv6: usize = 0;
-        let mut bytes_received_ipv4: usize = 0;
-        let mut bytes_received_ipv6 = 0;
+        let mut statistics = ReceiveStatistics::default();
+
+        let now = Instant::now();
 
         loop {
             match self.socket.recv_from(&mut self.buffer[..]) {
                 Ok((bytes_read, src)) => {
-                    if src.port() == 0 {
-                        ::log::info!("Ignored request from {} because source port is zero", src);
-
-                        continue;
-                    }
+                    let (src, payload_start) = match self.proxy_protocol.handle_datagram(
+                        src,
+                        &self.buffer[..bytes_read],
+                        pending_scrape_valid_until,
+                    ) {
+                        Some(src_and_payload_start) => src_and_payload_start,
+                        None => continue,
+                    };
 
-                    let src = CanonicalSocketAddr::new(src);
+                    let src = match self.filter_source(src) {
+                        Some(src) => src,
+                        None => continue,
+                    };
 
-                    let request_parsable = match Request::from_bytes(
-                        &self.buffer[..bytes_read],
+                    let request_parse_result = Request::from_bytes(
+                        &self.buffer[payload_start..bytes_read],
                         self.config.protocol.max_scrape_torrents,
-                    ) {
-                        Ok(request) => {
-                            self.handle_request(
-                                local_responses,
-                                pending_scrape_valid_until,
-                                request,
+                    );
+
+                    statistics.add_bytes(src, bytes_read);
+
+                    self.handle_request_parse_result(
+                        local_responses,
+                        &mut statistics,
+                        pending_scrape_valid_until,
+                        now,
+                        request_parse_result,
+                        src,
+                    );
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    ::log::warn!("recv_from error: {:#}", err);
+                }
+            }
+        }
+
+        if self.config.statistics_active() {
+            statistics.add_to_shared(&self.shared_state);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_and_handle_request_batches(
+        &mut self,
+        recv_batch: &mut RecvBatch,
+        local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
+        pending_scrape_valid_until: ValidUntil,
+    ) {
+        let mut statistics = ReceiveStatistics::default();
+
+        let now = Instant::now();
+
+        loop {
+            match recv_batch.recv(self.socket.as_raw_fd()) {
+                Ok(0) => {
+                    break;
+                }
+                Ok(num_received) => {
+                    for i in 0..num_received {
+                        let (packet, opt_src) = recv_batch.get(i);
+
+                        let (src, payload_start) = match opt_src.and_then(|src| {
+                            self.proxy_protocol.handle_datagram(
                                 src,
-                            );
-
-                            true
-                        }
-                        Err(err) => {
-                            ::log::debug!("Request::from_bytes error: {:?}", err);
-
-                            if let RequestParseError::Sendable {
-                                connection_id,
-                                transaction_id,
-                                err,
-                            } = err
-                            {
-                                if self.validator.connection_id_valid(src, connection_id) {
-                                    let response = ErrorResponse {
-                                        transaction_id,
-                                        message: err.right_or("Parse error").into(),
-                                    };
-
-                                    local_responses.push((response.into(), src));
-                                }
-                            }
-
-                            false
-                        }
-                    };
+                                packet,
+                                pending_scrape_valid_until,
+                            )
+                        }) {
+                            Some(src_and_payload_start) => src_and_payload_start,
+                            None => continue,
+                        };
+
+                        let src = match self.filter_source(src) {
+                            Some(src) => src,
+                            None => continue,
+                        };
+
+                        let request_parse_result = Request::from_bytes(
+                            &packet[payload_start..],
+                            self.config.protocol.max_scrape_torrents,
+                        );
 
-                    // Update statistics for converted address
-                    if src.is_ipv4() {
-                        if request_parsable {
-                            requests_received_ipv4 += 1;
-                        }
-                        bytes_received_ipv4 += bytes_read + EXTRA_PACKET_SIZE_IPV4;
-                    } else {
-                        if request_parsable {
-                            requests_received_ipv6 += 1;
-                        }
-                        bytes_received_ipv6 += bytes_read + EXTRA_PACKET_SIZE_IPV6;
+                        statistics.add_bytes(src, packet.len());
+
+                        self.handle_request_parse_result(
+                            local_responses,
+                            &mut statistics,
+                            pending_scrape_valid_until,
+                            now,
+                            request_parse_result,
+                            src,
+                        );
                     }
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
                     break;
                 }
                 Err(err) => {
-                    ::log::warn!("recv_from error: {:#}", err);
+                    ::log::warn!("recvmmsg error: {:#}", err);
                 }
             }
         }
 
-        if self.config.statistics.active() {
-            self.shared_state
-                .statistics_ipv4
-                .requests_received
-                .fetch_add(requests_received_ipv4, Ordering::Relaxed);
-            self.shared_state
-                .statistics_ipv6
-                .requests_received
-                .fetch_add(requests_received_ipv6, Ordering::Relaxed);
-            self.shared_state
-                .statistics_ipv4
-                .bytes_received
-                .fetch_add(bytes_received_ipv4, Ordering::Relaxed);
-            self.shared_state
-                .statistics_ipv6
-                .bytes_received
-                .fetch_add(bytes_received_ipv6, Ordering::Relaxed);
+        if self.config.statistics_active() {
+            statistics.add_to_shared(&self.shared_state);
+        }
+    }
+
+    /// Returns canonical source address if requests from it should be
+    /// handled
+    fn filter_source(&mut self, src: SocketAddr) -> Option<CanonicalSocketAddr> {
+        if src.port() == 0 {
+            ::log::info!("Ignored request from {} because source port is zero", src);
+
+            return None;
+        }
+
+        let src = CanonicalSocketAddr::new(src);
+
+        if !self
+            .ip_list_cache
+            .load()
+            .allows(self.config.ip_list.mode, src.get().ip())
+        {
+            ::log::debug!("Ignored request from {} because of IP list", src.get());
+
+            return None;
+        }
+
+        Some(src)
+    }
+
+    fn handle_request_parse_result(
+        &mut self,
+        local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
+        statistics: &mut ReceiveStatistics,
+        pending_scrape_valid_until: ValidUntil,
+        now: Instant,
+        request_parse_result: Result<Request, RequestParseError>,
+        src: CanonicalSocketAddr,
+    ) {
+        match request_parse_result {
+            Ok(request) => {
+                // Check connection ID before taking rate limit tokens, since
+                // source addresses can be spoofed to drain buckets of others
+                let connection_id_valid = match &request {
+                    Request::Connect(_) => true,
+                    Request::Announce(request) => self
+                        .validator
+                        .connection_id_valid(src, request.connection_id),
+                    Request::Scrape(request) => self
+                        .validator
+                        .connection_id_valid(src, request.connection_id),
+                };
+
+                if !connection_id_valid {
+                    statistics.add_request(src, &request);
+
+                    return;
+                }
+
+                let rate_limited = match self.opt_rate_limiter.as_mut() {
+                    Some(rate_limiter) => !rate_limiter.allows(src.get().ip(), &request, now),
+                    None => false,
+                };
+
+                if rate_limited {
+                    self.handle_rate_limited_request(local_responses, request, src);
+
+                    statistics.add_rate_limited_request(src);
+                } else {
+                    statistics.add_request(src, &request);
+
+                    self.handle_request(local_responses, pending_scrape_valid_until, request, src);
+                }
+            }
+            Err(err) => {
+                ::log::debug!("Request::from_bytes error: {:?}", err);
+
+                statistics.add_error_request(src);
+
+                if let RequestParseError::Sendable {
+                    connection_id,
+                    transaction_id,
+                    err,
+                } = err
+                {
+                    if self.validator.connection_id_valid(src, connection_id) {
+                        let response = ErrorResponse {
+                            transaction_id,
+                            message: err.right_or("Parse error").into(),
+                        };
+
+                        local_responses.push((response.into(), src));
+                    }
+                }
+            }
         }
     }
 
+    /// Handle request. Connection IDs of announce and scrape requests must
+    /// already have been validated.
     fn handle_request(
         &mut self,
         local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
@@ -321,84 +539,195 @@ impl SocketWorker {
             }
             Request::Announce(request) => {
                 if self
-                    .validator
-                    .connection_id_valid(src, request.connection_id)
+                    .access_list_cache
+                    .load()
+                    .allows(access_list_mode, &request.info_hash.0)
                 {
-                    if self
-                        .access_list_cache
-                        .load()
-                        .allows(access_list_mode, &request.info_hash.0)
-                    {
-                        let worker_index =
-                            SwarmWorkerIndex::from_info_hash(&self.config, request.info_hash);
-
-                        self.request_sender.try_send_to(
-                            worker_index,
-                            ConnectedRequest::Announce(request),
-                            src,
-                        );
-                    } else {
-                        let response = Response::Error(ErrorResponse {
-                            transaction_id: request.transaction_id,
-                            message: "Info hash not allowed".into(),
-                        });
+                    let worker_index =
+                        SwarmWorkerIndex::from_info_hash(&self.config, request.info_hash);
 
-                        local_responses.push((response, src))
-                    }
+                    self.request_sender.try_send_to(
+                        worker_index,
+                        ConnectedRequest::Announce(request),
+                        src,
+                    );
+                } else {
+                    let response = Response::Error(ErrorResponse {
+                        transaction_id: request.transaction_id,
+                        message: "Info hash not allowed".into(),
+                    });
+
+                    local_responses.push((response, src))
                 }
             }
             Request::Scrape(request) => {
-                if self
-                    .validator
-                    .connection_id_valid(src, request.connection_id)
-                {
-                    let split_requests = self.pending_scrape_responses.prepare_split_requests(
-                        &self.config,
-                        request,
-                        pending_scrape_valid_until,
-                    );
+                let split_requests = self.pending_scrape_responses.prepare_split_requests(
+                    &self.config,
+                    request,
+                    pending_scrape_valid_until,
+                );
 
-                    for (swarm_worker_index, request) in split_requests {
-                        self.request_sender.try_send_to(
-                            swarm_worker_index,
-                            ConnectedRequest::Scrape(request),
-                            src,
-                        );
-                    }
+                for (swarm_worker_index, request) in split_requests {
+                    self.request_sender.try_send_to(
+                        swarm_worker_index,
+                        ConnectedRequest::Scrape(request),
+                        src,
+                    );
                 }
             }
         }
     }
 
+    /// Optionally send error response to rate limited request. Connection
+    /// IDs of announce and scrape requests have already been validated, so
+    /// this can't be used for reflection.
+    fn handle_rate_limited_request(
+        &mut self,
+        local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
+        request: Request,
+        src: CanonicalSocketAddr,
+    ) {
+        ::log::debug!("Rate limited request from {}", src.get());
+
+        if !self.config.rate_limit.send_error_responses {
+            return;
+        }
+
+        let transaction_id = match request {
+            Request::Connect(_) => return,
+            Request::Announce(request) => request.transaction_id,
+            Request::Scrape(request) => request.transaction_id,
+        };
+
+        let response = ErrorResponse {
+            transaction_id,
+            message: "Rate limit exceeded".into(),
+        };
+
+        local_responses.push((response.into(), src));
+    }
+
     fn send_response(
         config: &Config,
         shared_state: &State,
         socket: &mut UdpSocket,
-        buffer: &mut [u8],
+        send_buffer: &mut SendBuffer,
         opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
         response: Response,
         canonical_addr: CanonicalSocketAddr,
     ) {
-        let mut cursor = Cursor::new(buffer);
+        let addr = destination_addr(config, canonical_addr);
 
-        if let Err(err) = response.write(&mut cursor) {
-            ::log::error!("Converting response to bytes failed: {:#}", err);
+        match send_buffer {
+            SendBuffer::Single(buffer) => {
+                let mut cursor = Cursor::new(&mut buffer[..]);
 
-            return;
+                if let Err(err) = response.write(&mut cursor) {
+                    ::log::error!("Converting response to bytes failed: {:#}", err);
+
+                    return;
+                }
+
+                let bytes_written = cursor.position() as usize;
+
+                let result = socket.send_to(&cursor.get_ref()[..bytes_written], addr);
+
+                Self::handle_send_result(
+                    config,
+                    shared_state,
+                    opt_resend_buffer,
+                    response,
+                    canonical_addr,
+                    result,
+                );
+            }
+            #[cfg(target_os = "linux")]
+            SendBuffer::Batch(batch) => {
+                if batch.is_full()
+                    && !Self::flush_send_batch(
+                        config,
+                        shared_state,
+                        socket,
+                        batch,
+                        opt_resend_buffer,
+                    )
+                {
+                    // Batch is still full of responses waiting for socket
+                    // to become writable
+                    Self::handle_send_result(
+                        config,
+                        shared_state,
+                        opt_resend_buffer,
+                        response,
+                        canonical_addr,
+                        Err(ErrorKind::WouldBlock.into()),
+                    );
+
+                    return;
+                }
+
+                if let Err(err) = batch.push(response, canonical_addr, addr) {
+                    ::log::error!("Converting response to bytes failed: {:#}", err);
+                }
+            }
+        }
+    }
+
+    /// Send any responses queued in batch. Returns false if some of them
+    /// remain queued because the socket is not writable.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn flush_send_buffer(
+        config: &Config,
+        shared_state: &State,
+        socket: &mut UdpSocket,
+        send_buffer: &mut SendBuffer,
+        opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
+    ) -> bool {
+        match send_buffer {
+            SendBuffer::Single(_) => true,
+            #[cfg(target_os = "linux")]
+            SendBuffer::Batch(batch) => {
+                Self::flush_send_batch(config, shared_state, socket, batch, opt_resend_buffer)
+            }
         }
+    }
 
-        let bytes_written = cursor.position() as usize;
+    #[cfg(target_os = "linux")]
+    fn flush_send_batch(
+        config: &Config,
+        shared_state: &State,
+        socket: &mut UdpSocket,
+        batch: &mut SendBatch,
+        opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
+    ) -> bool {
+        if batch.is_empty() {
+            return true;
+        }
 
-        let addr = if config.network.address.is_ipv4() {
-            canonical_addr
-                .get_ipv4()
-                .expect("found peer ipv6 address while running bound to ipv4 address")
-        } else {
-            canonical_addr.get_ipv6_mapped()
-        };
+        batch.send(socket.as_raw_fd(), |response, canonical_addr, result| {
+            Self::handle_send_result(
+                config,
+                shared_state,
+                opt_resend_buffer,
+                response,
+                canonical_addr,
+                result,
+            )
+        })
+    }
 
-        match socket.send_to(&cursor.get_ref()[..bytes_written], addr) {
-            Ok(amt) if config.statistics.active() => {
+    /// Update statistics after successful send, or add response to resend
+    /// buffer if sending failed because of full buffers
+    fn handle_send_result(
+        config: &Config,
+        shared_state: &State,
+        opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
+        response: Response,
+        canonical_addr: CanonicalSocketAddr,
+        result: io::Result<usize>,
+    ) {
+        match result {
+            Ok(amt) if config.statistics_active() => {
                 let stats = if canonical_addr.is_ipv4() {
                     let stats = &shared_state.statistics_ipv4;
 
@@ -441,7 +770,7 @@ impl SocketWorker {
                         || (err.kind() == ErrorKind::WouldBlock) =>
                 {
                     if resend_buffer.len() < config.network.resend_buffer_max_len {
-                        ::log::info!("Adding response to resend queue, since sending it to {} failed with: {:#}", addr, err);
+                        ::log::info!("Adding response to resend queue, since sending it to {} failed with: {:#}", canonical_addr.get(), err);
 
                         resend_buffer.push((response, canonical_addr));
                     } else {
@@ -449,13 +778,143 @@ impl SocketWorker {
                     }
                 }
                 _ => {
-                    ::log::warn!("Sending response to {} failed: {:#}", addr, err);
+                    ::log::warn!(
+                        "Sending response to {} failed: {:#}",
+                        canonical_addr.get(),
+                        err
+                    );
                 }
             },
         }
     }
 }
 
+/// Buffer for encoding responses before sending them
+enum SendBuffer {
+    Single(Box<[u8; BUFFER_SIZE]>),
+    #[cfg(target_os = "linux")]
+    Batch(SendBatch),
+}
+
+impl SendBuffer {
+    fn new(config: &Config) -> Self {
+        #[cfg(target_os = "linux")]
+        if config.network.socket_io == SocketIo::Mmsg {
+            return Self::Batch(SendBatch::new(config.network.mmsg_batch_size));
+        }
+
+        Self::Single(Box::new([0; BUFFER_SIZE]))
+    }
+}
+
+/// Statistics for received requests, collected locally before being added
+/// to shared state
+#[derive(Default)]
+struct ReceiveStatistics {
+    ipv4: ReceiveStatisticsForIpVersion,
+    ipv6: ReceiveStatisticsForIpVersion,
+}
+
+#[derive(Default)]
+struct ReceiveStatisticsForIpVersion {
+    requests_received_connect: usize,
+    requests_received_announce: usize,
+    requests_received_scrape: usize,
+    requests_received_error: usize,
+    requests_rate_limited: usize,
+    bytes_received: usize,
+}
+
+impl ReceiveStatistics {
+    fn add_request(&mut self, src: CanonicalSocketAddr, request: &Request) {
+        let stats = self.get_mut(src);
+
+        match request {
+            Request::Connect(_) => stats.requests_received_connect += 1,
+            Request::Announce(_) => stats.requests_received_announce += 1,
+            Request::Scrape(_) => stats.requests_received_scrape += 1,
+        }
+    }
+
+    /// Add request that could not be parsed
+    fn add_error_request(&mut self, src: CanonicalSocketAddr) {
+        self.get_mut(src).requests_received_error += 1;
+    }
+
+    fn add_rate_limited_request(&mut self, src: CanonicalSocketAddr) {
+        self.get_mut(src).requests_rate_limited += 1;
+    }
+
+    fn add_bytes(&mut self, src: CanonicalSocketAddr, bytes_read: usize) {
+        if src.is_ipv4() {
+            self.ipv4.bytes_received += bytes_read + EXTRA_PACKET_SIZE_IPV4;
+        } else {
+            self.ipv6.bytes_received += bytes_read + EXTRA_PACKET_SIZE_IPV6;
+        }
+    }
+
+    fn add_to_shared(&self, shared_state: &State) {
+        for (stats, shared) in [
+            (&self.ipv4, &shared_state.statistics_ipv4),
+            (&self.ipv6, &shared_state.statistics_ipv6),
+        ] {
+            shared
+                .requests_received_connect
+                .fetch_add(stats.requests_received_connect, Ordering::Relaxed);
+            shared
+                .requests_received_announce
+                .fetch_add(stats.requests_received_announce, Ordering::Relaxed);
+            shared
+                .requests_received_scrape
+                .fetch_add(stats.requests_received_scrape, Ordering::Relaxed);
+            shared
+                .requests_received_error
+                .fetch_add(stats.requests_received_error, Ordering::Relaxed);
+            shared
+                .requests_rate_limited
+                .fetch_add(stats.requests_rate_limited, Ordering::Relaxed);
+            shared
+                .bytes_received
+                .fetch_add(stats.bytes_received, Ordering::Relaxed);
+        }
+    }
+
+    fn get_mut(&mut self, src: CanonicalSocketAddr) -> &mut ReceiveStatisticsForIpVersion {
+        if src.is_ipv4() {
+            &mut self.ipv4
+        } else {
+            &mut self.ipv6
+        }
+    }
+}
+
+/// Convert response from swarm worker to protocol response. Scrape responses
+/// are only returned once responses from all relevant swarm workers have
+/// been received.
+fn finish_connected_response(
+    pending_scrape_responses: &mut PendingScrapeResponseSlab,
+    response: ConnectedResponse,
+) -> Option<Response> {
+    match response {
+        ConnectedResponse::Scrape(r) => pending_scrape_responses
+            .add_and_get_finished(r)
+            .map(Response::Scrape),
+        ConnectedResponse::AnnounceIpv4(r) => Some(Response::AnnounceIpv4(r)),
+        ConnectedResponse::AnnounceIpv6(r) => Some(Response::AnnounceIpv6(r)),
+    }
+}
+
+/// Address to send response to, given the address family of the socket
+fn destination_addr(config: &Config, canonical_addr: CanonicalSocketAddr) -> SocketAddr {
+    if config.network.address.is_ipv4() {
+        canonical_addr
+            .get_ipv4()
+            .expect("found peer ipv6 address while running bound to ipv4 address")
+    } else {
+        canonical_addr.get_ipv6_mapped()
+    }
+}
+
 fn create_socket(
     config: &Config,
     priv_dropper: PrivilegeDropper,
@@ -0,0 +1,84 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hashbrown::HashMap;
+
+use aquatic_common::proxy_protocol::{parse_v2_header, TrustedSources};
+use aquatic_common::{CanonicalSocketAddr, SecondsSinceServerStart, ValidUntil};
+
+/// Handles PROXY protocol v2 headers on datagrams from trusted sources
+///
+/// Since responses need to be sent back through the proxy, the proxy address
+/// is stored for each client address that requests have been received from.
+pub struct ProxyProtocolHandler {
+    trusted_sources: Arc<TrustedSources>,
+    reply_addrs: HashMap<CanonicalSocketAddr, (CanonicalSocketAddr, ValidUntil)>,
+}
+
+impl ProxyProtocolHandler {
+    pub fn new(trusted_sources: Arc<TrustedSources>) -> Self {
+        Self {
+            trusted_sources,
+            reply_addrs: Default::default(),
+        }
+    }
+
+    /// Returns address to use as request source and offset of request
+    /// payload in datagram, or None if datagram should be ignored
+    pub fn handle_datagram(
+        &mut self,
+        src: SocketAddr,
+        datagram: &[u8],
+        valid_until: ValidUntil,
+    ) -> Option<(SocketAddr, usize)> {
+        if self.trusted_sources.is_empty() {
+            return Some((src, 0));
+        }
+
+        let proxy_addr = CanonicalSocketAddr::new(src);
+
+        if !self.trusted_sources.contains(proxy_addr) {
+            return Some((src, 0));
+        }
+
+        match parse_v2_header(datagram) {
+            Ok((header, len)) => {
+                let client_addr = header.client_addr(proxy_addr);
+
+                if client_addr != proxy_addr {
+                    self.reply_addrs
+                        .insert(client_addr, (proxy_addr, valid_until));
+                }
+
+                Some((client_addr.get(), len))
+            }
+            Err(err) => {
+                ::log::debug!(
+                    "Ignored datagram from {} with invalid PROXY protocol header: {}",
+                    src,
+                    err
+                );
+
+                None
+            }
+        }
+    }
+
+    /// Get address to send response for client to
+    pub fn reply_addr(&self, addr: CanonicalSocketAddr) -> CanonicalSocketAddr {
+        if self.reply_addrs.is_empty() {
+            return addr;
+        }
+
+        self.reply_addrs
+            .get(&addr)
+            .map(|(proxy_addr, _)| *proxy_addr)
+            .unwrap_or(addr)
+    }
+
+    pub fn clean(&mut self, now: SecondsSinceServerStart) {
+        self.reply_addrs
+            .retain(|_, (_, valid_until)| valid_until.valid(now));
+        self.reply_addrs.shrink_to_fit();
+    }
+}
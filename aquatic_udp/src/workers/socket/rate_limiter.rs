@@ -0,0 +1,251 @@
+use std::net::IpAddr;
+use std::time::Instant;
+
+use hashbrown::HashMap;
+
+use aquatic_udp_protocol::Request;
+
+use crate::config::RateLimitConfig;
+
+const NUM_REQUEST_KINDS: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RequestKind {
+    Connect = 0,
+    Announce = 1,
+    Scrape = 2,
+}
+
+impl RequestKind {
+    fn from_request(request: &Request) -> Self {
+        match request {
+            Request::Connect(_) => Self::Connect,
+            Request::Announce(_) => Self::Announce,
+            Request::Scrape(_) => Self::Scrape,
+        }
+    }
+}
+
+/// IP address with host bits (as determined by configured prefix length)
+/// set to zero
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum BucketKey {
+    V4(u32),
+    V6(u128),
+}
+
+impl BucketKey {
+    fn new(config: &RateLimitConfig, ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => {
+                let prefix_len = u32::from(config.ipv4_prefix_len.min(32));
+                let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+
+                Self::V4(u32::from(ip) & mask)
+            }
+            IpAddr::V6(ip) => {
+                let prefix_len = u32::from(config.ipv6_prefix_len.min(128));
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+
+                Self::V6(u128::from(ip) & mask)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Budget {
+    per_second: f32,
+    capacity: f32,
+}
+
+impl Budget {
+    fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            per_second: per_second as f32,
+            capacity: burst.max(1) as f32,
+        }
+    }
+}
+
+/// Token buckets for all request kinds for a single address or subnet
+struct Buckets {
+    last_refill: Instant,
+    tokens: [f32; NUM_REQUEST_KINDS],
+}
+
+impl Buckets {
+    fn new(budgets: &[Budget; NUM_REQUEST_KINDS], now: Instant) -> Self {
+        let mut tokens = [0.0; NUM_REQUEST_KINDS];
+
+        for (tokens, budget) in tokens.iter_mut().zip(budgets.iter()) {
+            *tokens = budget.capacity;
+        }
+
+        Self {
+            last_refill: now,
+            tokens,
+        }
+    }
+
+    fn refill(&mut self, budgets: &[Budget; NUM_REQUEST_KINDS], now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f32();
+
+        for (tokens, budget) in self.tokens.iter_mut().zip(budgets.iter()) {
+            *tokens = (*tokens + elapsed * budget.per_second).min(budget.capacity);
+        }
+
+        self.last_refill = now;
+    }
+
+    fn is_full(&self, budgets: &[Budget; NUM_REQUEST_KINDS]) -> bool {
+        self.tokens
+            .iter()
+            .zip(budgets.iter())
+            .all(|(tokens, budget)| *tokens >= budget.capacity)
+    }
+}
+
+/// Per-IP (or per-subnet) token bucket rate limiter
+///
+/// Each socket worker has its own instance.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    budgets: [Budget; NUM_REQUEST_KINDS],
+    buckets: HashMap<BucketKey, Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: config.clone(),
+            budgets: [
+                Budget::new(config.connect_per_second, config.connect_burst),
+                Budget::new(config.announce_per_second, config.announce_burst),
+                Budget::new(config.scrape_per_second, config.scrape_burst),
+            ],
+            buckets: Default::default(),
+        }
+    }
+
+    /// Take a token for this request type from bucket of source address.
+    /// Returns false if the bucket is empty, meaning that the request should
+    /// be rejected.
+    pub fn allows(&mut self, ip: IpAddr, request: &Request, now: Instant) -> bool {
+        let kind = RequestKind::from_request(request) as usize;
+
+        if self.budgets[kind].per_second == 0.0 {
+            return true;
+        }
+
+        let budgets = &self.budgets;
+
+        let buckets = self
+            .buckets
+            .entry(BucketKey::new(&self.config, ip))
+            .or_insert_with(|| Buckets::new(budgets, now));
+
+        buckets.refill(budgets, now);
+
+        let tokens = &mut buckets.tokens[kind];
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remove buckets that have been refilled completely, since they are
+    /// equivalent to new buckets
+    pub fn clean(&mut self, now: Instant) {
+        let budgets = &self.budgets;
+
+        self.buckets.retain(|_, buckets| {
+            buckets.refill(budgets, now);
+
+            !buckets.is_full(budgets)
+        });
+
+        self.buckets.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
+
+    use aquatic_udp_protocol::{ConnectRequest, TransactionId};
+
+    use super::*;
+
+    fn connect_request() -> Request {
+        Request::Connect(ConnectRequest {
+            transaction_id: TransactionId(0),
+        })
+    }
+
+    #[test]
+    fn test_bucket_key() {
+        let config = RateLimitConfig {
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            BucketKey::new(&config, Ipv4Addr::new(1, 2, 3, 4).into()),
+            BucketKey::new(&config, Ipv4Addr::new(1, 2, 3, 5).into()),
+        );
+        assert_ne!(
+            BucketKey::new(&config, Ipv4Addr::new(1, 2, 3, 4).into()),
+            BucketKey::new(&config, Ipv4Addr::new(1, 2, 4, 4).into()),
+        );
+        assert_eq!(
+            BucketKey::new(&config, Ipv6Addr::LOCALHOST.into()),
+            BucketKey::V6(0),
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let config = RateLimitConfig {
+            connect_per_second: 2,
+            connect_burst: 4,
+            ..Default::default()
+        };
+
+        let mut rate_limiter = RateLimiter::new(&config);
+
+        let ip = IpAddr::from(Ipv4Addr::new(1, 2, 3, 4));
+        let other_ip = IpAddr::from(Ipv4Addr::new(1, 2, 3, 5));
+        let request = connect_request();
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            assert!(rate_limiter.allows(ip, &request, now));
+        }
+
+        assert!(!rate_limiter.allows(ip, &request, now));
+        assert!(rate_limiter.allows(other_ip, &request, now));
+
+        let later = now + Duration::from_millis(500);
+
+        assert!(rate_limiter.allows(ip, &request, later));
+        assert!(!rate_limiter.allows(ip, &request, later));
+
+        // Bucket for other_ip has been refilled and can be removed
+        rate_limiter.clean(later);
+
+        assert_eq!(rate_limiter.buckets.len(), 1);
+
+        rate_limiter.clean(later + Duration::from_secs(10));
+
+        assert!(rate_limiter.buckets.is_empty());
+    }
+}
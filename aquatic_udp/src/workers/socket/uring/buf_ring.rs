@@ -0,0 +1,187 @@
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::io;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use io_uring::types::BufRingEntry;
+use io_uring::Submitter;
+
+/// Ring of buffers provided to the kernel for receiving packets
+///
+/// The kernel picks a buffer from the ring for each received packet. Once
+/// the packet has been handled, the buffer is returned to the ring.
+pub struct BufRing {
+    entries: *mut BufRingEntry,
+    entries_layout: Layout,
+    buffers: Box<[u8]>,
+    buffer_len: usize,
+    num_entries: u16,
+    tail: u16,
+}
+
+impl BufRing {
+    /// Allocate ring with all buffers available to kernel. Number of entries
+    /// must be a power of two.
+    pub fn new(num_entries: u16, buffer_len: usize) -> Self {
+        assert!(num_entries.is_power_of_two());
+
+        // Ring must be page aligned
+        let entries_layout = Layout::array::<BufRingEntry>(num_entries.into())
+            .and_then(|layout| layout.align_to(4096))
+            .expect("buffer ring layout");
+
+        let entries = unsafe { alloc_zeroed(entries_layout) } as *mut BufRingEntry;
+
+        if entries.is_null() {
+            handle_alloc_error(entries_layout);
+        }
+
+        let mut ring = Self {
+            entries,
+            entries_layout,
+            buffers: vec![0; usize::from(num_entries) * buffer_len].into_boxed_slice(),
+            buffer_len,
+            num_entries,
+            tail: 0,
+        };
+
+        for buffer_id in 0..num_entries {
+            ring.push_entry(buffer_id);
+        }
+
+        ring.publish_tail();
+
+        ring
+    }
+
+    /// Register ring with io_uring instance. The ring must outlive the
+    /// instance.
+    pub fn register(&self, submitter: &Submitter, buf_group_id: u16) -> io::Result<()> {
+        unsafe {
+            submitter.register_buf_ring_with_flags(
+                self.entries as u64,
+                self.num_entries,
+                buf_group_id,
+                0,
+            )
+        }
+    }
+
+    /// Get contents of buffer filled by the kernel
+    pub fn get(&self, buffer_id: u16, len: usize) -> &[u8] {
+        let start = usize::from(buffer_id) * self.buffer_len;
+
+        &self.buffers[start..start + len.min(self.buffer_len)]
+    }
+
+    /// Return buffer to ring, making it available to the kernel again
+    pub fn recycle(&mut self, buffer_id: u16) {
+        self.push_entry(buffer_id);
+        self.publish_tail();
+    }
+
+    fn push_entry(&mut self, buffer_id: u16) {
+        let index = usize::from(self.tail & (self.num_entries - 1));
+        let start = usize::from(buffer_id) * self.buffer_len;
+
+        let entry = unsafe { &mut *self.entries.add(index) };
+
+        entry.set_addr(unsafe { self.buffers.as_mut_ptr().add(start) } as u64);
+        entry.set_len(self.buffer_len as u32);
+        entry.set_bid(buffer_id);
+
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    fn publish_tail(&self) {
+        unsafe {
+            let tail = BufRingEntry::tail(self.entries) as *const AtomicU16;
+
+            (*tail).store(self.tail, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.entries as *mut u8, self.entries_layout) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(ring: &BufRing, index: usize) -> &BufRingEntry {
+        unsafe { &*ring.entries.add(index) }
+    }
+
+    fn published_tail(ring: &BufRing) -> u16 {
+        unsafe { (*(BufRingEntry::tail(ring.entries) as *const AtomicU16)).load(Ordering::Acquire) }
+    }
+
+    #[test]
+    fn test_buf_ring_initial_entries() {
+        let ring = BufRing::new(4, 8);
+
+        assert_eq!(published_tail(&ring), 4);
+
+        for i in 0..4u16 {
+            let entry = entry(&ring, i.into());
+
+            assert_eq!(entry.bid(), i);
+            assert_eq!(entry.len(), 8);
+            assert_eq!(
+                entry.addr(),
+                ring.buffers.as_ptr() as u64 + u64::from(i) * 8
+            );
+        }
+    }
+
+    #[test]
+    fn test_buf_ring_get() {
+        let mut ring = BufRing::new(2, 4);
+
+        ring.buffers.copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+
+        assert_eq!(ring.get(0, 2), &[0, 1]);
+        assert_eq!(ring.get(1, 4), &[4, 5, 6, 7]);
+        // Length is clamped to buffer length
+        assert_eq!(ring.get(1, 100), &[4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_buf_ring_recycle() {
+        let mut ring = BufRing::new(4, 8);
+
+        // Kernel consumed buffers in some order, which are then recycled.
+        // Entries wrap around to start of ring.
+        for (n, buffer_id) in [2u16, 0, 3, 1, 2].into_iter().enumerate() {
+            ring.recycle(buffer_id);
+
+            let entry = entry(&ring, n % 4);
+
+            assert_eq!(entry.bid(), buffer_id);
+            assert_eq!(
+                entry.addr(),
+                ring.buffers.as_ptr() as u64 + u64::from(buffer_id) * 8
+            );
+            assert_eq!(published_tail(&ring), 4 + n as u16 + 1);
+        }
+    }
+
+    #[test]
+    fn test_buf_ring_tail_wraps() {
+        let mut ring = BufRing::new(2, 1);
+
+        ring.tail = u16::MAX;
+        ring.recycle(1);
+
+        assert_eq!(published_tail(&ring), 0);
+        assert_eq!(entry(&ring, 1).bid(), 1);
+
+        ring.recycle(0);
+
+        assert_eq!(published_tail(&ring), 1);
+        assert_eq!(entry(&ring, 0).bid(), 0);
+    }
+}
@@ -0,0 +1,419 @@
+//! Socket worker event loop using io_uring
+//!
+//! Packets are received with a multishot recvmsg operation into a ring of
+//! buffers registered with the kernel and responses are sent with sendmsg
+//! operations from preallocated buffers. Request handling and communication
+//! with swarm workers is shared with the default event loop.
+
+mod buf_ring;
+mod send_buffers;
+
+use std::io;
+use std::mem::size_of;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use io_uring::types::{Fd, RecvMsgOut, SubmitArgs, Timespec};
+use io_uring::{cqueue, opcode, squeue, IoUring, Probe};
+use socket2::SockAddr;
+
+use aquatic_common::{CanonicalSocketAddr, ValidUntil};
+use aquatic_udp_protocol::*;
+
+use crate::common::BUFFER_SIZE;
+use crate::config::Config;
+
+use super::{destination_addr, finish_connected_response, ReceiveStatistics, SocketWorker};
+
+use buf_ring::BufRing;
+use send_buffers::SendBuffers;
+
+/// Buffer group id of receive buffer ring
+const BUF_GROUP_ID: u16 = 0;
+
+/// User data of multishot recvmsg entry. Sendmsg entries use send buffer
+/// index as user data.
+const USER_DATA_RECV: u64 = u64::MAX;
+
+/// Length of name field in received buffers, large enough for IPv6 addresses
+const RECV_NAME_LEN: usize = size_of::<libc::sockaddr_in6>();
+
+/// Length of each receive buffer
+///
+/// Consists of:
+/// - 16 byte io_uring_recvmsg_out header
+/// - source address
+/// - payload
+const RECV_BUFFER_LEN: usize = 16 + RECV_NAME_LEN + BUFFER_SIZE;
+
+/// Maximum number of send buffers and receive buffers
+///
+/// The submission queue has one extra entry for the recvmsg operation and
+/// io_uring allows at most 32768 submission queue entries.
+const MAX_RING_SIZE: usize = (1 << 15) - 1;
+
+/// Check that the running kernel supports the io_uring functionality used by
+/// the socket worker
+pub fn supported_on_current_kernel() -> anyhow::Result<()> {
+    // Declared before ring, so that they are dropped after it
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).with_context(|| "bind probe socket")?;
+    let buf_ring = BufRing::new(1, RECV_BUFFER_LEN);
+
+    let mut recv_msghdr: libc::msghdr = unsafe { ::std::mem::zeroed() };
+    recv_msghdr.msg_namelen = RECV_NAME_LEN as libc::socklen_t;
+
+    let mut ring: IoUring = IoUring::new(2).with_context(|| "create io_uring instance")?;
+
+    let mut probe = Probe::new();
+
+    ring.submitter()
+        .register_probe(&mut probe)
+        .with_context(|| "register probe")?;
+
+    for (code, name) in [
+        (opcode::RecvMsg::CODE, "recvmsg"),
+        (opcode::SendMsg::CODE, "sendmsg"),
+    ] {
+        if !probe.is_supported(code) {
+            return Err(anyhow::anyhow!("io_uring {} operation not supported", name));
+        }
+    }
+
+    // Provided buffer rings require Linux 5.19
+    buf_ring
+        .register(&ring.submitter(), BUF_GROUP_ID)
+        .with_context(|| "register buffer ring")?;
+
+    // Multishot recvmsg requires Linux 6.0. Older kernels only fail the
+    // operation with EINVAL once it is run, so receive a packet sent to the
+    // probe socket itself.
+    socket
+        .send_to(&[0], socket.local_addr()?)
+        .with_context(|| "send probe packet")?;
+
+    let recv_entry = opcode::RecvMsgMulti::new(Fd(socket.as_raw_fd()), &recv_msghdr, BUF_GROUP_ID)
+        .build()
+        .user_data(USER_DATA_RECV);
+
+    unsafe { ring.submission().push(&recv_entry) }.with_context(|| "push recvmsg entry")?;
+
+    ring.submit_and_wait(1)
+        .with_context(|| "submit io_uring entries")?;
+
+    let result = ring
+        .completion()
+        .next()
+        .map(|cqe| cqe.result())
+        .ok_or_else(|| anyhow::anyhow!("no io_uring recvmsg completion"))?;
+
+    match result {
+        result if result >= 0 => Ok(()),
+        result if -result == libc::EINVAL => Err(anyhow::anyhow!(
+            "io_uring multishot recvmsg not supported (requires Linux 6.0 or later)"
+        )),
+        result => Err(io::Error::from_raw_os_error(-result))
+            .with_context(|| "receive probe packet with io_uring multishot recvmsg"),
+    }
+}
+
+impl SocketWorker {
+    /// Run event loop. Only returns on errors that prevent further operation.
+    pub(super) fn run_io_uring(&mut self) -> anyhow::Result<()> {
+        let ring_size = self
+            .config
+            .network
+            .io_uring_ring_size
+            .clamp(1, MAX_RING_SIZE);
+
+        let mut local_responses = Vec::new();
+        let mut opt_resend_buffer =
+            (self.config.network.resend_buffer_max_len > 0).then_some(Vec::new());
+
+        let mut send_buffers = SendBuffers::new(ring_size);
+        // Declared before ring, so that it is dropped after it
+        let mut buf_ring = BufRing::new((ring_size as u16).next_power_of_two(), RECV_BUFFER_LEN);
+
+        // One extra entry is needed for resubmitting recvmsg entry
+        let mut ring: IoUring =
+            IoUring::new(ring_size as u32 + 1).with_context(|| "create io_uring instance")?;
+
+        buf_ring
+            .register(&ring.submitter(), BUF_GROUP_ID)
+            .with_context(|| "register io_uring buffer ring")?;
+
+        let fd = Fd(self.socket.as_raw_fd());
+
+        // Only name length is read by the kernel for multishot recvmsg
+        let mut recv_msghdr: libc::msghdr = unsafe { ::std::mem::zeroed() };
+        recv_msghdr.msg_namelen = RECV_NAME_LEN as libc::socklen_t;
+
+        let recv_entry = opcode::RecvMsgMulti::new(fd, &recv_msghdr, BUF_GROUP_ID)
+            .build()
+            .user_data(USER_DATA_RECV);
+        let mut resubmit_recv_entry = true;
+
+        // Used for responses that have already been resent once
+        let mut no_resend_buffer = None;
+
+        let timeout = Timespec::from(Duration::from_millis(self.config.network.poll_timeout_ms));
+        let submit_args = SubmitArgs::new().timespec(&timeout);
+
+        let pending_scrape_cleaning_duration =
+            Duration::from_secs(self.config.cleaning.pending_scrape_cleaning_interval);
+
+        let mut pending_scrape_valid_until = ValidUntil::new(
+            self.server_start_instant,
+            self.config.cleaning.max_pending_scrape_age,
+        );
+        let mut last_pending_scrape_cleaning = Instant::now();
+
+        let mut iter_counter = 0usize;
+
+        loop {
+            {
+                let mut submission_queue = ring.submission();
+
+                if resubmit_recv_entry {
+                    unsafe { submission_queue.push(&recv_entry) }
+                        .with_context(|| "push recvmsg entry")?;
+
+                    resubmit_recv_entry = false;
+                }
+
+                // If resend buffer is enabled, send any responses in it
+                if let Some(resend_buffer) = opt_resend_buffer.as_mut() {
+                    let num_to_send = resend_buffer.len().min(send_buffers.num_free());
+
+                    for (response, addr) in resend_buffer.drain(..num_to_send) {
+                        queue_response(
+                            &self.config,
+                            &mut submission_queue,
+                            &mut send_buffers,
+                            fd,
+                            response,
+                            addr,
+                            false,
+                        )?;
+                    }
+                }
+
+                // Send any connect and error responses generated by this socket worker
+                let num_to_send = local_responses.len().min(send_buffers.num_free());
+
+                for (response, addr) in local_responses.drain(..num_to_send) {
+                    queue_response(
+                        &self.config,
+                        &mut submission_queue,
+                        &mut send_buffers,
+                        fd,
+                        response,
+                        self.proxy_protocol.reply_addr(addr),
+                        true,
+                    )?;
+                }
+
+                // Check channel for any responses generated by swarm workers
+                let num_to_receive = send_buffers.num_free();
+
+                for (response, addr) in self.response_receiver.try_iter().take(num_to_receive) {
+                    if let Some(response) =
+                        finish_connected_response(&mut self.pending_scrape_responses, response)
+                    {
+                        queue_response(
+                            &self.config,
+                            &mut submission_queue,
+                            &mut send_buffers,
+                            fd,
+                            response,
+                            self.proxy_protocol.reply_addr(addr),
+                            true,
+                        )?;
+                    }
+                }
+            }
+
+            match ring.submitter().submit_with_args(1, &submit_args) {
+                Ok(_) => (),
+                Err(err)
+                    if matches!(
+                        err.raw_os_error(),
+                        Some(libc::ETIME | libc::EBUSY | libc::EINTR)
+                    ) => {}
+                Err(err) => return Err(err).with_context(|| "submit io_uring entries"),
+            }
+
+            let mut statistics = ReceiveStatistics::default();
+
+            let now = Instant::now();
+
+            for cqe in ring.completion() {
+                if cqe.user_data() == USER_DATA_RECV {
+                    if !cqueue::more(cqe.flags()) {
+                        resubmit_recv_entry = true;
+                    }
+
+                    let result = cqe.result();
+
+                    if result < 0 {
+                        match -result {
+                            // Buffer ring was temporarily empty
+                            libc::ENOBUFS => {
+                                ::log::debug!("io_uring receive buffers exhausted");
+                            }
+                            libc::EINVAL => {
+                                return Err(anyhow::anyhow!(
+                                    "io_uring multishot recvmsg not supported (requires Linux 6.0 or later)"
+                                ));
+                            }
+                            errno => {
+                                ::log::warn!(
+                                    "io_uring recvmsg error: {:#}",
+                                    io::Error::from_raw_os_error(errno)
+                                );
+                            }
+                        }
+
+                        continue;
+                    }
+
+                    let buffer_id = match cqueue::buffer_select(cqe.flags()) {
+                        Some(buffer_id) => buffer_id,
+                        None => {
+                            ::log::error!("io_uring recvmsg completion without buffer");
+
+                            continue;
+                        }
+                    };
+
+                    let buffer = buf_ring.get(buffer_id, result as usize);
+
+                    match RecvMsgOut::parse(buffer, &recv_msghdr) {
+                        Ok(msg) if msg.is_payload_truncated() => {
+                            ::log::debug!("Ignored truncated request");
+                        }
+                        Ok(msg) => {
+                            let payload = msg.payload_data();
+
+                            let src_and_payload_start = parse_source_addr(msg.name_data())
+                                .and_then(|src| {
+                                    self.proxy_protocol.handle_datagram(
+                                        src,
+                                        payload,
+                                        pending_scrape_valid_until,
+                                    )
+                                })
+                                .and_then(|(src, payload_start)| {
+                                    Some((self.filter_source(src)?, payload_start))
+                                });
+
+                            if let Some((src, payload_start)) = src_and_payload_start {
+                                let request_parse_result = Request::from_bytes(
+                                    &payload[payload_start..],
+                                    self.config.protocol.max_scrape_torrents,
+                                );
+
+                                statistics.add_bytes(src, payload.len());
+
+                                self.handle_request_parse_result(
+                                    &mut local_responses,
+                                    &mut statistics,
+                                    pending_scrape_valid_until,
+                                    now,
+                                    request_parse_result,
+                                    src,
+                                );
+                            }
+                        }
+                        Err(()) => {
+                            ::log::error!("Couldn't parse io_uring recvmsg buffer");
+                        }
+                    }
+
+                    buf_ring.recycle(buffer_id);
+                } else {
+                    let result = if cqe.result() < 0 {
+                        Err(io::Error::from_raw_os_error(-cqe.result()))
+                    } else {
+                        Ok(cqe.result() as usize)
+                    };
+
+                    if let Some((response, addr, resendable)) =
+                        send_buffers.take(cqe.user_data() as usize)
+                    {
+                        let opt_resend_buffer = if resendable {
+                            &mut opt_resend_buffer
+                        } else {
+                            &mut no_resend_buffer
+                        };
+
+                        Self::handle_send_result(
+                            &self.config,
+                            &self.shared_state,
+                            opt_resend_buffer,
+                            response,
+                            addr,
+                            result,
+                        );
+                    }
+                }
+            }
+
+            if self.config.statistics_active() {
+                statistics.add_to_shared(&self.shared_state);
+            }
+
+            // Run periodic ValidUntil updates and state cleaning
+            if iter_counter % 256 == 0 {
+                self.run_periodic_tasks(
+                    &mut pending_scrape_valid_until,
+                    &mut last_pending_scrape_cleaning,
+                    pending_scrape_cleaning_duration,
+                );
+            }
+
+            iter_counter = iter_counter.wrapping_add(1);
+        }
+    }
+}
+
+fn queue_response(
+    config: &Config,
+    submission_queue: &mut squeue::SubmissionQueue,
+    send_buffers: &mut SendBuffers,
+    fd: Fd,
+    response: Response,
+    canonical_addr: CanonicalSocketAddr,
+    resendable: bool,
+) -> anyhow::Result<()> {
+    let addr = destination_addr(config, canonical_addr);
+
+    match send_buffers.prepare_entry(fd, response, canonical_addr, addr, resendable) {
+        Ok(entry) => {
+            // Submission queue has room for one entry per send buffer
+            unsafe { submission_queue.push(&entry) }.with_context(|| "push sendmsg entry")?;
+        }
+        Err(err) => {
+            ::log::error!("Converting response to bytes failed: {:#}", err);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_source_addr(name_data: &[u8]) -> Option<SocketAddr> {
+    let mut storage: libc::sockaddr_storage = unsafe { ::std::mem::zeroed() };
+
+    let len = name_data.len().min(size_of::<libc::sockaddr_storage>());
+
+    unsafe {
+        ::std::ptr::copy_nonoverlapping(
+            name_data.as_ptr(),
+            &mut storage as *mut libc::sockaddr_storage as *mut u8,
+            len,
+        );
+
+        SockAddr::new(storage, len as libc::socklen_t).as_socket()
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{self, Cursor};
+use std::net::{Ipv4Addr, SocketAddr};
+
+use io_uring::types::Fd;
+use io_uring::{opcode, squeue};
+use socket2::SockAddr;
+
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::Response;
+
+use crate::common::BUFFER_SIZE;
+
+struct SendBuffer {
+    bytes: [u8; BUFFER_SIZE],
+    addr: SockAddr,
+    iovec: libc::iovec,
+    msghdr: libc::msghdr,
+    /// Response being sent, kept for updating statistics and resending.
+    /// Boolean denotes whether response may be added to resend buffer.
+    response: Option<(Response, CanonicalSocketAddr, bool)>,
+}
+
+/// Preallocated buffers for sending responses
+///
+/// Each buffer is in use from when its send operation is submitted until the
+/// corresponding completion event has been received.
+pub struct SendBuffers {
+    buffers: Vec<SendBuffer>,
+    free: Vec<usize>,
+}
+
+impl SendBuffers {
+    pub fn new(num_buffers: usize) -> Self {
+        let buffers = (0..num_buffers)
+            .map(|_| SendBuffer {
+                bytes: [0; BUFFER_SIZE],
+                addr: SockAddr::from(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+                iovec: libc::iovec {
+                    iov_base: ::std::ptr::null_mut(),
+                    iov_len: 0,
+                },
+                msghdr: unsafe { ::std::mem::zeroed() },
+                response: None,
+            })
+            .collect();
+
+        Self {
+            buffers,
+            free: (0..num_buffers).rev().collect(),
+        }
+    }
+
+    pub fn num_free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Encode response into free buffer and return sendmsg entry with buffer
+    /// index as user data
+    pub fn prepare_entry(
+        &mut self,
+        fd: Fd,
+        response: Response,
+        canonical_addr: CanonicalSocketAddr,
+        addr: SocketAddr,
+        resendable: bool,
+    ) -> io::Result<squeue::Entry> {
+        let index = self
+            .free
+            .pop()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no free send buffer"))?;
+
+        // Buffers vector is never resized, so pointers into it remain valid
+        let buffer = &mut self.buffers[index];
+
+        let mut cursor = Cursor::new(&mut buffer.bytes[..]);
+
+        if let Err(err) = response.write(&mut cursor) {
+            self.free.push(index);
+
+            return Err(err);
+        }
+
+        let bytes_written = cursor.position() as usize;
+
+        buffer.iovec.iov_base = buffer.bytes.as_mut_ptr() as *mut libc::c_void;
+        buffer.iovec.iov_len = bytes_written;
+        buffer.addr = SockAddr::from(addr);
+
+        buffer.msghdr.msg_name = buffer.addr.as_ptr() as *mut libc::c_void;
+        buffer.msghdr.msg_namelen = buffer.addr.len();
+        buffer.msghdr.msg_iov = &mut buffer.iovec;
+        buffer.msghdr.msg_iovlen = 1;
+
+        buffer.response = Some((response, canonical_addr, resendable));
+
+        Ok(opcode::SendMsg::new(fd, &buffer.msghdr)
+            .build()
+            .user_data(index as u64))
+    }
+
+    /// Mark buffer as free and return response that was sent from it
+    pub fn take(&mut self, index: usize) -> Option<(Response, CanonicalSocketAddr, bool)> {
+        let opt_response = self.buffers.get_mut(index)?.response.take();
+
+        if opt_response.is_some() {
+            self.free.push(index);
+        }
+
+        opt_response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aquatic_udp_protocol::*;
+
+    use super::*;
+
+    fn connect_response(i: i32) -> Response {
+        Response::Connect(ConnectResponse {
+            connection_id: ConnectionId(0),
+            transaction_id: TransactionId(i),
+        })
+    }
+
+    fn prepare(buffers: &mut SendBuffers, response: Response) -> io::Result<usize> {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1234));
+
+        buffers
+            .prepare_entry(Fd(-1), response, CanonicalSocketAddr::new(addr), addr, true)
+            .map(|entry| entry.get_user_data() as usize)
+    }
+
+    #[test]
+    fn test_send_buffer_indices() {
+        let mut buffers = SendBuffers::new(3);
+
+        for i in 0..3 {
+            assert_eq!(
+                prepare(&mut buffers, connect_response(i)).unwrap(),
+                i as usize
+            );
+        }
+
+        assert_eq!(buffers.num_free(), 0);
+        assert!(prepare(&mut buffers, connect_response(3)).is_err());
+
+        let (response, _, resendable) = buffers.take(1).unwrap();
+
+        assert_eq!(response, connect_response(1));
+        assert!(resendable);
+        assert_eq!(buffers.num_free(), 1);
+
+        // Taking a free or nonexistent buffer doesn't free it again
+        assert!(buffers.take(1).is_none());
+        assert!(buffers.take(3).is_none());
+        assert_eq!(buffers.num_free(), 1);
+
+        // Freed buffer is reused
+        assert_eq!(prepare(&mut buffers, connect_response(4)).unwrap(), 1);
+        assert_eq!(buffers.take(1).unwrap().0, connect_response(4));
+    }
+
+    #[test]
+    fn test_send_buffer_encode_error() {
+        let mut buffers = SendBuffers::new(1);
+
+        let too_large = Response::Scrape(ScrapeResponse {
+            transaction_id: TransactionId(0),
+            torrent_stats: vec![
+                TorrentScrapeStatistics {
+                    seeders: NumberOfPeers(0),
+                    completed: NumberOfDownloads(0),
+                    leechers: NumberOfPeers(0),
+                };
+                BUFFER_SIZE / 12 + 1
+            ],
+        });
+
+        assert!(prepare(&mut buffers, too_large).is_err());
+
+        // Buffer is returned to free list
+        assert_eq!(buffers.num_free(), 1);
+        assert!(buffers.take(0).is_none());
+        assert_eq!(prepare(&mut buffers, connect_response(0)).unwrap(), 0);
+    }
+}
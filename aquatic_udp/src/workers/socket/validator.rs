@@ -1,7 +1,12 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::net::IpAddr;
-use std::time::Instant;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
+use arc_swap::{ArcSwap, Cache};
 use constant_time_eq::constant_time_eq;
 use getrandom::getrandom;
 
@@ -10,12 +15,132 @@ use aquatic_udp_protocol::ConnectionId;
 
 use crate::config::Config;
 
+type Key = [u8; 32];
+
+/// Key together with BLAKE3 hasher initialized with it, which is cloned for
+/// each hash instead of being recreated
+#[derive(Clone)]
+struct KeyedHasher {
+    key: Key,
+    hasher: blake3::Hasher,
+}
+
+impl KeyedHasher {
+    fn new(key: Key) -> Self {
+        Self {
+            key,
+            hasher: blake3::Hasher::new_keyed(&key),
+        }
+    }
+
+    fn hash(&self, valid_until: [u8; 4], ip_addr: IpAddr) -> [u8; 4] {
+        let mut hasher = self.hasher.clone();
+
+        hasher.update(&valid_until);
+
+        match ip_addr {
+            IpAddr::V4(ip) => hasher.update(&ip.octets()),
+            IpAddr::V6(ip) => hasher.update(&ip.octets()),
+        };
+
+        let mut hash = [0u8; 4];
+
+        hasher.finalize_xof().fill(&mut hash);
+
+        hash
+    }
+}
+
+impl PartialEq for KeyedHasher {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for KeyedHasher {}
+
+impl ::std::fmt::Debug for KeyedHasher {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        f.debug_tuple("KeyedHasher").field(&self.key).finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Keys {
+    /// Used for creating and validating connection IDs
+    current: KeyedHasher,
+    /// Only used for validating connection IDs
+    previous: Option<KeyedHasher>,
+}
+
+impl Keys {
+    fn random() -> anyhow::Result<Self> {
+        let mut key = [0; 32];
+
+        getrandom(&mut key)
+            .with_context(|| "Couldn't get random bytes for ConnectionValidator key")?;
+
+        Ok(Self {
+            current: KeyedHasher::new(key),
+            previous: None,
+        })
+    }
+
+    /// Read keys from file. If file only contains one key, use current key
+    /// of `opt_old_keys` as previous key, or keep previous key of
+    /// `opt_old_keys` if current key is unchanged.
+    fn from_path(path: &Path, opt_old_keys: Option<&Self>) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Couldn't open connection ID secret file {:?}", path))?;
+
+        let mut keys = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut key = [0; 32];
+
+            hex::decode_to_slice(line, &mut key)
+                .with_context(|| "Invalid key in connection ID secret file")?;
+
+            keys.push(key);
+        }
+
+        match keys[..] {
+            [current] => {
+                let previous = match opt_old_keys {
+                    Some(old_keys) if old_keys.current.key == current => old_keys.previous.clone(),
+                    Some(old_keys) => Some(old_keys.current.clone()),
+                    None => None,
+                };
+
+                Ok(Self {
+                    current: KeyedHasher::new(current),
+                    previous,
+                })
+            }
+            [current, previous] => Ok(Self {
+                current: KeyedHasher::new(current),
+                previous: Some(KeyedHasher::new(previous)),
+            }),
+            _ => Err(anyhow::anyhow!(
+                "Connection ID secret file must contain one or two keys, found {}",
+                keys.len()
+            )),
+        }
+    }
+}
+
 /// HMAC (BLAKE3) based ConnectionID creator and validator
 ///
 /// Structure of created ConnectionID (bytes making up inner i64):
-/// - &[0..4]: connection expiration time as number of seconds after
-///   ConnectionValidator instance was created, encoded as u32 bytes.
-///   Value fits around 136 years.
+/// - &[0..4]: connection expiration time as number of seconds since the
+///   Unix epoch, encoded as u32 bytes. Value fits until year 2106.
 /// - &[4..8]: truncated keyed BLAKE3 hash of above 4 bytes and octets of
 ///   client IP address
 ///
@@ -23,36 +148,67 @@ use crate::config::Config;
 /// prevent the tracker from being used as an amplification vector for DDoS
 /// attacks. By including 32 bits of BLAKE3 keyed hash output in its contents,
 /// such abuse should be rendered impractical.
+///
+/// Since expiration time is based on wall-clock time, ConnectionIDs created
+/// by one process can be validated by another one using the same keys.
 #[derive(Clone)]
 pub struct ConnectionValidator {
-    start_time: Instant,
     max_connection_age: u32,
-    keyed_hasher: blake3::Hasher,
+    keys: Cache<Arc<ArcSwap<Keys>>, Arc<Keys>>,
 }
 
 impl ConnectionValidator {
     /// Create new instance. Must be created once and cloned if used in several
-    /// threads.
+    /// threads, so that key updates reach all instances.
     pub fn new(config: &Config) -> anyhow::Result<Self> {
-        let mut key = [0; 32];
-
-        getrandom(&mut key)
-            .with_context(|| "Couldn't get random bytes for ConnectionValidator key")?;
-
-        let keyed_hasher = blake3::Hasher::new_keyed(&key);
+        let keys = if config.connection_id.load_secret_from_file {
+            Keys::from_path(&config.connection_id.secret_path, None)?
+        } else {
+            Keys::random()?
+        };
 
         Ok(Self {
-            keyed_hasher,
-            start_time: Instant::now(),
             max_connection_age: config.cleaning.max_connection_age,
+            keys: Cache::new(Arc::new(ArcSwap::from_pointee(keys))),
         })
     }
 
+    /// Reload keys from secret file if it is configured to be used. Updates
+    /// all clones of this instance.
+    pub fn update_keys(&self, config: &Config) -> anyhow::Result<()> {
+        if !config.connection_id.load_secret_from_file {
+            return Ok(());
+        }
+
+        let arc_swap = self.keys.arc_swap();
+        let old_keys = arc_swap.load_full();
+
+        match Keys::from_path(&config.connection_id.secret_path, Some(old_keys.as_ref())) {
+            Ok(keys) => {
+                arc_swap.store(Arc::new(keys));
+
+                ::log::info!("Connection ID secret updated");
+
+                Ok(())
+            }
+            Err(err) => {
+                ::log::error!("Updating connection ID secret failed: {:#}", err);
+
+                Err(err)
+            }
+        }
+    }
+
     pub fn create_connection_id(&mut self, source_addr: CanonicalSocketAddr) -> ConnectionId {
-        let valid_until =
-            (self.start_time.elapsed().as_secs() as u32 + self.max_connection_age).to_ne_bytes();
+        let valid_until = unix_seconds()
+            .saturating_add(self.max_connection_age)
+            .to_ne_bytes();
 
-        let hash = self.hash(valid_until, source_addr.get().ip());
+        let hash = self
+            .keys
+            .load()
+            .current
+            .hash(valid_until, source_addr.get().ip());
 
         let mut connection_id_bytes = [0u8; 8];
 
@@ -70,34 +226,33 @@ impl ConnectionValidator {
         let bytes = connection_id.0.to_ne_bytes();
         let (valid_until, hash) = bytes.split_at(4);
         let valid_until: [u8; 4] = valid_until.try_into().unwrap();
+        let ip = source_addr.get().ip();
 
-        if !constant_time_eq(hash, &self.hash(valid_until, source_addr.get().ip())) {
+        let keys = self.keys.load();
+
+        let hash_valid = constant_time_eq(hash, &keys.current.hash(valid_until, ip))
+            || keys.previous.as_ref().map_or(false, |previous| {
+                constant_time_eq(hash, &previous.hash(valid_until, ip))
+            });
+
+        if !hash_valid {
             return false;
         }
 
-        u32::from_ne_bytes(valid_until) > self.start_time.elapsed().as_secs() as u32
+        u32::from_ne_bytes(valid_until) > unix_seconds()
     }
+}
 
-    fn hash(&mut self, valid_until: [u8; 4], ip_addr: IpAddr) -> [u8; 4] {
-        self.keyed_hasher.update(&valid_until);
-
-        match ip_addr {
-            IpAddr::V4(ip) => self.keyed_hasher.update(&ip.octets()),
-            IpAddr::V6(ip) => self.keyed_hasher.update(&ip.octets()),
-        };
-
-        let mut hash = [0u8; 4];
-
-        self.keyed_hasher.finalize_xof().fill(&mut hash);
-        self.keyed_hasher.reset();
-
-        hash
-    }
+fn unix_seconds() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::SocketAddr;
+    use std::net::{Ipv4Addr, SocketAddr};
 
     use quickcheck_macros::quickcheck;
 
@@ -140,4 +295,69 @@ mod tests {
             quickcheck::TestResult::from_bool(original_valid)
         }
     }
+
+    #[test]
+    fn test_connection_validator_key_rotation() {
+        let path = ::std::env::temp_dir().join(format!(
+            "aquatic-udp-connection-id-secret-test-{}",
+            ::std::process::id()
+        ));
+
+        let key_a = [1u8; 32];
+        let key_b = [2u8; 32];
+        let key_c = [3u8; 32];
+
+        let mut config = Config::default();
+
+        config.connection_id.load_secret_from_file = true;
+        config.connection_id.secret_path = path.clone();
+
+        let addr = CanonicalSocketAddr::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 1)));
+
+        ::std::fs::write(&path, format!("# comment\n{}\n", hex::encode(key_a))).unwrap();
+
+        let mut validator = ConnectionValidator::new(&config).unwrap();
+        let mut other_instance = ConnectionValidator::new(&config).unwrap();
+        let mut clone = validator.clone();
+
+        let connection_id = validator.create_connection_id(addr);
+
+        assert!(other_instance.connection_id_valid(addr, connection_id));
+
+        // Single new key: old current key is kept as previous key
+        ::std::fs::write(&path, hex::encode(key_b)).unwrap();
+        validator.update_keys(&config).unwrap();
+
+        assert!(clone.connection_id_valid(addr, connection_id));
+        let expected_keys = Keys {
+            current: KeyedHasher::new(key_b),
+            previous: Some(KeyedHasher::new(key_a)),
+        };
+
+        assert_eq!(*clone.keys.load().as_ref(), expected_keys);
+
+        // Reloading unchanged file keeps previous key
+        validator.update_keys(&config).unwrap();
+
+        assert!(clone.connection_id_valid(addr, connection_id));
+        assert_eq!(*clone.keys.load().as_ref(), expected_keys);
+
+        ::std::fs::write(
+            &path,
+            format!("{}\n{}\n", hex::encode(key_c), hex::encode(key_b)),
+        )
+        .unwrap();
+        validator.update_keys(&config).unwrap();
+
+        assert!(!clone.connection_id_valid(addr, connection_id));
+        assert!(clone.connection_id_valid(addr, validator.create_connection_id(addr)));
+
+        // Invalid file: keys are not changed
+        ::std::fs::write(&path, "abc").unwrap();
+
+        assert!(validator.update_keys(&config).is_err());
+        assert_eq!(clone.keys.load().current.key, key_c);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
 }
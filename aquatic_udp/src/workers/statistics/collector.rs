@@ -14,15 +14,18 @@ pub struct StatisticsCollector {
     last_update: Instant,
     pending_histograms: Vec<Histogram<u64>>,
     last_complete_histogram: PeerHistogramStatistics,
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    ip_version: &'static str,
 }
 
 impl StatisticsCollector {
-    pub fn new(shared: Arc<Statistics>) -> Self {
+    pub fn new(shared: Arc<Statistics>, ip_version: &'static str) -> Self {
         Self {
             shared,
             last_update: Instant::now(),
             pending_histograms: Vec::new(),
             last_complete_histogram: Default::default(),
+            ip_version,
         }
     }
 
@@ -32,11 +35,23 @@ impl StatisticsCollector {
         if self.pending_histograms.len() == config.swarm_workers {
             self.last_complete_histogram =
                 PeerHistogramStatistics::new(self.pending_histograms.drain(..).sum());
+
+            #[cfg(feature = "metrics")]
+            if config.metrics.run_prometheus_endpoint {
+                self.last_complete_histogram.update_metrics(self.ip_version);
+            }
         }
     }
 
-    pub fn collect_from_shared(&mut self) -> CollectedStatistics {
-        let requests_received = Self::fetch_and_reset(&self.shared.requests_received);
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn collect_from_shared(&mut self, config: &Config) -> CollectedStatistics {
+        let requests_received_connect =
+            Self::fetch_and_reset(&self.shared.requests_received_connect);
+        let requests_received_announce =
+            Self::fetch_and_reset(&self.shared.requests_received_announce);
+        let requests_received_scrape = Self::fetch_and_reset(&self.shared.requests_received_scrape);
+        let requests_received_error = Self::fetch_and_reset(&self.shared.requests_received_error);
+        let requests_rate_limited = Self::fetch_and_reset(&self.shared.requests_rate_limited);
         let responses_sent_connect = Self::fetch_and_reset(&self.shared.responses_sent_connect);
         let responses_sent_announce = Self::fetch_and_reset(&self.shared.responses_sent_announce);
         let responses_sent_scrape = Self::fetch_and_reset(&self.shared.responses_sent_scrape);
@@ -46,6 +61,27 @@ impl StatisticsCollector {
         let num_torrents = Self::sum_atomic_usizes(&self.shared.torrents);
         let num_peers = Self::sum_atomic_usizes(&self.shared.peers);
 
+        #[cfg(feature = "metrics")]
+        if config.metrics.run_prometheus_endpoint {
+            self.update_metrics(
+                [
+                    ("connect", requests_received_connect),
+                    ("announce", requests_received_announce),
+                    ("scrape", requests_received_scrape),
+                    ("error", requests_received_error),
+                ],
+                requests_rate_limited,
+                [
+                    ("connect", responses_sent_connect),
+                    ("announce", responses_sent_announce),
+                    ("scrape", responses_sent_scrape),
+                    ("error", responses_sent_error),
+                ],
+                bytes_received,
+                bytes_sent,
+            );
+        }
+
         let elapsed = {
             let now = Instant::now();
 
@@ -56,7 +92,13 @@ impl StatisticsCollector {
             elapsed
         };
 
+        let requests_received = requests_received_connect
+            + requests_received_announce
+            + requests_received_scrape
+            + requests_received_error;
+
         let requests_per_second = requests_received / elapsed;
+        let requests_per_second_rate_limited = requests_rate_limited / elapsed;
         let responses_per_second_connect = responses_sent_connect / elapsed;
         let responses_per_second_announce = responses_sent_announce / elapsed;
         let responses_per_second_scrape = responses_sent_scrape / elapsed;
@@ -71,6 +113,8 @@ impl StatisticsCollector {
 
         CollectedStatistics {
             requests_per_second: (requests_per_second as usize).to_formatted_string(&Locale::en),
+            requests_per_second_rate_limited: (requests_per_second_rate_limited as usize)
+                .to_formatted_string(&Locale::en),
             responses_per_second_total: (responses_per_second_total as usize)
                 .to_formatted_string(&Locale::en),
             responses_per_second_connect: (responses_per_second_connect as usize)
@@ -89,6 +133,67 @@ impl StatisticsCollector {
         }
     }
 
+    #[cfg(feature = "metrics")]
+    fn update_metrics(
+        &self,
+        requests_received: [(&'static str, f64); 4],
+        requests_rate_limited: f64,
+        responses_sent: [(&'static str, f64); 4],
+        bytes_received: f64,
+        bytes_sent: f64,
+    ) {
+        for (request_type, num_received) in requests_received {
+            ::metrics::counter!(
+                "aquatic_requests_total",
+                num_received as u64,
+                "type" => request_type,
+                "ip_version" => self.ip_version,
+            );
+        }
+        ::metrics::counter!(
+            "aquatic_requests_rate_limited_total",
+            requests_rate_limited as u64,
+            "ip_version" => self.ip_version,
+        );
+
+        for (response_type, num_sent) in responses_sent {
+            ::metrics::counter!(
+                "aquatic_responses_total",
+                num_sent as u64,
+                "type" => response_type,
+                "ip_version" => self.ip_version,
+            );
+        }
+
+        ::metrics::counter!(
+            "aquatic_rx_bytes_total",
+            bytes_received as u64,
+            "ip_version" => self.ip_version,
+        );
+        ::metrics::counter!(
+            "aquatic_tx_bytes_total",
+            bytes_sent as u64,
+            "ip_version" => self.ip_version,
+        );
+
+        for (worker_index, n) in self.shared.torrents.iter().enumerate() {
+            ::metrics::gauge!(
+                "aquatic_torrents",
+                n.load(Ordering::Relaxed) as f64,
+                "ip_version" => self.ip_version,
+                "worker_index" => worker_index.to_string(),
+            );
+        }
+        for (worker_index, n) in self.shared.peers.iter().enumerate() {
+            ::metrics::gauge!(
+                "aquatic_peers",
+                n.load(Ordering::Relaxed) as f64,
+                "ip_version" => self.ip_version,
+                "worker_index" => worker_index.to_string(),
+            );
+        }
+    }
+
     fn sum_atomic_usizes(values: &[AtomicUsize]) -> usize {
         values.iter().map(|n| n.load(Ordering::Relaxed)).sum()
     }
@@ -101,6 +206,7 @@ impl StatisticsCollector {
 #[derive(Clone, Debug, Serialize)]
 pub struct CollectedStatistics {
     pub requests_per_second: String,
+    pub requests_per_second_rate_limited: String,
     pub responses_per_second_total: String,
     pub responses_per_second_connect: String,
     pub responses_per_second_announce: String,
@@ -148,4 +254,32 @@ impl PeerHistogramStatistics {
             p100: h.value_at_percentile(100.0),
         }
     }
+
+    #[cfg(feature = "metrics")]
+    fn update_metrics(&self, ip_version: &'static str) {
+        let percentiles = [
+            ("0", self.p0),
+            ("10", self.p10),
+            ("20", self.p20),
+            ("30", self.p30),
+            ("40", self.p40),
+            ("50", self.p50),
+            ("60", self.p60),
+            ("70", self.p70),
+            ("80", self.p80),
+            ("90", self.p90),
+            ("95", self.p95),
+            ("99", self.p99),
+            ("100", self.p100),
+        ];
+
+        for (percentile, value) in percentiles {
+            ::metrics::gauge!(
+                "aquatic_peers_per_torrent",
+                value as f64,
+                "percentile" => percentile,
+                "ip_version" => ip_version,
+            );
+        }
+    }
 }
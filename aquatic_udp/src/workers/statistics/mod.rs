@@ -31,6 +31,7 @@ struct TemplateData {
     ipv4_active: bool,
     ipv6_active: bool,
     extended_active: bool,
+    rate_limit_active: bool,
     ipv4: CollectedStatistics,
     ipv6: CollectedStatistics,
     last_updated: String,
@@ -57,8 +58,8 @@ pub fn run_statistics_worker(
         None
     };
 
-    let mut ipv4_collector = StatisticsCollector::new(shared_state.statistics_ipv4);
-    let mut ipv6_collector = StatisticsCollector::new(shared_state.statistics_ipv6);
+    let mut ipv4_collector = StatisticsCollector::new(shared_state.statistics_ipv4, "4");
+    let mut ipv6_collector = StatisticsCollector::new(shared_state.statistics_ipv6, "6");
 
     loop {
         ::std::thread::sleep(Duration::from_secs(config.statistics.interval));
@@ -70,8 +71,8 @@ pub fn run_statistics_worker(
             }
         }
 
-        let statistics_ipv4 = ipv4_collector.collect_from_shared();
-        let statistics_ipv6 = ipv6_collector.collect_from_shared();
+        let statistics_ipv4 = ipv4_collector.collect_from_shared(&config);
+        let statistics_ipv6 = ipv6_collector.collect_from_shared(&config);
 
         if config.statistics.print_to_stdout {
             println!("General:");
@@ -98,6 +99,7 @@ pub fn run_statistics_worker(
                 ipv4_active: config.network.ipv4_active(),
                 ipv6_active: config.network.ipv6_active(),
                 extended_active: config.statistics.extended,
+                rate_limit_active: config.rate_limit.enabled,
                 ipv4: statistics_ipv4,
                 ipv6: statistics_ipv6,
                 last_updated: OffsetDateTime::now_utc()
@@ -119,6 +121,14 @@ fn print_to_stdout(config: &Config, statistics: &CollectedStatistics) {
         statistics.rx_mbits, statistics.tx_mbits,
     );
     println!("  requests/second: {:>10}", statistics.requests_per_second);
+
+    if config.rate_limit.enabled {
+        println!(
+            "  rate limited requests/second: {:>10}",
+            statistics.requests_per_second_rate_limited
+        );
+    }
+
     println!("  responses/second");
     println!(
         "    total:         {:>10}",
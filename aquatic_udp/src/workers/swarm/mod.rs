@@ -1,3 +1,4 @@
+pub mod snapshot;
 mod storage;
 
 use std::net::IpAddr;
@@ -24,22 +25,28 @@ pub fn run_swarm_worker(
     config: Config,
     state: State,
     server_start_instant: ServerStartInstant,
+    mut torrents: TorrentMaps,
     request_receiver: Receiver<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>,
+    control_receiver: Receiver<SwarmControlMessage>,
     response_sender: ConnectedResponseSender,
     statistics_sender: Sender<StatisticsMessage>,
     worker_index: SwarmWorkerIndex,
 ) {
-    let mut torrents = TorrentMaps::default();
     let mut rng = SmallRng::from_entropy();
 
     let timeout = Duration::from_millis(config.request_channel_recv_timeout_ms);
     let mut peer_valid_until = ValidUntil::new(server_start_instant, config.cleaning.max_peer_age);
 
-    let cleaning_interval = Duration::from_secs(config.cleaning.torrent_cleaning_interval);
+    // Clean one shard of each torrent map at a time, spreading cleaning
+    // work evenly over the cleaning interval
+    let num_shards = torrents.num_shards();
+    let shard_cleaning_interval = torrents.shard_cleaning_interval(&config);
     let statistics_update_interval = Duration::from_secs(config.statistics.interval);
+    let snapshot_interval = Duration::from_secs(config.snapshot.interval);
 
     let mut last_cleaning = Instant::now();
     let mut last_statistics_update = Instant::now();
+    let mut last_snapshot = Instant::now();
 
     let mut iter_counter = 0usize;
 
@@ -51,6 +58,7 @@ pub fn run_swarm_worker(
                         &config,
                         &mut rng,
                         &mut torrents.ipv4,
+                        &torrents.ipv6,
                         request,
                         ip,
                         peer_valid_until,
@@ -63,6 +71,7 @@ pub fn run_swarm_worker(
                         &config,
                         &mut rng,
                         &mut torrents.ipv6,
+                        &torrents.ipv4,
                         request,
                         ip,
                         peer_valid_until,
@@ -70,31 +79,51 @@ pub fn run_swarm_worker(
 
                     ConnectedResponse::AnnounceIpv6(response)
                 }
-                (ConnectedRequest::Scrape(request), IpAddr::V4(_)) => {
-                    ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv4, request))
-                }
-                (ConnectedRequest::Scrape(request), IpAddr::V6(_)) => {
-                    ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv6, request))
-                }
+                (ConnectedRequest::Scrape(request), IpAddr::V4(_)) => ConnectedResponse::Scrape(
+                    handle_scrape_request(&config, &torrents.ipv4, &torrents.ipv6, request),
+                ),
+                (ConnectedRequest::Scrape(request), IpAddr::V6(_)) => ConnectedResponse::Scrape(
+                    handle_scrape_request(&config, &torrents.ipv6, &torrents.ipv4, request),
+                ),
             };
 
             response_sender.try_send_to(sender_index, response, src);
         }
 
+        if let Ok(SwarmControlMessage::SaveSnapshot(done_sender)) = control_receiver.try_recv() {
+            if let Err(err) =
+                snapshot::save_snapshot(&config, worker_index, &torrents, server_start_instant)
+            {
+                ::log::error!("Couldn't save snapshot: {:#}", err);
+            }
+
+            let _ = done_sender.send(());
+        }
+
         // Run periodic tasks
         if iter_counter % 128 == 0 {
             let now = Instant::now();
 
             peer_valid_until = ValidUntil::new(server_start_instant, config.cleaning.max_peer_age);
 
-            if now > last_cleaning + cleaning_interval {
-                let (ipv4, ipv6) = torrents.clean_and_get_statistics(
+            if now > last_cleaning + shard_cleaning_interval {
+                // If periodic tasks were run too rarely to keep up, clean
+                // multiple shards
+                let num_shards_to_clean = (now - last_cleaning)
+                    .as_nanos()
+                    .checked_div(shard_cleaning_interval.as_nanos())
+                    .unwrap_or(1)
+                    .clamp(1, num_shards as u128)
+                    as usize;
+
+                let (ipv4, ipv6) = torrents.clean_next_shards_and_get_statistics(
                     &config,
                     &state.access_list,
                     server_start_instant,
+                    num_shards_to_clean,
                 );
 
-                if config.statistics.active() {
+                if config.statistics_active() {
                     state.statistics_ipv4.peers[worker_index.0].store(ipv4.0, Ordering::Release);
                     state.statistics_ipv6.peers[worker_index.0].store(ipv6.0, Ordering::Release);
 
@@ -112,7 +141,7 @@ pub fn run_swarm_worker(
 
                 last_cleaning = now;
             }
-            if config.statistics.active()
+            if config.statistics_active()
                 && now > last_statistics_update + statistics_update_interval
             {
                 state.statistics_ipv4.torrents[worker_index.0]
@@ -122,16 +151,32 @@ pub fn run_swarm_worker(
 
                 last_statistics_update = now;
             }
+            if config.snapshot.enabled
+                && config.snapshot.interval != 0
+                && now > last_snapshot + snapshot_interval
+            {
+                if let Err(err) =
+                    snapshot::save_snapshot(&config, worker_index, &torrents, server_start_instant)
+                {
+                    ::log::error!("Couldn't save snapshot: {:#}", err);
+                }
+
+                last_snapshot = now;
+            }
         }
 
         iter_counter = iter_counter.wrapping_add(1);
     }
 }
 
-pub fn handle_announce_request<I: Ip>(
+/// Handle announce request. Seeders and leechers in `other_torrents` (the
+/// torrent map for the other IP version) are included in counts if
+/// `config.protocol.count_peers_across_ip_versions` is set.
+pub fn handle_announce_request<I: Ip, J: Ip>(
     config: &Config,
     rng: &mut SmallRng,
     torrents: &mut TorrentMap<I>,
+    other_torrents: &TorrentMap<J>,
     request: AnnounceRequest,
     peer_ip: I,
     peer_valid_until: ValidUntil,
@@ -145,11 +190,11 @@ pub fn handle_announce_request<I: Ip>(
         )
     };
 
-    let torrent_data = torrents.0.entry(request.info_hash).or_default();
+    let torrent_data = torrents.get_or_insert_default(request.info_hash.0);
 
     let peer_status = PeerStatus::from_event_and_bytes_left(request.event, request.bytes_left);
 
-    torrent_data.update_peer(
+    let opt_previous_status = torrent_data.update_peer(
         request.peer_id,
         peer_ip,
         request.port,
@@ -157,36 +202,76 @@ pub fn handle_announce_request<I: Ip>(
         peer_valid_until,
     );
 
+    if config.protocol.track_completed_downloads {
+        torrent_data.count_completed_download(
+            request.peer_id,
+            request.event,
+            peer_status,
+            opt_previous_status,
+        );
+    }
+
     let response_peers = if let PeerStatus::Stopped = peer_status {
         Vec::new()
     } else {
         torrent_data.extract_response_peers(rng, request.peer_id, max_num_peers_to_take)
     };
 
+    let mut num_seeders = torrent_data.num_seeders();
+    let mut num_leechers = torrent_data.num_leechers();
+
+    if config.protocol.count_peers_across_ip_versions {
+        if let Some(other_torrent_data) = other_torrents.get(&request.info_hash.0) {
+            num_seeders += other_torrent_data.num_seeders();
+            num_leechers += other_torrent_data.num_leechers();
+        }
+    }
+
     AnnounceResponse {
         transaction_id: request.transaction_id,
         announce_interval: AnnounceInterval(config.protocol.peer_announce_interval),
-        leechers: NumberOfPeers(torrent_data.num_leechers().try_into().unwrap_or(i32::MAX)),
-        seeders: NumberOfPeers(torrent_data.num_seeders().try_into().unwrap_or(i32::MAX)),
+        leechers: NumberOfPeers(num_leechers.try_into().unwrap_or(i32::MAX)),
+        seeders: NumberOfPeers(num_seeders.try_into().unwrap_or(i32::MAX)),
         peers: response_peers,
     }
 }
 
-pub fn handle_scrape_request<I: Ip>(
-    torrents: &mut TorrentMap<I>,
+/// Handle scrape request. Statistics of `other_torrents` (the torrent map
+/// for the other IP version) are included if
+/// `config.protocol.count_peers_across_ip_versions` is set.
+pub fn handle_scrape_request<I: Ip, J: Ip>(
+    config: &Config,
+    torrents: &TorrentMap<I>,
+    other_torrents: &TorrentMap<J>,
     request: PendingScrapeRequest,
 ) -> PendingScrapeResponse {
-    const EMPTY_STATS: TorrentScrapeStatistics = create_torrent_scrape_statistics(0, 0);
+    const EMPTY_STATS: TorrentScrapeStatistics = create_torrent_scrape_statistics(0, 0, 0);
 
     let torrent_stats = request
         .info_hashes
         .into_iter()
         .map(|(i, info_hash)| {
-            let stats = torrents
-                .0
-                .get(&info_hash)
-                .map(|torrent_data| torrent_data.scrape_statistics())
-                .unwrap_or(EMPTY_STATS);
+            let opt_torrent_data = torrents.get(&info_hash.0);
+            let opt_other_torrent_data = if config.protocol.count_peers_across_ip_versions {
+                other_torrents.get(&info_hash.0)
+            } else {
+                None
+            };
+
+            let stats = match (opt_torrent_data, opt_other_torrent_data) {
+                (Some(a), Some(b)) => create_torrent_scrape_statistics(
+                    (a.num_seeders() + b.num_seeders())
+                        .try_into()
+                        .unwrap_or(i32::MAX),
+                    (a.num_leechers() + b.num_leechers())
+                        .try_into()
+                        .unwrap_or(i32::MAX),
+                    a.num_completed_combined(b).try_into().unwrap_or(i32::MAX),
+                ),
+                (Some(torrent_data), None) => torrent_data.scrape_statistics(),
+                (None, Some(torrent_data)) => torrent_data.scrape_statistics(),
+                (None, None) => EMPTY_STATS,
+            };
 
             (i, stats)
         })
@@ -199,10 +284,14 @@ pub fn handle_scrape_request<I: Ip>(
 }
 
 #[inline(always)]
-const fn create_torrent_scrape_statistics(seeders: i32, leechers: i32) -> TorrentScrapeStatistics {
+const fn create_torrent_scrape_statistics(
+    seeders: i32,
+    leechers: i32,
+    completed: i32,
+) -> TorrentScrapeStatistics {
     TorrentScrapeStatistics {
         seeders: NumberOfPeers(seeders),
-        completed: NumberOfDownloads(0), // No implementation planned
+        completed: NumberOfDownloads(completed),
         leechers: NumberOfPeers(leechers),
     }
 }
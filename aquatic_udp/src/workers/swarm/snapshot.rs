@@ -0,0 +1,607 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aquatic_common::{SecondsSinceServerStart, ServerStartInstant, ValidUntil};
+use aquatic_udp_protocol::*;
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::storage::{TorrentMap, TorrentMaps};
+
+const MAGIC_BYTES: &[u8; 8] = b"AQUDPSWM";
+const FORMAT_VERSION: u32 = 3;
+
+const FILE_NAME_PREFIX: &str = "swarm-";
+const FILE_NAME_EXTENSION: &str = "snapshot";
+/// Extension that snapshot files that couldn't be loaded are renamed to
+const INVALID_FILE_NAME_EXTENSION: &str = "invalid";
+
+/// Save torrent maps of a swarm worker to its snapshot file
+///
+/// File format (all integers big-endian):
+/// - magic bytes (8 bytes)
+/// - format version (u32)
+/// - unix timestamp of when snapshot was saved (u64)
+/// - IPv4 torrent map, then IPv6 torrent map, each consisting of:
+///   - number of torrents (u64)
+///   - for each torrent: info hash (20 bytes), number of peer ids counted
+///     as completed downloads (u64), those peer ids (20 bytes each),
+///     number of peers (u64)
+///   - for each peer: peer id (20 bytes), ip address (4 or 16 bytes),
+///     port (u16), seeder flag (u8), seconds until peer expires (u32)
+///
+/// Data is first written to a temporary file, which is then renamed, so
+/// that a partially written snapshot never replaces a complete one.
+pub fn save_snapshot(
+    config: &Config,
+    worker_index: SwarmWorkerIndex,
+    torrents: &TorrentMaps,
+    server_start_instant: ServerStartInstant,
+) -> anyhow::Result<()> {
+    let path = snapshot_path(config, worker_index);
+    let tmp_path = path.with_extension("tmp");
+
+    fs::create_dir_all(&config.snapshot.directory).with_context(|| {
+        format!(
+            "create snapshot directory {}",
+            config.snapshot.directory.display()
+        )
+    })?;
+
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("create snapshot file {}", tmp_path.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    let now = server_start_instant.seconds_elapsed();
+
+    writer.write_all(MAGIC_BYTES)?;
+    writer.write_all(&FORMAT_VERSION.to_be_bytes())?;
+    writer.write_all(&unix_timestamp_now().to_be_bytes())?;
+
+    write_torrent_map(&mut writer, &torrents.ipv4, now)?;
+    write_torrent_map(&mut writer, &torrents.ipv6, now)?;
+
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+
+    fs::rename(&tmp_path, &path)
+        .with_context(|| format!("rename snapshot file to {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Load torrent maps from all snapshot files in configured directory and
+/// distribute them among swarm workers
+///
+/// Since the number of swarm workers might have changed since the snapshots
+/// were saved, torrents are reassigned based on info hash. Files belonging
+/// to swarm workers that no longer exist are removed after loading. Files
+/// that can't be read are skipped entirely and renamed, so that they are not
+/// read again on next start.
+pub fn load_snapshots(
+    config: &Config,
+    server_start_instant: ServerStartInstant,
+) -> Vec<TorrentMaps> {
+    let mut torrent_maps: Vec<TorrentMaps> = ::std::iter::repeat_with(|| TorrentMaps::new(config))
+        .take(config.swarm_workers)
+        .collect();
+
+    let entries = match fs::read_dir(&config.snapshot.directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            ::log::info!(
+                "Snapshot directory {} not found, starting with empty torrent maps",
+                config.snapshot.directory.display()
+            );
+
+            return torrent_maps;
+        }
+        Err(err) => {
+            ::log::error!(
+                "Couldn't read snapshot directory {}: {:#}",
+                config.snapshot.directory.display(),
+                err
+            );
+
+            return torrent_maps;
+        }
+    };
+
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(err) => {
+                ::log::error!("Couldn't read snapshot directory entry: {:#}", err);
+
+                continue;
+            }
+        };
+
+        let worker_index = match parse_snapshot_path(&path) {
+            Some(worker_index) => worker_index,
+            None => continue,
+        };
+
+        match load_snapshot(config, &path, server_start_instant, &mut torrent_maps) {
+            Ok(num_peers) => {
+                ::log::info!("Loaded {} peers from {}", num_peers, path.display());
+            }
+            Err(err) => {
+                ::log::error!("Couldn't load snapshot {}: {:#}", path.display(), err);
+
+                let invalid_path = path.with_extension(INVALID_FILE_NAME_EXTENSION);
+
+                if let Err(err) = fs::rename(&path, &invalid_path) {
+                    ::log::error!(
+                        "Couldn't rename invalid snapshot {} to {}: {:#}",
+                        path.display(),
+                        invalid_path.display(),
+                        err
+                    );
+                }
+
+                continue;
+            }
+        }
+
+        if worker_index.0 >= config.swarm_workers {
+            if let Err(err) = fs::remove_file(&path) {
+                ::log::error!(
+                    "Couldn't remove stale snapshot {}: {:#}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    torrent_maps
+}
+
+/// Read snapshot file and insert its torrents into torrent maps. Nothing is
+/// inserted unless the whole file could be read.
+fn load_snapshot(
+    config: &Config,
+    path: &Path,
+    server_start_instant: ServerStartInstant,
+    torrent_maps: &mut [TorrentMaps],
+) -> anyhow::Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic_bytes = [0u8; 8];
+
+    reader.read_exact(&mut magic_bytes)?;
+
+    if &magic_bytes != MAGIC_BYTES {
+        return Err(anyhow::anyhow!("not a snapshot file"));
+    }
+
+    let version = read_u32(&mut reader)?;
+
+    if version != FORMAT_VERSION {
+        return Err(anyhow::anyhow!(
+            "unsupported format version {} (expected {})",
+            version,
+            FORMAT_VERSION
+        ));
+    }
+
+    let saved_at = read_u64(&mut reader)?;
+    let seconds_since_saved: u32 = unix_timestamp_now()
+        .saturating_sub(saved_at)
+        .try_into()
+        .unwrap_or(u32::MAX);
+
+    let ipv4_torrents =
+        read_torrent_map::<Ipv4Addr>(&mut reader, server_start_instant, seconds_since_saved)
+            .with_context(|| "read ipv4 torrents")?;
+    let ipv6_torrents =
+        read_torrent_map::<Ipv6Addr>(&mut reader, server_start_instant, seconds_since_saved)
+            .with_context(|| "read ipv6 torrents")?;
+
+    let mut num_peers = 0;
+
+    num_peers += insert_torrents(config, torrent_maps, ipv4_torrents, |maps| &mut maps.ipv4);
+    num_peers += insert_torrents(config, torrent_maps, ipv6_torrents, |maps| &mut maps.ipv6);
+
+    Ok(num_peers)
+}
+
+fn write_torrent_map<I: SnapshotIp>(
+    writer: &mut impl Write,
+    torrent_map: &TorrentMap<I>,
+    now: SecondsSinceServerStart,
+) -> io::Result<()> {
+    writer.write_all(&(torrent_map.num_torrents() as u64).to_be_bytes())?;
+
+    for (info_hash, torrent_data) in torrent_map.iter() {
+        writer.write_all(info_hash)?;
+        writer.write_all(&(torrent_data.completed_downloads.count() as u64).to_be_bytes())?;
+
+        for peer_id in torrent_data.completed_downloads.peer_ids() {
+            writer.write_all(&peer_id.0)?;
+        }
+
+        writer.write_all(&(torrent_data.peers.len() as u64).to_be_bytes())?;
+
+        for (peer_id, peer) in torrent_data.peers.iter() {
+            writer.write_all(&peer_id.0)?;
+            peer.ip_address.write(writer)?;
+            writer.write_all(&peer.port.0.to_be_bytes())?;
+            writer.write_all(&[peer.is_seeder as u8])?;
+            writer.write_all(&peer.valid_until.seconds_remaining(now).to_be_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Torrent read from snapshot file
+struct SnapshotTorrent<I> {
+    info_hash: InfoHash,
+    completed_downloads: Vec<PeerId>,
+    peers: Vec<SnapshotPeer<I>>,
+}
+
+struct SnapshotPeer<I> {
+    peer_id: PeerId,
+    ip_address: I,
+    port: Port,
+    status: PeerStatus,
+    valid_until: ValidUntil,
+}
+
+/// Read one torrent map from snapshot. Expired peers and torrents without
+/// any remaining peers are skipped.
+fn read_torrent_map<I: SnapshotIp>(
+    reader: &mut impl Read,
+    server_start_instant: ServerStartInstant,
+    seconds_since_saved: u32,
+) -> io::Result<Vec<SnapshotTorrent<I>>> {
+    let mut torrents = Vec::new();
+
+    for _ in 0..read_u64(reader)? {
+        let mut info_hash = InfoHash([0; 20]);
+
+        reader.read_exact(&mut info_hash.0)?;
+
+        let mut completed_downloads = Vec::new();
+
+        for _ in 0..read_u64(reader)? {
+            let mut peer_id = PeerId([0; 20]);
+
+            reader.read_exact(&mut peer_id.0)?;
+
+            completed_downloads.push(peer_id);
+        }
+
+        let mut peers = Vec::new();
+
+        for _ in 0..read_u64(reader)? {
+            let mut peer_id = PeerId([0; 20]);
+
+            reader.read_exact(&mut peer_id.0)?;
+
+            let ip_address = I::read(reader)?;
+            let port = Port(read_u16(reader)?);
+            let is_seeder = read_u8(reader)? != 0;
+            let seconds_remaining = read_u32(reader)?.saturating_sub(seconds_since_saved);
+
+            if seconds_remaining == 0 {
+                continue;
+            }
+
+            let status = if is_seeder {
+                PeerStatus::Seeding
+            } else {
+                PeerStatus::Leeching
+            };
+
+            peers.push(SnapshotPeer {
+                peer_id,
+                ip_address,
+                port,
+                status,
+                valid_until: ValidUntil::new(server_start_instant, seconds_remaining),
+            });
+        }
+
+        if !peers.is_empty() {
+            torrents.push(SnapshotTorrent {
+                info_hash,
+                completed_downloads,
+                peers,
+            });
+        }
+    }
+
+    Ok(torrents)
+}
+
+/// Insert each torrent into the torrent maps of the swarm worker responsible
+/// for it. Returns number of inserted peers.
+fn insert_torrents<I: Ip>(
+    config: &Config,
+    torrent_maps: &mut [TorrentMaps],
+    torrents: Vec<SnapshotTorrent<I>>,
+    get_torrent_map: impl Fn(&mut TorrentMaps) -> &mut TorrentMap<I>,
+) -> usize {
+    let mut num_peers = 0;
+
+    for torrent in torrents {
+        let worker_index = SwarmWorkerIndex::from_info_hash(config, torrent.info_hash);
+        let torrent_data = get_torrent_map(&mut torrent_maps[worker_index.0])
+            .get_or_insert_default(torrent.info_hash.0);
+
+        for peer_id in torrent.completed_downloads {
+            torrent_data.completed_downloads.insert(peer_id);
+        }
+
+        for peer in torrent.peers {
+            torrent_data.update_peer(
+                peer.peer_id,
+                peer.ip_address,
+                peer.port,
+                peer.status,
+                peer.valid_until,
+            );
+
+            num_peers += 1;
+        }
+    }
+
+    num_peers
+}
+
+fn snapshot_path(config: &Config, worker_index: SwarmWorkerIndex) -> PathBuf {
+    config.snapshot.directory.join(format!(
+        "{}{}.{}",
+        FILE_NAME_PREFIX, worker_index.0, FILE_NAME_EXTENSION
+    ))
+}
+
+fn parse_snapshot_path(path: &Path) -> Option<SwarmWorkerIndex> {
+    if path.extension()? != FILE_NAME_EXTENSION {
+        return None;
+    }
+
+    path.file_stem()?
+        .to_str()?
+        .strip_prefix(FILE_NAME_PREFIX)?
+        .parse()
+        .ok()
+        .map(SwarmWorkerIndex)
+}
+
+fn unix_timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+trait SnapshotIp: Ip {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()>;
+    fn read(reader: &mut impl Read) -> io::Result<Self>;
+}
+
+impl SnapshotIp for Ipv4Addr {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.octets())
+    }
+    fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut octets = [0; 4];
+
+        reader.read_exact(&mut octets)?;
+
+        Ok(octets.into())
+    }
+}
+
+impl SnapshotIp for Ipv6Addr {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.octets())
+    }
+    fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut octets = [0; 16];
+
+        reader.read_exact(&mut octets)?;
+
+        Ok(octets.into())
+    }
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+
+    reader.read_exact(&mut bytes)?;
+
+    Ok(bytes[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load_snapshot() {
+        let mut config = Config::default();
+
+        config.swarm_workers = 2;
+        config.snapshot.directory = ::std::env::temp_dir().join(format!(
+            "aquatic-udp-snapshot-test-{}",
+            ::std::process::id()
+        ));
+
+        let server_start_instant = ServerStartInstant::new();
+        let valid_until = ValidUntil::new(server_start_instant, 100);
+
+        let mut torrents = TorrentMaps::new(&config);
+
+        for i in 0..4u8 {
+            let torrent_data = torrents.ipv4.get_or_insert_default([i; 20]);
+
+            torrent_data.update_peer(
+                PeerId([i; 20]),
+                Ipv4Addr::new(127, 0, 0, i),
+                Port(1000 + i as u16),
+                PeerStatus::Seeding,
+                valid_until,
+            );
+            torrent_data.update_peer(
+                PeerId([i + 100; 20]),
+                Ipv4Addr::new(127, 0, 1, i),
+                Port(2000 + i as u16),
+                PeerStatus::Leeching,
+                valid_until,
+            );
+
+            for j in 0..i {
+                torrent_data.completed_downloads.insert(PeerId([j; 20]));
+            }
+        }
+
+        torrents.ipv6.get_or_insert_default([1; 20]).update_peer(
+            PeerId([1; 20]),
+            Ipv6Addr::LOCALHOST,
+            Port(1),
+            PeerStatus::Leeching,
+            valid_until,
+        );
+
+        // Expired peers are not restored
+        torrents.ipv6.get_or_insert_default([2; 20]).update_peer(
+            PeerId([2; 20]),
+            Ipv6Addr::LOCALHOST,
+            Port(2),
+            PeerStatus::Leeching,
+            ValidUntil::new(server_start_instant, 0),
+        );
+
+        save_snapshot(
+            &config,
+            SwarmWorkerIndex(0),
+            &torrents,
+            server_start_instant,
+        )
+        .unwrap();
+
+        let loaded = load_snapshots(&config, ServerStartInstant::new());
+
+        fs::remove_dir_all(&config.snapshot.directory).unwrap();
+
+        assert_eq!(loaded.len(), 2);
+
+        for (i, maps) in loaded.iter().enumerate() {
+            for (info_hash, torrent_data) in maps.ipv4.iter() {
+                assert_eq!(
+                    SwarmWorkerIndex::from_info_hash(&config, InfoHash(*info_hash)).0,
+                    i
+                );
+                assert_eq!(torrent_data.num_seeders(), 1);
+                assert_eq!(torrent_data.num_leechers(), 1);
+                assert_eq!(torrent_data.num_completed(), info_hash[0] as usize);
+            }
+        }
+
+        let num_ipv4_torrents: usize = loaded.iter().map(|maps| maps.ipv4.num_torrents()).sum();
+        let num_ipv6_torrents: usize = loaded.iter().map(|maps| maps.ipv6.num_torrents()).sum();
+
+        assert_eq!(num_ipv4_torrents, 4);
+        assert_eq!(num_ipv6_torrents, 1);
+
+        let peer = loaded[1]
+            .ipv4
+            .get(&[1; 20])
+            .unwrap()
+            .peers
+            .get(&PeerId([1; 20]))
+            .unwrap();
+
+        assert_eq!(peer.ip_address, Ipv4Addr::new(127, 0, 0, 1));
+        assert_eq!(peer.port, Port(1001));
+        assert!(peer.is_seeder);
+    }
+
+    #[test]
+    fn test_load_invalid_snapshot() {
+        let mut config = Config::default();
+
+        config.swarm_workers = 1;
+        config.snapshot.directory = ::std::env::temp_dir().join(format!(
+            "aquatic-udp-invalid-snapshot-test-{}",
+            ::std::process::id()
+        ));
+
+        let server_start_instant = ServerStartInstant::new();
+
+        let mut torrents = TorrentMaps::new(&config);
+
+        for i in 0..4u8 {
+            torrents.ipv4.get_or_insert_default([i; 20]).update_peer(
+                PeerId([i; 20]),
+                Ipv4Addr::new(127, 0, 0, i),
+                Port(1000),
+                PeerStatus::Seeding,
+                ValidUntil::new(server_start_instant, 100),
+            );
+        }
+
+        // Second file belongs to swarm worker that no longer exists
+        for worker_index in [SwarmWorkerIndex(0), SwarmWorkerIndex(1)] {
+            save_snapshot(&config, worker_index, &torrents, server_start_instant).unwrap();
+
+            // Cut off IPv6 torrent map, so that all IPv4 torrents can be read
+            let path = snapshot_path(&config, worker_index);
+            let bytes = fs::read(&path).unwrap();
+
+            fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        }
+
+        for _ in 0..2 {
+            let loaded = load_snapshots(&config, ServerStartInstant::new());
+
+            assert_eq!(loaded[0].ipv4.num_torrents(), 0);
+        }
+
+        for worker_index in [SwarmWorkerIndex(0), SwarmWorkerIndex(1)] {
+            let path = snapshot_path(&config, worker_index);
+
+            assert!(!path.exists());
+            assert!(path.with_extension(INVALID_FILE_NAME_EXTENSION).exists());
+        }
+
+        fs::remove_dir_all(&config.snapshot.directory).unwrap();
+    }
+}
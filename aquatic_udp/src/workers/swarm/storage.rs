@@ -1,13 +1,16 @@
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use aquatic_common::completed_downloads::CompletedDownloads;
+use aquatic_common::sharded_map::ShardedMap;
 use aquatic_common::IndexMap;
 use aquatic_common::SecondsSinceServerStart;
 use aquatic_common::ServerStartInstant;
 use aquatic_common::{
     access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache, AccessListMode},
-    extract_response_peers, AmortizedIndexMap, ValidUntil,
+    extract_response_peers, ValidUntil,
 };
 
 use aquatic_udp_protocol::*;
@@ -20,11 +23,11 @@ use crate::config::Config;
 use super::create_torrent_scrape_statistics;
 
 #[derive(Clone, Debug)]
-struct Peer<I: Ip> {
-    ip_address: I,
-    port: Port,
-    is_seeder: bool,
-    valid_until: ValidUntil,
+pub(super) struct Peer<I: Ip> {
+    pub(super) ip_address: I,
+    pub(super) port: Port,
+    pub(super) is_seeder: bool,
+    pub(super) valid_until: ValidUntil,
 }
 
 impl<I: Ip> Peer<I> {
@@ -39,11 +42,15 @@ impl<I: Ip> Peer<I> {
 type PeerMap<I> = IndexMap<PeerId, Peer<I>>;
 
 pub struct TorrentData<I: Ip> {
-    peers: PeerMap<I>,
+    pub(super) peers: PeerMap<I>,
     num_seeders: usize,
+    /// Only updated if `config.protocol.track_completed_downloads` is set
+    pub(super) completed_downloads: CompletedDownloads<PeerId>,
 }
 
 impl<I: Ip> TorrentData<I> {
+    /// Insert, update or remove peer. Returns previous status of peer if it
+    /// was present.
     pub fn update_peer(
         &mut self,
         peer_id: PeerId,
@@ -51,7 +58,7 @@ impl<I: Ip> TorrentData<I> {
         port: Port,
         status: PeerStatus,
         valid_until: ValidUntil,
-    ) {
+    ) -> Option<PeerStatus> {
         let opt_removed_peer = match status {
             PeerStatus::Leeching => {
                 let peer = Peer {
@@ -78,14 +85,46 @@ impl<I: Ip> TorrentData<I> {
             PeerStatus::Stopped => self.peers.remove(&peer_id),
         };
 
-        if let Some(Peer {
-            is_seeder: true, ..
-        }) = opt_removed_peer
-        {
-            self.num_seeders -= 1;
+        match opt_removed_peer {
+            Some(Peer {
+                is_seeder: true, ..
+            }) => {
+                self.num_seeders -= 1;
+
+                Some(PeerStatus::Seeding)
+            }
+            Some(_) => Some(PeerStatus::Leeching),
+            None => None,
         }
     }
 
+    /// Count completed download (see [CompletedDownloads])
+    pub fn count_completed_download(
+        &mut self,
+        peer_id: PeerId,
+        event: AnnounceEvent,
+        status: PeerStatus,
+        opt_previous_status: Option<PeerStatus>,
+    ) {
+        self.completed_downloads.update(
+            peer_id,
+            status == PeerStatus::Seeding,
+            opt_previous_status.map(|status| status == PeerStatus::Seeding),
+            event == AnnounceEvent::Completed,
+        );
+    }
+
+    pub fn num_completed(&self) -> usize {
+        self.completed_downloads.count()
+    }
+
+    /// Number of completed downloads in this swarm and swarm of other IP
+    /// version, counting peers present in both once
+    pub fn num_completed_combined<J: Ip>(&self, other: &TorrentData<J>) -> usize {
+        self.completed_downloads
+            .count_union(&other.completed_downloads)
+    }
+
     pub fn extract_response_peers(
         &self,
         rng: &mut SmallRng,
@@ -97,7 +136,7 @@ impl<I: Ip> TorrentData<I> {
             &self.peers,
             max_num_peers_to_take,
             peer_id,
-            Peer::to_response_peer,
+            |_, peer| peer.to_response_peer(),
         )
     }
 
@@ -113,6 +152,7 @@ impl<I: Ip> TorrentData<I> {
         create_torrent_scrape_statistics(
             self.num_seeders.try_into().unwrap_or(i32::MAX),
             self.num_leechers().try_into().unwrap_or(i32::MAX),
+            self.num_completed().try_into().unwrap_or(i32::MAX),
         )
     }
 
@@ -139,98 +179,50 @@ impl<I: Ip> Default for TorrentData<I> {
         Self {
             peers: Default::default(),
             num_seeders: 0,
+            completed_downloads: Default::default(),
         }
     }
 }
 
-#[derive(Default)]
-pub struct TorrentMap<I: Ip>(pub AmortizedIndexMap<InfoHash, TorrentData<I>>);
-
-impl<I: Ip> TorrentMap<I> {
-    /// Remove forbidden or inactive torrents, reclaim space and return number of remaining peers
-    fn clean_and_get_statistics(
-        &mut self,
-        config: &Config,
-        access_list_cache: &mut AccessListCache,
-        access_list_mode: AccessListMode,
-        now: SecondsSinceServerStart,
-    ) -> (usize, Option<Histogram<u64>>) {
-        let mut num_peers = 0;
-
-        let mut opt_histogram: Option<Histogram<u64>> = if config.statistics.extended {
-            match Histogram::new(3) {
-                Ok(histogram) => Some(histogram),
-                Err(err) => {
-                    ::log::error!("Couldn't create peer histogram: {:#}", err);
-
-                    None
-                }
-            }
-        } else {
-            None
-        };
-
-        self.0.retain(|info_hash, torrent| {
-            if !access_list_cache
-                .load()
-                .allows(access_list_mode, &info_hash.0)
-            {
-                return false;
-            }
-
-            torrent.clean(now);
-
-            num_peers += torrent.peers.len();
-
-            match opt_histogram {
-                Some(ref mut histogram) if torrent.peers.len() != 0 => {
-                    let n = torrent
-                        .peers
-                        .len()
-                        .try_into()
-                        .expect("Couldn't fit usize into u64");
-
-                    if let Err(err) = histogram.record(n) {
-                        ::log::error!("Couldn't record {} to histogram: {:#}", n, err);
-                    }
-                }
-                _ => (),
-            }
-
-            !torrent.peers.is_empty()
-        });
-
-        self.0.shrink_to_fit();
-
-        (num_peers, opt_histogram)
-    }
-
-    pub fn num_torrents(&self) -> usize {
-        self.0.len()
-    }
-}
+/// Torrents indexed by info hash bytes
+pub type TorrentMap<I> = ShardedMap<TorrentData<I>>;
 
 pub struct TorrentMaps {
     pub ipv4: TorrentMap<Ipv4Addr>,
     pub ipv6: TorrentMap<Ipv6Addr>,
+    /// Peer histograms being collected during current rounds of cleaning
+    pending_histograms: (Option<Histogram<u64>>, Option<Histogram<u64>>),
 }
 
-impl Default for TorrentMaps {
-    fn default() -> Self {
+impl TorrentMaps {
+    pub fn new(config: &Config) -> Self {
         Self {
-            ipv4: TorrentMap(Default::default()),
-            ipv6: TorrentMap(Default::default()),
+            ipv4: TorrentMap::new(config.cleaning.torrent_map_shards),
+            ipv6: TorrentMap::new(config.cleaning.torrent_map_shards),
+            pending_histograms: (create_histogram(config), create_histogram(config)),
         }
     }
-}
 
-impl TorrentMaps {
-    /// Remove forbidden or inactive torrents, reclaim space and return number of remaining peers
-    pub fn clean_and_get_statistics(
+    /// Time between cleaning shards of each torrent map
+    pub fn shard_cleaning_interval(&self, config: &Config) -> Duration {
+        self.ipv4.shard_cleaning_interval(Duration::from_secs(
+            config.cleaning.torrent_cleaning_interval,
+        ))
+    }
+
+    /// Number of shards in each torrent map
+    pub fn num_shards(&self) -> usize {
+        self.ipv4.num_shards()
+    }
+
+    /// Clean next `num_shards` shards of each torrent map (see
+    /// [clean_next_shards_and_get_statistics])
+    pub fn clean_next_shards_and_get_statistics(
         &mut self,
         config: &Config,
         access_list: &Arc<AccessListArcSwap>,
         server_start_instant: ServerStartInstant,
+        num_shards: usize,
     ) -> (
         (usize, Option<Histogram<u64>>),
         (usize, Option<Histogram<u64>>),
@@ -239,17 +231,95 @@ impl TorrentMaps {
         let mode = config.access_list.mode;
         let now = server_start_instant.seconds_elapsed();
 
-        let ipv4 = self
-            .ipv4
-            .clean_and_get_statistics(config, &mut cache, mode, now);
-        let ipv6 = self
-            .ipv6
-            .clean_and_get_statistics(config, &mut cache, mode, now);
+        let ipv4 = clean_next_shards_and_get_statistics(
+            config,
+            &mut self.ipv4,
+            &mut self.pending_histograms.0,
+            &mut cache,
+            mode,
+            now,
+            num_shards,
+        );
+        let ipv6 = clean_next_shards_and_get_statistics(
+            config,
+            &mut self.ipv6,
+            &mut self.pending_histograms.1,
+            &mut cache,
+            mode,
+            now,
+            num_shards,
+        );
 
         (ipv4, ipv6)
     }
 }
 
+/// Remove forbidden or inactive torrents and reclaim space in next
+/// `num_shards` shards of torrent map.
+///
+/// Returns number of peers in all shards as of their last cleaning, as
+/// well as a peer histogram if extended statistics are enabled and a
+/// full round of cleaning was just completed.
+fn clean_next_shards_and_get_statistics<I: Ip>(
+    config: &Config,
+    torrent_map: &mut TorrentMap<I>,
+    pending_histogram: &mut Option<Histogram<u64>>,
+    access_list_cache: &mut AccessListCache,
+    access_list_mode: AccessListMode,
+    now: SecondsSinceServerStart,
+    num_shards: usize,
+) -> (usize, Option<Histogram<u64>>) {
+    let mut opt_complete_histogram = None;
+
+    for _ in 0..num_shards {
+        let round_completed = torrent_map.clean_next_shard(|info_hash, torrent| {
+            if !access_list_cache.load().allows(access_list_mode, info_hash) {
+                return None;
+            }
+
+            torrent.clean(now);
+
+            let num_peers = torrent.peers.len();
+
+            if num_peers == 0 {
+                return None;
+            }
+
+            if let Some(histogram) = pending_histogram.as_mut() {
+                let n = num_peers.try_into().expect("Couldn't fit usize into u64");
+
+                if let Err(err) = histogram.record(n) {
+                    ::log::error!("Couldn't record {} to histogram: {:#}", n, err);
+                }
+            }
+
+            Some(num_peers)
+        });
+
+        if round_completed {
+            opt_complete_histogram =
+                ::std::mem::replace(pending_histogram, create_histogram(config));
+        }
+    }
+
+    (torrent_map.num_peers(), opt_complete_histogram)
+}
+
+fn create_histogram(config: &Config) -> Option<Histogram<u64>> {
+    if config.statistics.extended {
+        match Histogram::new(3) {
+            Ok(histogram) => Some(histogram),
+            Err(err) => {
+                ::log::error!("Couldn't create peer histogram: {:#}", err);
+
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
@@ -276,6 +346,93 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_count_completed_download() {
+        let mut torrent_data = TorrentData::<Ipv4Addr>::default();
+        let valid_until = ValidUntil::new(ServerStartInstant::new(), 0);
+        let peer_id = gen_peer_id(0);
+
+        // Counting rules are tested in aquatic_common::completed_downloads
+        for status in [PeerStatus::Leeching, PeerStatus::Seeding] {
+            let opt_previous_status = torrent_data.update_peer(
+                peer_id,
+                Ipv4Addr::LOCALHOST,
+                Port(1),
+                status,
+                valid_until,
+            );
+
+            torrent_data.count_completed_download(
+                peer_id,
+                AnnounceEvent::None,
+                status,
+                opt_previous_status,
+            );
+        }
+
+        assert_eq!(torrent_data.num_completed(), 1);
+    }
+
+    #[test]
+    fn test_torrent_map_incremental_cleaning() {
+        let mut config = Config::default();
+
+        config.cleaning.torrent_map_shards = 3;
+        config.statistics.extended = true;
+
+        let access_list = Arc::new(AccessListArcSwap::default());
+        let mut access_list_cache = create_access_list_cache(&access_list);
+        let server_start_instant = ServerStartInstant::new();
+        let now = server_start_instant.seconds_elapsed();
+
+        let mut torrent_map = TorrentMap::<Ipv4Addr>::new(config.cleaning.torrent_map_shards);
+        let mut pending_histogram = create_histogram(&config);
+
+        assert_eq!(torrent_map.num_shards(), 4);
+
+        for i in 0..64u8 {
+            let mut info_hash = InfoHash([0; 20]);
+
+            info_hash.0[4] = i;
+
+            // Peers of last half of torrents have expired
+            let valid_until = ValidUntil::new(server_start_instant, (i < 32).into());
+
+            torrent_map.get_or_insert_default(info_hash.0).update_peer(
+                gen_peer_id(i.into()),
+                Ipv4Addr::LOCALHOST,
+                Port(1),
+                PeerStatus::Leeching,
+                valid_until,
+            );
+        }
+
+        assert_eq!(torrent_map.num_torrents(), 64);
+
+        let mut clean = |num_shards| {
+            clean_next_shards_and_get_statistics(
+                &config,
+                &mut torrent_map,
+                &mut pending_histogram,
+                &mut access_list_cache,
+                AccessListMode::Off,
+                now,
+                num_shards,
+            )
+        };
+
+        let (num_peers, opt_histogram) = clean(1);
+
+        assert_eq!(num_peers, 8);
+        assert!(opt_histogram.is_none());
+
+        let (num_peers, opt_histogram) = clean(3);
+
+        assert_eq!(num_peers, 32);
+        assert_eq!(opt_histogram.unwrap().len(), 32);
+        assert_eq!(torrent_map.num_torrents(), 32);
+    }
+
     #[test]
     fn test_extract_response_peers() {
         fn prop(data: (u16, u16)) -> TestResult {
@@ -306,7 +463,7 @@ mod tests {
                 &peer_map,
                 req_num_peers,
                 opt_sender_key.unwrap_or_else(|| gen_peer_id(1)),
-                Peer::to_response_peer,
+                |_, peer| peer.to_response_peer(),
             );
 
             // Check that number of returned peers is correct
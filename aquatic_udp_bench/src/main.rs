@@ -8,7 +8,7 @@
 //! ```
 
 use aquatic_common::{PanicSentinelWatcher, ServerStartInstant};
-use aquatic_udp::workers::swarm::run_swarm_worker;
+use aquatic_udp::workers::swarm::{run_swarm_worker, TorrentMaps};
 use crossbeam_channel::unbounded;
 use num_format::{Locale, ToFormattedString};
 use rand::{rngs::SmallRng, thread_rng, Rng, SeedableRng};
@@ -51,12 +51,14 @@ pub fn run(bench_config: BenchConfig) -> ::anyhow::Result<()> {
 
     let response_sender = ConnectedResponseSender::new(vec![response_sender]);
     let (statistics_sender, _) = unbounded();
+    let (_, control_receiver) = unbounded();
 
     let server_start_instant = ServerStartInstant::new();
 
     {
         let config = aquatic_config.clone();
         let state = State::new(config.swarm_workers);
+        let torrents = TorrentMaps::new(&config);
 
         ::std::thread::spawn(move || {
             run_swarm_worker(
@@ -64,7 +66,9 @@ pub fn run(bench_config: BenchConfig) -> ::anyhow::Result<()> {
                 config,
                 state,
                 server_start_instant,
+                torrents,
                 request_receiver,
+                control_receiver,
                 response_sender,
                 statistics_sender,
                 SwarmWorkerIndex(0),
@@ -9,6 +9,11 @@ use super::common::*;
 
 const PROTOCOL_IDENTIFIER: i64 = 4_497_486_125_440;
 
+/// BEP 41 option types
+const OPTION_END_OF_OPTIONS: u8 = 0x0;
+const OPTION_NOP: u8 = 0x1;
+const OPTION_URL_DATA: u8 = 0x2;
+
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum AnnounceEvent {
@@ -61,6 +66,9 @@ pub struct AnnounceRequest {
     pub key: PeerKey,
     pub peers_wanted: NumberOfPeers,
     pub port: Port,
+    /// Path and query string of announce URL, concatenated from BEP 41
+    /// URLData options. None if request contained no such options.
+    pub path: Option<Vec<u8>>,
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -164,6 +172,10 @@ impl Request {
                 bytes.write_u32::<NetworkEndian>(r.key.0)?;
                 bytes.write_i32::<NetworkEndian>(r.peers_wanted.0)?;
                 bytes.write_u16::<NetworkEndian>(r.port.0)?;
+
+                if let Some(path) = r.path {
+                    write_url_data_options(bytes, &path)?;
+                }
             }
 
             Request::Scrape(r) => {
@@ -254,6 +266,8 @@ impl Request {
                     Some(Ipv4Addr::from(ip))
                 };
 
+                let opt_path = read_url_data_options(&mut cursor);
+
                 Ok((AnnounceRequest {
                     connection_id: ConnectionId(connection_id),
                     transaction_id: TransactionId(transaction_id),
@@ -267,6 +281,7 @@ impl Request {
                     key: PeerKey(key),
                     peers_wanted: NumberOfPeers(peers_wanted),
                     port: Port(port),
+                    path: opt_path,
                 })
                 .into())
             }
@@ -307,6 +322,58 @@ impl Request {
     }
 }
 
+/// Write path as BEP 41 URLData options, splitting it into multiple options
+/// if it is longer than 255 bytes
+fn write_url_data_options(bytes: &mut impl Write, path: &[u8]) -> Result<(), io::Error> {
+    if path.is_empty() {
+        bytes.write_all(&[OPTION_URL_DATA, 0])?;
+    }
+
+    for chunk in path.chunks(u8::MAX as usize) {
+        bytes.write_all(&[OPTION_URL_DATA, chunk.len() as u8])?;
+        bytes.write_all(chunk)?;
+    }
+
+    bytes.write_all(&[OPTION_END_OF_OPTIONS])
+}
+
+/// Read BEP 41 options, returning concatenated URLData contents if any
+/// such options are present. Unknown options are skipped. Parsing stops at
+/// malformed (e.g., truncated) options, since requests with trailing data
+/// that is not valid options have always been accepted.
+fn read_url_data_options(cursor: &mut Cursor<&[u8]>) -> Option<Vec<u8>> {
+    let mut opt_path: Option<Vec<u8>> = None;
+
+    while let Ok(option_type) = cursor.read_u8() {
+        match option_type {
+            OPTION_END_OF_OPTIONS => break,
+            OPTION_NOP => continue,
+            option_type => {
+                let len = match cursor.read_u8() {
+                    Ok(len) => len as usize,
+                    Err(_) => break,
+                };
+
+                let position = cursor.position() as usize;
+                let data = match cursor.get_ref().get(position..position + len) {
+                    Some(data) => data,
+                    None => break,
+                };
+
+                if option_type == OPTION_URL_DATA {
+                    opt_path
+                        .get_or_insert_with(Vec::new)
+                        .extend_from_slice(data);
+                }
+
+                cursor.set_position((position + len) as u64);
+            }
+        }
+    }
+
+    opt_path
+}
+
 #[cfg(test)]
 mod tests {
     use quickcheck::TestResult;
@@ -348,6 +415,7 @@ mod tests {
                 key: PeerKey(u32::arbitrary(g)),
                 peers_wanted: NumberOfPeers(i32::arbitrary(g)),
                 port: Port(u16::arbitrary(g)),
+                path: Option::<Vec<u8>>::arbitrary(g),
             }
         }
     }
@@ -391,6 +459,81 @@ mod tests {
         same_after_conversion(request.into())
     }
 
+    #[test]
+    fn test_announce_request_url_data_options() {
+        let mut request = AnnounceRequest {
+            connection_id: ConnectionId(1),
+            transaction_id: TransactionId(2),
+            info_hash: InfoHash([3; 20]),
+            peer_id: PeerId([4; 20]),
+            bytes_downloaded: NumberOfBytes(5),
+            bytes_uploaded: NumberOfBytes(6),
+            bytes_left: NumberOfBytes(7),
+            event: AnnounceEvent::Started,
+            ip_address: None,
+            key: PeerKey(8),
+            peers_wanted: NumberOfPeers(9),
+            port: Port(10),
+            path: None,
+        };
+
+        let mut buf = Vec::new();
+
+        Request::from(request.clone()).write(&mut buf).unwrap();
+
+        // NOP, multi-part URLData, unknown option, URLData, EndOfOptions,
+        // then trailing garbage which should be ignored
+        buf.extend_from_slice(&[OPTION_NOP, OPTION_URL_DATA, 4]);
+        buf.extend_from_slice(b"/ann");
+        buf.extend_from_slice(&[OPTION_URL_DATA, 3]);
+        buf.extend_from_slice(b"oun");
+        buf.extend_from_slice(&[0xff, 2, 1, 1]);
+        buf.extend_from_slice(&[OPTION_URL_DATA, 7]);
+        buf.extend_from_slice(b"ce?a=bc");
+        buf.extend_from_slice(&[OPTION_END_OF_OPTIONS, OPTION_URL_DATA, 1, b'x']);
+
+        request.path = Some(b"/announce?a=bc".to_vec());
+
+        assert_eq!(
+            Request::from_bytes(&buf[..], 1).unwrap(),
+            Request::Announce(request.clone())
+        );
+
+        // Truncated option data: stop parsing options, but keep request
+        // and any URLData read before
+        let len = buf.len();
+        buf.truncate(len - 4);
+        buf.extend_from_slice(&[OPTION_URL_DATA, 10, b'x']);
+
+        assert_eq!(
+            Request::from_bytes(&buf[..], 1).unwrap(),
+            Request::Announce(request.clone())
+        );
+
+        // Missing option length
+        buf.truncate(len - 4);
+        buf.push(OPTION_URL_DATA);
+
+        assert_eq!(
+            Request::from_bytes(&buf[..], 1).unwrap(),
+            Request::Announce(request.clone())
+        );
+
+        // Garbage without any valid options
+        request.path = None;
+
+        let mut buf = Vec::new();
+
+        Request::from(request.clone()).write(&mut buf).unwrap();
+
+        buf.extend_from_slice(&[0x80, 200, 1, 2, 3]);
+
+        assert_eq!(
+            Request::from_bytes(&buf[..], 1).unwrap(),
+            Request::Announce(request)
+        );
+    }
+
     #[quickcheck]
     fn test_scrape_request_convert_identity(request: ScrapeRequest) -> TestResult {
         if request.info_hashes.is_empty() {
@@ -1,10 +1,16 @@
 use std::{net::IpAddr, sync::Arc};
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::connection_limits::ConnectionLimiter;
+use aquatic_common::ip_list::IpListArcSwap;
+use aquatic_common::proxy_protocol::TrustedSources;
+use aquatic_common::reverse_proxy::TrustedProxies;
 
 pub use aquatic_common::ValidUntil;
 use aquatic_ws_protocol::{InfoHash, PeerId};
 
+use crate::workers::rate_limit::IpRateLimiter;
+
 #[derive(Copy, Clone, Debug)]
 pub enum IpVersion {
     V4,
@@ -26,6 +32,94 @@ impl IpVersion {
 #[derive(Default, Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub ip_list: Arc<IpListArcSwap>,
+    pub trusted_proxies: Arc<TrustedProxies>,
+    pub proxy_protocol_sources: Arc<TrustedSources>,
+    pub connection_limiter: Arc<ConnectionLimiter>,
+    pub ip_rate_limiter: Arc<IpRateLimiter>,
+    pub allowed_origins: Arc<AllowedOrigins>,
+}
+
+/// Origin header values to accept in WebSocket handshake requests. Empty
+/// list means that all requests are accepted.
+#[derive(Clone, Debug, Default)]
+pub struct AllowedOrigins(Vec<OriginPattern>);
+
+impl AllowedOrigins {
+    pub fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| OriginPattern::new(pattern))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self(patterns))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check if handshake request with Origin header value is allowed.
+    /// Requests without the header are only allowed if list is empty.
+    pub fn allows(&self, opt_origin: Option<&[u8]>) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+
+        let origin = match opt_origin.and_then(|origin| ::std::str::from_utf8(origin).ok()) {
+            Some(origin) => origin.trim().to_ascii_lowercase(),
+            None => return false,
+        };
+
+        self.0.iter().any(|pattern| pattern.matches(&origin))
+    }
+}
+
+#[derive(Clone, Debug)]
+enum OriginPattern {
+    Exact(String),
+    /// Pattern with wildcard matching one or more characters other than
+    /// '/' and ':'
+    Wildcard {
+        prefix: String,
+        suffix: String,
+    },
+}
+
+impl OriginPattern {
+    fn new(pattern: &str) -> anyhow::Result<Self> {
+        let pattern = pattern.trim().to_ascii_lowercase();
+
+        if pattern.is_empty() {
+            return Err(anyhow::anyhow!("empty origin pattern"));
+        }
+
+        match pattern.split_once('*') {
+            None => Ok(Self::Exact(pattern)),
+            Some((_, suffix)) if suffix.contains('*') => Err(anyhow::anyhow!(
+                "origin pattern {} contains more than one wildcard",
+                pattern
+            )),
+            Some((prefix, suffix)) => Ok(Self::Wildcard {
+                prefix: prefix.to_owned(),
+                suffix: suffix.to_owned(),
+            }),
+        }
+    }
+
+    /// Origin must be lowercase
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            Self::Exact(pattern) => origin == pattern,
+            Self::Wildcard { prefix, suffix } => origin
+                .strip_prefix(prefix.as_str())
+                .and_then(|rest| rest.strip_suffix(suffix.as_str()))
+                .map(|wildcard| {
+                    !(wildcard.is_empty() || wildcard.contains('/') || wildcard.contains(':'))
+                })
+                .unwrap_or(false),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -74,3 +168,42 @@ pub enum SwarmControlMessage {
         ip_version: IpVersion,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_origins() {
+        let allowed_origins = AllowedOrigins::new(&[
+            "https://example.com".into(),
+            "https://*.Example.org".into(),
+            "http://localhost:*".into(),
+        ])
+        .unwrap();
+
+        let f = |origin: &str| allowed_origins.allows(Some(origin.as_bytes()));
+
+        assert!(f("https://example.com"));
+        assert!(f("HTTPS://EXAMPLE.COM"));
+        assert!(f("https://a.example.org"));
+        assert!(f("https://a.b.example.org"));
+        assert!(f("http://localhost:8080"));
+
+        assert!(!f("http://example.com"));
+        assert!(!f("https://example.com.evil.com"));
+        assert!(!f("https://example.org"));
+        assert!(!f("https://.example.org"));
+        assert!(!f("https://evil.com/.example.org"));
+        assert!(!f("https://evil.com:1.example.org"));
+        assert!(!f("http://localhost"));
+        assert!(!f("null"));
+        assert!(!allowed_origins.allows(None));
+        assert!(!allowed_origins.allows(Some(b"\xff")));
+
+        assert!(AllowedOrigins::default().allows(None));
+
+        assert!(AllowedOrigins::new(&["https://*.*.example.com".into()]).is_err());
+        assert!(AllowedOrigins::new(&["".into()]).is_err());
+    }
+}
@@ -2,7 +2,11 @@ use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc;
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
+use aquatic_common::{
+    access_list::AccessListConfig, connection_limits::ConnectionLimitConfig, ip_list::IpListConfig,
+    privileges::PrivilegeConfig, proxy_protocol::ProxyProtocolConfig,
+    reverse_proxy::ReverseProxyConfig, rustls_config::SniCertificateConfig,
+};
 use serde::Deserialize;
 
 use aquatic_common::cli::LogLevel;
@@ -10,8 +14,11 @@ use aquatic_toml_config::TomlConfig;
 
 /// aquatic_ws configuration
 ///
-/// Running behind a reverse proxy is supported, but IPv4 peer requests have
-/// to be proxied to IPv4 requests, and IPv6 requests to IPv6 requests.
+/// Running behind a reverse proxy is supported. Either configure the proxy
+/// addresses in `reverse_proxy.trusted_proxies` to have client IP addresses
+/// taken from request headers (or in `proxy_protocol.trusted_sources` to have
+/// them taken from PROXY protocol headers), or proxy IPv4 peer requests to
+/// IPv4 requests and IPv6 requests to IPv6 requests.
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
@@ -26,8 +33,13 @@ pub struct Config {
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
     pub cleaning: CleaningConfig,
+    pub rate_limits: RateLimitConfig,
     pub privileges: PrivilegeConfig,
     pub access_list: AccessListConfig,
+    pub ip_list: IpListConfig,
+    pub reverse_proxy: ReverseProxyConfig,
+    pub proxy_protocol: ProxyProtocolConfig,
+    pub connection_limits: ConnectionLimitConfig,
     #[cfg(feature = "metrics")]
     pub metrics: MetricsConfig,
     pub cpu_pinning: CpuPinningConfigAsc,
@@ -42,8 +54,13 @@ impl Default for Config {
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
             cleaning: CleaningConfig::default(),
+            rate_limits: RateLimitConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            ip_list: IpListConfig::default(),
+            reverse_proxy: ReverseProxyConfig::default(),
+            proxy_protocol: ProxyProtocolConfig::default(),
+            connection_limits: ConnectionLimitConfig::default(),
             #[cfg(feature = "metrics")]
             metrics: Default::default(),
             cpu_pinning: Default::default(),
@@ -69,10 +86,21 @@ pub struct NetworkConfig {
 
     /// Enable TLS
     pub enable_tls: bool,
-    /// Path to TLS certificate (DER-encoded X.509)
+    /// Path to TLS certificate chain (PEM-encoded X.509)
     pub tls_certificate_path: PathBuf,
-    /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
+    /// Path to TLS private key (PEM-encoded PKCS#8, PKCS#1 or SEC1 key)
     pub tls_private_key_path: PathBuf,
+    /// Additional certificates for TLS connections to specific server names
+    /// (SNI), as tables with keys `server_name`, `certificate_path` and
+    /// `private_key_path`. Server names may be wildcards matching a single
+    /// label (e.g., "*.example.com"). The certificate in
+    /// `tls_certificate_path` is used for other connections.
+    pub tls_sni_certificates: Vec<SniCertificateConfig>,
+    /// Check TLS certificate and private key files for changes this often
+    /// (seconds) and reload them once they have been left unmodified for a
+    /// full interval. Set to 0 to disable. Files are also reloaded on
+    /// SIGUSR1.
+    pub tls_file_check_interval: u64,
 
     pub websocket_max_message_size: usize,
     pub websocket_max_frame_size: usize,
@@ -80,6 +108,17 @@ pub struct NetworkConfig {
     /// Return a HTTP 200 Ok response when receiving GET /health. Can not be
     /// combined with enable_tls.
     pub enable_http_health_checks: bool,
+
+    /// Only accept WebSocket handshakes with these Origin header values
+    /// (e.g., ["https://example.com", "https://*.example.com"]). Leave empty
+    /// to accept all.
+    ///
+    /// Comparison is case-insensitive. A single `*` may be used to match one
+    /// or more characters other than '/' and ':'. Handshakes without an
+    /// Origin header, which non-browser clients usually don't send, are
+    /// rejected unless the list is empty. Rejected handshakes get a HTTP 403
+    /// response.
+    pub allowed_origins: Vec<String>,
 }
 
 impl Default for NetworkConfig {
@@ -92,11 +131,15 @@ impl Default for NetworkConfig {
             enable_tls: false,
             tls_certificate_path: "".into(),
             tls_private_key_path: "".into(),
+            tls_sni_certificates: Vec::new(),
+            tls_file_check_interval: 0,
 
             websocket_max_message_size: 64 * 1024,
             websocket_max_frame_size: 16 * 1024,
 
             enable_http_health_checks: false,
+
+            allowed_origins: Vec::new(),
         }
     }
 }
@@ -110,6 +153,18 @@ pub struct ProtocolConfig {
     pub max_offers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Count completed downloads for each torrent and include the numbers
+    /// in scrape responses
+    ///
+    /// Each peer id is counted at most once per torrent, so ids of counted
+    /// peers are stored for as long as the torrent has peers.
+    pub track_completed_downloads: bool,
+    /// Use a single swarm per torrent for IPv4 and IPv6 peers
+    ///
+    /// Offers can then be sent between peers connecting over different IP
+    /// versions, and seeder/leecher counts in announce and scrape responses
+    /// cover all peers. In metrics, all peers are counted as IPv4 peers.
+    pub share_peers_across_ip_versions: bool,
 }
 
 impl Default for ProtocolConfig {
@@ -118,6 +173,8 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 255,
             max_offers: 10,
             peer_announce_interval: 120,
+            track_completed_downloads: false,
+            share_peers_across_ip_versions: false,
         }
     }
 }
@@ -127,6 +184,10 @@ impl Default for ProtocolConfig {
 pub struct CleaningConfig {
     /// Clean peers this often (seconds)
     pub torrent_cleaning_interval: u64,
+    /// Split torrent maps of each swarm worker into this many shards, which
+    /// are cleaned one at a time (rounded up to nearest power of two,
+    /// maximum 65536)
+    pub torrent_map_shards: usize,
     /// Remove peers that have not announced for this long (seconds)
     pub max_peer_age: u32,
     // Clean connections this often (seconds)
@@ -139,6 +200,7 @@ impl Default for CleaningConfig {
     fn default() -> Self {
         Self {
             torrent_cleaning_interval: 30,
+            torrent_map_shards: 16,
             max_peer_age: 1800,
             max_connection_idle: 60 * 5,
             connection_cleaning_interval: 30,
@@ -146,6 +208,39 @@ impl Default for CleaningConfig {
     }
 }
 
+/// Limits on messages received from clients
+///
+/// Clients exceeding a limit get an error response and are disconnected.
+/// Offers are counted when announce requests containing them are received
+/// (at most `protocol.max_offers` per request). Per-IP limits are tracked
+/// across socket workers. Set a limit to 0 to disable it.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Maximum average number of messages per second for each connection
+    pub messages_per_second_per_connection: u32,
+    /// Maximum average number of messages per second for each IP address
+    pub messages_per_second_per_ip: u32,
+    /// Maximum average number of offers per second for each connection
+    pub offers_per_second_per_connection: u32,
+    /// Maximum average number of offers per second for each IP address
+    pub offers_per_second_per_ip: u32,
+    /// Allow bursts of up to this many seconds worth of messages or offers
+    pub burst_seconds: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_second_per_connection: 0,
+            messages_per_second_per_ip: 0,
+            offers_per_second_per_connection: 0,
+            offers_per_second_per_ip: 0,
+            burst_seconds: 10,
+        }
+    }
+}
+
 #[cfg(feature = "metrics")]
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
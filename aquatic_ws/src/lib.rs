@@ -3,11 +3,15 @@ pub mod config;
 pub mod workers;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
 use aquatic_common::cpu_pinning::glommio::{get_worker_placement, set_affinity_for_util_worker};
 use aquatic_common::cpu_pinning::WorkerIndex;
-use aquatic_common::rustls_config::create_rustls_config;
+use aquatic_common::rustls_config::{
+    create_rustls_config, spawn_rustls_config_file_watcher, update_rustls_config,
+    RustlsConfigArcSwap,
+};
 use aquatic_common::{PanicSentinelWatcher, ServerStartInstant};
 use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
 use signal_hook::{
@@ -16,7 +20,12 @@ use signal_hook::{
 };
 
 use aquatic_common::access_list::update_access_list;
+use aquatic_common::connection_limits::ConnectionLimiter;
+use aquatic_common::ip_list::update_ip_list;
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::proxy_protocol::TrustedSources;
+use aquatic_common::reverse_proxy::TrustedProxies;
+use aquatic_common::sharded_map::validate_num_shards;
 
 use common::*;
 use config::Config;
@@ -33,6 +42,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         ));
     }
 
+    validate_num_shards(config.cleaning.torrent_map_shards)?;
+
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
     #[cfg(feature = "prometheus")]
@@ -50,9 +61,19 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             })?;
     }
 
-    let state = State::default();
+    let state = State {
+        trusted_proxies: Arc::new(TrustedProxies::new(&config.reverse_proxy)?),
+        proxy_protocol_sources: Arc::new(TrustedSources::new(&config.proxy_protocol)?),
+        connection_limiter: Arc::new(ConnectionLimiter::new(&config.connection_limits)?),
+        allowed_origins: Arc::new(
+            AllowedOrigins::new(&config.network.allowed_origins)
+                .with_context(|| "parse allowed origins")?,
+        ),
+        ..Default::default()
+    };
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_ip_list(&config.ip_list, &state.ip_list)?;
 
     let num_peers = config.socket_workers + config.swarm_workers;
 
@@ -64,13 +85,26 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
 
     let opt_tls_config = if config.network.enable_tls {
-        Some(Arc::new(
+        let tls_config = Arc::new(RustlsConfigArcSwap::from_pointee(
             create_rustls_config(
                 &config.network.tls_certificate_path,
                 &config.network.tls_private_key_path,
+                &config.network.tls_sni_certificates,
             )
             .with_context(|| "create rustls config")?,
-        ))
+        ));
+
+        if config.network.tls_file_check_interval != 0 {
+            spawn_rustls_config_file_watcher(
+                config.network.tls_certificate_path.clone(),
+                config.network.tls_private_key_path.clone(),
+                config.network.tls_sni_certificates.clone(),
+                Duration::from_secs(config.network.tls_file_check_interval),
+                tls_config.clone(),
+            )?;
+        }
+
+        Some(tls_config)
     } else {
         None
     };
@@ -169,6 +203,16 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         match signal {
             SIGUSR1 => {
                 let _ = update_access_list(&config.access_list, &state.access_list);
+                let _ = update_ip_list(&config.ip_list, &state.ip_list);
+
+                if let Some(tls_config) = opt_tls_config.as_ref() {
+                    let _ = update_rustls_config(
+                        &config.network.tls_certificate_path,
+                        &config.network.tls_private_key_path,
+                        &config.network.tls_sni_certificates,
+                        tls_config,
+                    );
+                }
             }
             SIGTERM => {
                 if sentinel_watcher.panic_was_triggered() {
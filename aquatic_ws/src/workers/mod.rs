@@ -1,2 +1,3 @@
+pub mod rate_limit;
 pub mod socket;
 pub mod swarm;
@@ -0,0 +1,261 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+
+use crate::config::RateLimitConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitExceeded {
+    Messages,
+    Offers,
+}
+
+impl RateLimitExceeded {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Messages => "Too many messages",
+            Self::Offers => "Too many offers",
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Messages => "messages",
+            Self::Offers => "offers",
+        }
+    }
+}
+
+/// Message and offer limits for a connection
+pub struct ConnectionRateLimiter(Buckets);
+
+impl ConnectionRateLimiter {
+    pub fn new(now: Instant) -> Self {
+        Self(Buckets::new(now))
+    }
+
+    pub fn check(
+        &mut self,
+        config: &RateLimitConfig,
+        num_offers: usize,
+        now: Instant,
+    ) -> Result<(), RateLimitExceeded> {
+        self.0.try_take(
+            config.messages_per_second_per_connection,
+            config.offers_per_second_per_connection,
+            config.burst_seconds,
+            num_offers,
+            now,
+        )
+    }
+}
+
+/// Message and offer limits for IP addresses, shared by socket workers
+#[derive(Debug, Default)]
+pub struct IpRateLimiter(Mutex<HashMap<IpAddr, Buckets>>);
+
+impl IpRateLimiter {
+    pub fn check(
+        &self,
+        config: &RateLimitConfig,
+        ip: IpAddr,
+        num_offers: usize,
+        now: Instant,
+    ) -> Result<(), RateLimitExceeded> {
+        if config.messages_per_second_per_ip == 0 && config.offers_per_second_per_ip == 0 {
+            return Ok(());
+        }
+
+        self.0
+            .lock()
+            .unwrap()
+            .entry(ip)
+            .or_insert_with(|| Buckets::new(now))
+            .try_take(
+                config.messages_per_second_per_ip,
+                config.offers_per_second_per_ip,
+                config.burst_seconds,
+                num_offers,
+                now,
+            )
+    }
+
+    /// Remove entries that have not been used for long enough to have been
+    /// refilled completely
+    pub fn clean(&self, config: &RateLimitConfig, now: Instant) {
+        let max_idle = Duration::from_secs(config.burst_seconds.max(1).into());
+
+        let mut by_ip = self.0.lock().unwrap();
+
+        by_ip.retain(|_, buckets| now.saturating_duration_since(buckets.used_at) < max_idle);
+        by_ip.shrink_to_fit();
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Buckets {
+    messages: TokenBucket,
+    offers: TokenBucket,
+    used_at: Instant,
+}
+
+impl Buckets {
+    fn new(now: Instant) -> Self {
+        Self {
+            messages: TokenBucket::new(now),
+            offers: TokenBucket::new(now),
+            used_at: now,
+        }
+    }
+
+    /// Take one message token and `num_offers` offer tokens
+    fn try_take(
+        &mut self,
+        messages_per_second: u32,
+        offers_per_second: u32,
+        burst_seconds: u32,
+        num_offers: usize,
+        now: Instant,
+    ) -> Result<(), RateLimitExceeded> {
+        self.used_at = now;
+
+        if !self
+            .messages
+            .try_take(messages_per_second, burst_seconds, 1, now)
+        {
+            return Err(RateLimitExceeded::Messages);
+        }
+        if num_offers != 0
+            && !self
+                .offers
+                .try_take(offers_per_second, burst_seconds, num_offers, now)
+        {
+            return Err(RateLimitExceeded::Offers);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// Create full bucket (capacity is applied on first use)
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: f64::INFINITY,
+            updated_at: now,
+        }
+    }
+
+    /// Refill bucket with `rate` tokens per second, up to a capacity of
+    /// `burst_seconds` worth of tokens, then try to take `n` tokens. A rate
+    /// of zero means no limit.
+    fn try_take(&mut self, rate: u32, burst_seconds: u32, n: usize, now: Instant) -> bool {
+        if rate == 0 {
+            return true;
+        }
+
+        let rate = f64::from(rate);
+        let capacity = rate * f64::from(burst_seconds.max(1));
+        let elapsed = now.saturating_duration_since(self.updated_at);
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+        self.updated_at = now;
+
+        let n = n as f64;
+
+        if self.tokens >= n {
+            self.tokens -= n;
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limits() {
+        let config = RateLimitConfig {
+            messages_per_second_per_connection: 2,
+            messages_per_second_per_ip: 0,
+            offers_per_second_per_connection: 10,
+            offers_per_second_per_ip: 0,
+            burst_seconds: 2,
+        };
+
+        let start = Instant::now();
+        let mut limiter = ConnectionRateLimiter::new(start);
+
+        for _ in 0..4 {
+            assert_eq!(limiter.check(&config, 0, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.check(&config, 0, start),
+            Err(RateLimitExceeded::Messages)
+        );
+
+        let later = start + Duration::from_millis(500);
+
+        assert_eq!(limiter.check(&config, 10, later), Ok(()));
+        assert_eq!(
+            limiter.check(&config, 0, later),
+            Err(RateLimitExceeded::Messages)
+        );
+
+        // Bucket can't be filled above capacity
+        let much_later = later + Duration::from_secs(60);
+
+        assert_eq!(limiter.check(&config, 10, much_later), Ok(()));
+        assert_eq!(limiter.check(&config, 10, much_later), Ok(()));
+        assert_eq!(
+            limiter.check(&config, 1, much_later),
+            Err(RateLimitExceeded::Offers)
+        );
+    }
+
+    #[test]
+    fn test_ip_rate_limits() {
+        let config = RateLimitConfig {
+            messages_per_second_per_ip: 1,
+            burst_seconds: 1,
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let ip_a: IpAddr = "1.2.3.4".parse().unwrap();
+        let ip_b: IpAddr = "2001:db8::1".parse().unwrap();
+
+        let limiter = IpRateLimiter::default();
+
+        assert_eq!(limiter.check(&config, ip_a, 100, start), Ok(()));
+        assert_eq!(
+            limiter.check(&config, ip_a, 0, start),
+            Err(RateLimitExceeded::Messages)
+        );
+        assert_eq!(limiter.check(&config, ip_b, 0, start), Ok(()));
+
+        limiter.clean(&config, start + Duration::from_secs(1));
+
+        assert!(limiter.0.lock().unwrap().is_empty());
+
+        // Disabled limits don't create entries
+        limiter
+            .check(&RateLimitConfig::default(), ip_a, 0, start)
+            .unwrap();
+
+        assert!(limiter.0.lock().unwrap().is_empty());
+    }
+}
@@ -1,16 +1,21 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::connection_limits::{ConnectionLimitExceeded, ConnectionPermit};
+use aquatic_common::ip_list::{create_ip_list_cache, IpListArcSwap};
 use aquatic_common::privileges::PrivilegeDropper;
-use aquatic_common::rustls_config::RustlsConfig;
-use aquatic_common::{PanicSentinel, ServerStartInstant};
+use aquatic_common::proxy_protocol::read_client_addr;
+use aquatic_common::reverse_proxy::TrustedProxies;
+use aquatic_common::rustls_config::RustlsConfigArcSwap;
+use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ServerStartInstant};
 use aquatic_ws_protocol::*;
 use async_tungstenite::WebSocketStream;
 use futures::stream::{SplitSink, SplitStream};
@@ -32,6 +37,8 @@ use crate::config::Config;
 
 use crate::common::*;
 
+use super::rate_limit::{ConnectionRateLimiter, IpRateLimiter, RateLimitExceeded};
+
 const LOCAL_CHANNEL_SIZE: usize = 16;
 
 #[cfg(feature = "metrics")]
@@ -49,7 +56,10 @@ struct ConnectionReference {
     out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
     /// Updated after sending message to peer
     valid_until: ValidUntil,
-    announced_info_hashes: HashMap<InfoHash, PeerId>,
+    /// Peer ids used in announces to torrents. Entries expire when peers
+    /// would have been removed from swarms for not announcing, and are
+    /// removed on stopped events.
+    announced_info_hashes: HashMap<InfoHash, (PeerId, ValidUntil)>,
     ip_version: IpVersion,
 }
 
@@ -57,7 +67,7 @@ pub async fn run_socket_worker(
     _sentinel: PanicSentinel,
     config: Config,
     state: State,
-    opt_tls_config: Option<Arc<RustlsConfig>>,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
     control_message_mesh_builder: MeshBuilder<SwarmControlMessage, Partial>,
     in_message_mesh_builder: MeshBuilder<(InMessageMeta, InMessage), Partial>,
     out_message_mesh_builder: MeshBuilder<(OutMessageMeta, OutMessage), Partial>,
@@ -69,7 +79,7 @@ pub async fn run_socket_worker(
     WORKER_INDEX.with(|index| index.set(worker_index));
 
     let config = Rc::new(config);
-    let access_list = state.access_list;
+    let mut ip_list_cache = create_ip_list_cache(&state.ip_list);
 
     let listener = create_tcp_listener(&config, priv_dropper).expect("create tcp listener");
 
@@ -119,6 +129,37 @@ pub async fn run_socket_worker(
     )
     .unwrap();
 
+    // Periodically clean IP rate limiter, which is shared by all socket
+    // workers
+    if worker_index == 0 {
+        TimerActionRepeat::repeat_into(
+            enclose!((config, state) move || {
+                enclose!((config, state) move || async move {
+                    state.ip_rate_limiter.clean(&config.rate_limits, Instant::now());
+
+                    Some(Duration::from_secs(
+                        config.cleaning.connection_cleaning_interval,
+                    ))
+                })()
+            }),
+            tq_prioritized,
+        )
+        .unwrap();
+    }
+
+    // Periodically update connection limiter metrics, which are shared by
+    // all socket workers
+    #[cfg(feature = "metrics")]
+    if worker_index == 0 {
+        TimerActionRepeat::repeat(enclose!((state) move || {
+            enclose!((state) move || async move {
+                update_connection_limiter_metrics(&state);
+
+                Some(Duration::from_secs(1))
+            })()
+        }));
+    }
+
     for (_, out_message_receiver) in out_message_receivers.streams() {
         spawn_local_into(
             receive_out_messages(out_message_receiver, connection_slab.clone()),
@@ -133,8 +174,23 @@ pub async fn run_socket_worker(
     while let Some(stream) = incoming.next().await {
         match stream {
             Ok(stream) => {
-                let ip_version = match stream.peer_addr() {
-                    Ok(addr) => IpVersion::canonical_from_ip(addr.ip()),
+                let (peer_addr, ip_version) = match stream.peer_addr() {
+                    Ok(addr) => {
+                        let peer_addr = CanonicalSocketAddr::new(addr);
+                        let ip = peer_addr.get().ip();
+
+                        // Connections from PROXY protocol sources are
+                        // checked after reading client address
+                        if !state.proxy_protocol_sources.contains(peer_addr)
+                            && !ip_list_cache.load().allows(config.ip_list.mode, ip)
+                        {
+                            ::log::debug!("Closed connection from {} because of IP list", ip);
+
+                            continue;
+                        }
+
+                        (peer_addr, IpVersion::canonical_from_ip(addr.ip()))
+                    }
                     Err(err) => {
                         ::log::info!("could not extract ip version (v4 or v6): {:#}", err);
 
@@ -142,6 +198,18 @@ pub async fn run_socket_worker(
                     }
                 };
 
+                let connection_permit = match acquire_connection_permit(&state, peer_addr) {
+                    Ok(connection_permit) => connection_permit,
+                    Err(err) => {
+                        ::log::debug!("Closed connection: {}", err);
+
+                        #[cfg(feature = "metrics")]
+                        record_rejected_connection(err);
+
+                        continue;
+                    }
+                };
+
                 let (out_message_sender, out_message_receiver) = new_bounded(LOCAL_CHANNEL_SIZE);
                 let out_message_sender = Rc::new(out_message_sender);
 
@@ -158,7 +226,7 @@ pub async fn run_socket_worker(
 
                 ::log::trace!("accepting stream, assigning id {}", key);
 
-                let task_handle = spawn_local_into(enclose!((config, access_list, control_message_senders, in_message_senders, connection_slab, opt_tls_config) async move {
+                let task_handle = spawn_local_into(enclose!((config, state, control_message_senders, in_message_senders, connection_slab, opt_tls_config) async move {
                     #[cfg(feature = "metrics")]
                     ::metrics::increment_gauge!(
                         "aquatic_active_connections",
@@ -169,7 +237,7 @@ pub async fn run_socket_worker(
 
                     if let Err(err) = run_connection(
                         config.clone(),
-                        access_list,
+                        state,
                         in_message_senders,
                         tq_prioritized,
                         tq_regular,
@@ -180,7 +248,8 @@ pub async fn run_socket_worker(
                         out_message_consumer_id,
                         ConnectionId(key),
                         opt_tls_config,
-                        ip_version,
+                        connection_permit,
+                        peer_addr,
                         stream,
                     ).await {
                         ::log::debug!("connection error: {:#}", err);
@@ -200,9 +269,16 @@ pub async fn run_socket_worker(
                     // multiple RefCell borrows
                     let opt_reference = connection_slab.borrow_mut().try_remove(key);
 
-                    // Tell swarm workers to remove peer
+                    // Tell swarm workers to remove peer from torrents it is
+                    // still active in
                     if let Some(reference) = opt_reference {
-                        for (info_hash, peer_id) in reference.announced_info_hashes {
+                        let now = server_start_instant.seconds_elapsed();
+
+                        for (info_hash, (peer_id, valid_until)) in reference.announced_info_hashes {
+                            if !valid_until.valid(now) {
+                                continue;
+                            }
+
                             let message = SwarmControlMessage::ConnectionClosed {
                                 info_hash,
                                 peer_id,
@@ -237,6 +313,51 @@ pub async fn run_socket_worker(
     }
 }
 
+/// Register connection with connection limiter. Per-IP limits don't apply to
+/// connections from proxies, since they are shared by many clients. Instead,
+/// they are applied once client address is known (see [limit_client_ip]).
+fn acquire_connection_permit(
+    state: &State,
+    peer_addr: CanonicalSocketAddr,
+) -> Result<ConnectionPermit, ConnectionLimitExceeded> {
+    let opt_ip = (!(state.proxy_protocol_sources.contains(peer_addr)
+        || state.trusted_proxies.is_trusted(peer_addr)))
+    .then(|| peer_addr.get().ip());
+
+    state.connection_limiter.try_acquire(opt_ip)
+}
+
+/// Apply per-IP connection limit to client address of connection from proxy
+fn limit_client_ip(connection_permit: &mut ConnectionPermit, ip: IpAddr) -> anyhow::Result<()> {
+    connection_permit.try_limit_ip(ip).map_err(|err| {
+        #[cfg(feature = "metrics")]
+        record_rejected_connection(err);
+
+        anyhow::anyhow!("closed connection from {}: {}", ip, err)
+    })
+}
+
+#[cfg(feature = "metrics")]
+fn record_rejected_connection(err: ConnectionLimitExceeded) {
+    ::metrics::increment_counter!(
+        "aquatic_rejected_connections_total",
+        "reason" => err.kind(),
+        "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+    );
+}
+
+#[cfg(feature = "metrics")]
+fn update_connection_limiter_metrics(state: &State) {
+    ::metrics::gauge!(
+        "aquatic_connection_limiter_connections",
+        state.connection_limiter.num_connections() as f64,
+    );
+    ::metrics::gauge!(
+        "aquatic_connection_limiter_ip_prefixes",
+        state.connection_limiter.num_ip_prefixes() as f64,
+    );
+}
+
 async fn clean_connections(
     config: Rc<Config>,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
@@ -246,6 +367,18 @@ async fn clean_connections(
 
     connection_slab.borrow_mut().retain(|_, reference| {
         if reference.valid_until.valid(now) {
+            // Swarm workers will have removed peers from these torrents
+            reference
+                .announced_info_hashes
+                .retain(|_, (_, valid_until)| valid_until.valid(now));
+
+            // Only reallocate when a lot of memory would be freed
+            if reference.announced_info_hashes.capacity()
+                > 2 * reference.announced_info_hashes.len()
+            {
+                reference.announced_info_hashes.shrink_to_fit();
+            }
+
             true
         } else {
             if let Some(ref handle) = reference.task_handle {
@@ -294,7 +427,7 @@ async fn receive_out_messages(
 
 async fn run_connection(
     config: Rc<Config>,
-    access_list: Arc<AccessListArcSwap>,
+    state: State,
     in_message_senders: Rc<Senders<(InMessageMeta, InMessage)>>,
     tq_prioritized: TaskQueueHandle,
     tq_regular: TaskQueueHandle,
@@ -304,29 +437,55 @@ async fn run_connection(
     server_start_instant: ServerStartInstant,
     out_message_consumer_id: ConsumerId,
     connection_id: ConnectionId,
-    opt_tls_config: Option<Arc<RustlsConfig>>,
-    ip_version: IpVersion,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
+    // Keeps connection counted until it is closed or task is cancelled
+    mut connection_permit: ConnectionPermit,
+    peer_addr: CanonicalSocketAddr,
     mut stream: TcpStream,
 ) -> anyhow::Result<()> {
+    let peer_addr = if state.proxy_protocol_sources.contains(peer_addr) {
+        let client_addr = read_client_addr(&mut stream, peer_addr).await?;
+        let ip = client_addr.get().ip();
+
+        if !state.ip_list.load().allows(config.ip_list.mode, ip) {
+            return Err(anyhow::anyhow!(
+                "closed connection from {} because of IP list",
+                ip
+            ));
+        }
+
+        limit_client_ip(&mut connection_permit, ip)?;
+
+        client_addr
+    } else {
+        peer_addr
+    };
+
     if let Some(tls_config) = opt_tls_config {
-        let tls_acceptor: TlsAcceptor = tls_config.into();
+        // Use latest config, since it may have been reloaded
+        let tls_acceptor: TlsAcceptor = tls_config.load_full().into();
 
         let stream = tls_acceptor.accept(stream).await?;
 
         run_stream_agnostic_connection(
             config.clone(),
-            access_list,
+            state.access_list,
+            state.ip_list,
+            state.trusted_proxies,
+            state.allowed_origins,
             in_message_senders,
             tq_prioritized,
             tq_regular,
             connection_slab.clone(),
+            state.ip_rate_limiter,
             out_message_sender,
             out_message_receiver,
             server_start_instant,
             out_message_consumer_id,
             connection_id,
+            &mut connection_permit,
             stream,
-            ip_version,
+            peer_addr,
         )
         .await
     } else {
@@ -361,18 +520,23 @@ async fn run_connection(
 
         run_stream_agnostic_connection(
             config.clone(),
-            access_list,
+            state.access_list,
+            state.ip_list,
+            state.trusted_proxies,
+            state.allowed_origins,
             in_message_senders,
             tq_prioritized,
             tq_regular,
             connection_slab.clone(),
+            state.ip_rate_limiter,
             out_message_sender,
             out_message_receiver,
             server_start_instant,
             out_message_consumer_id,
             connection_id,
+            &mut connection_permit,
             stream,
-            ip_version,
+            peer_addr,
         )
         .await
     }
@@ -383,17 +547,22 @@ async fn run_stream_agnostic_connection<
 >(
     config: Rc<Config>,
     access_list: Arc<AccessListArcSwap>,
+    ip_list: Arc<IpListArcSwap>,
+    trusted_proxies: Arc<TrustedProxies>,
+    allowed_origins: Arc<AllowedOrigins>,
     in_message_senders: Rc<Senders<(InMessageMeta, InMessage)>>,
     tq_prioritized: TaskQueueHandle,
     tq_regular: TaskQueueHandle,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
+    ip_rate_limiter: Arc<IpRateLimiter>,
     out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
     out_message_receiver: LocalReceiver<(OutMessageMeta, OutMessage)>,
     server_start_instant: ServerStartInstant,
     out_message_consumer_id: ConsumerId,
     connection_id: ConnectionId,
+    connection_permit: &mut ConnectionPermit,
     stream: S,
-    ip_version: IpVersion,
+    peer_addr: CanonicalSocketAddr,
 ) -> anyhow::Result<()> {
     let ws_config = tungstenite::protocol::WebSocketConfig {
         max_frame_size: Some(config.network.websocket_max_frame_size),
@@ -401,7 +570,92 @@ async fn run_stream_agnostic_connection<
         max_send_queue: Some(2),
         ..Default::default()
     };
-    let stream = async_tungstenite::accept_async_with_config(stream, Some(ws_config)).await?;
+
+    let mut client_addr = peer_addr;
+    // Set to metrics label of reason if handshake is rejected in callback
+    let mut opt_rejection_reason: Option<&'static str> = None;
+
+    let is_trusted_proxy = trusted_proxies.is_trusted(peer_addr);
+
+    let result = if is_trusted_proxy || !allowed_origins.is_empty() {
+        let callback = |request: &tungstenite::handshake::server::Request,
+                        response: tungstenite::handshake::server::Response| {
+            if is_trusted_proxy {
+                let header_values = request
+                    .headers()
+                    .get_all(trusted_proxies.header_name())
+                    .into_iter()
+                    .map(|value| value.as_bytes());
+
+                client_addr = trusted_proxies.client_addr(peer_addr, header_values);
+
+                // Connection address was checked when accepting connection,
+                // but client address wasn't
+                if !ip_list
+                    .load()
+                    .allows(config.ip_list.mode, client_addr.get().ip())
+                {
+                    opt_rejection_reason = Some("ip_list");
+
+                    return Err(forbidden_response());
+                }
+
+                // Per-IP connection limit didn't apply to proxy address.
+                // Rejection is counted in connection metrics, not in
+                // handshake metrics.
+                if let Err(err) = limit_client_ip(connection_permit, client_addr.get().ip()) {
+                    ::log::debug!("{:#}", err);
+
+                    return Err(forbidden_response());
+                }
+            }
+
+            let opt_origin = request
+                .headers()
+                .get(tungstenite::http::header::ORIGIN)
+                .map(|value| value.as_bytes());
+
+            if !allowed_origins.allows(opt_origin) {
+                opt_rejection_reason = Some("origin");
+
+                return Err(forbidden_response());
+            }
+
+            Ok(response)
+        };
+
+        async_tungstenite::accept_hdr_async_with_config(stream, callback, Some(ws_config)).await
+    } else {
+        async_tungstenite::accept_async_with_config(stream, Some(ws_config)).await
+    };
+
+    // Connection address might have been replaced by client address from
+    // PROXY protocol or proxy headers
+    let ip_version = IpVersion::canonical_from_ip(client_addr.get().ip());
+
+    if let Some(reason) = opt_rejection_reason {
+        #[cfg(feature = "metrics")]
+        ::metrics::increment_counter!(
+            "aquatic_rejected_handshakes_total",
+            "reason" => reason,
+            "ip_version" => ip_version_to_metrics_str(ip_version),
+            "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+        );
+
+        return Err(anyhow::anyhow!(
+            "rejected WebSocket handshake from {} (reason: {})",
+            client_addr.get().ip(),
+            reason
+        ));
+    }
+
+    let stream = result?;
+
+    // Peer removal messages sent after connection is closed use IP version
+    // stored in connection reference
+    if let Some(reference) = connection_slab.borrow_mut().get_mut(connection_id.0) {
+        reference.ip_version = ip_version;
+    }
 
     let (ws_out, ws_in) = futures::StreamExt::split(stream);
 
@@ -421,6 +675,10 @@ async fn run_stream_agnostic_connection<
                 ws_in,
                 ip_version,
                 connection_id,
+                server_start_instant,
+                client_ip: client_addr.get().ip(),
+                rate_limiter: ConnectionRateLimiter::new(Instant::now()),
+                ip_rate_limiter,
             };
 
             let result = reader.run_in_message_loop().await;
@@ -470,6 +728,10 @@ struct ConnectionReader<S> {
     ws_in: SplitStream<WebSocketStream<S>>,
     ip_version: IpVersion,
     connection_id: ConnectionId,
+    server_start_instant: ServerStartInstant,
+    client_ip: IpAddr,
+    rate_limiter: ConnectionRateLimiter,
+    ip_rate_limiter: Arc<IpRateLimiter>,
 }
 
 impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
@@ -489,7 +751,36 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
 
             match &message {
                 tungstenite::Message::Text(_) | tungstenite::Message::Binary(_) => {
-                    match InMessage::from_ws_message(message) {
+                    let result = InMessage::from_ws_message(message);
+
+                    let num_offers = match &result {
+                        Ok(InMessage::AnnounceRequest(AnnounceRequest {
+                            offers: Some(offers),
+                            ..
+                        })) => offers.len().min(self.config.protocol.max_offers),
+                        _ => 0,
+                    };
+
+                    if let Err(exceeded) = self.check_rate_limits(num_offers) {
+                        #[cfg(feature = "metrics")]
+                        ::metrics::increment_counter!(
+                            "aquatic_rate_limited_connections_total",
+                            "kind" => exceeded.kind(),
+                            "ip_version" => ip_version_to_metrics_str(self.ip_version),
+                            "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+                        );
+
+                        self.send_error_response(exceeded.message().into(), None, None)
+                            .await?;
+
+                        return Err(anyhow::anyhow!(
+                            "closed connection from {} because of rate limits: {}",
+                            self.client_ip,
+                            exceeded.message()
+                        ));
+                    }
+
+                    match result {
                         Ok(in_message) => {
                             self.handle_in_message(in_message).await?;
                         }
@@ -552,13 +843,22 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                                 )
                             })?;
 
-                        // Store peer id / check if stored peer id matches
+                        let stopped =
+                            matches!(announce_request.event, Some(AnnounceEvent::Stopped));
+                        let valid_until = ValidUntil::new(
+                            self.server_start_instant,
+                            self.config.cleaning.max_peer_age,
+                        );
+
+                        // Store peer id / check if stored peer id matches.
+                        // Remove entry on stopped event, since swarm worker
+                        // will remove peer.
                         match connection_reference
                             .announced_info_hashes
                             .entry(announce_request.info_hash)
                         {
-                            Entry::Occupied(entry) => {
-                                if *entry.get() != announce_request.peer_id {
+                            Entry::Occupied(mut entry) => {
+                                if entry.get().0 != announce_request.peer_id {
                                     // Drop Rc borrow before awaiting
                                     drop(connection_slab);
 
@@ -573,9 +873,17 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                                         "Peer used more than one PeerId for a single torrent"
                                     ));
                                 }
+
+                                if stopped {
+                                    entry.remove();
+                                } else {
+                                    entry.get_mut().1 = valid_until;
+                                }
                             }
                             Entry::Vacant(entry) => {
-                                entry.insert(announce_request.peer_id);
+                                if !stopped {
+                                    entry.insert((announce_request.peer_id, valid_until));
+                                }
                             }
                         }
                     }
@@ -670,6 +978,17 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         Ok(())
     }
 
+    /// Check connection limits first, so that IP limits are not affected
+    /// by messages from connections that are closed anyway
+    fn check_rate_limits(&mut self, num_offers: usize) -> Result<(), RateLimitExceeded> {
+        let config = &self.config.rate_limits;
+        let now = Instant::now();
+
+        self.rate_limiter.check(config, num_offers, now)?;
+        self.ip_rate_limiter
+            .check(config, self.client_ip, num_offers, now)
+    }
+
     async fn send_error_response(
         &self,
         failure_reason: Cow<'static, str>,
@@ -826,6 +1145,15 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
     }
 }
 
+fn forbidden_response() -> tungstenite::handshake::server::ErrorResponse {
+    let mut error_response =
+        tungstenite::handshake::server::ErrorResponse::new(Some("Forbidden".into()));
+
+    *error_response.status_mut() = tungstenite::http::StatusCode::FORBIDDEN;
+
+    error_response
+}
+
 fn calculate_in_message_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
     (info_hash.0[0] as usize) % config.swarm_workers
 }
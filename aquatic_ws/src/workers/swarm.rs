@@ -4,6 +4,8 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::completed_downloads::CompletedDownloads;
+use aquatic_common::sharded_map::ShardedMap;
 use futures::StreamExt;
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role, Senders};
 use glommio::enclose;
@@ -13,8 +15,7 @@ use hashbrown::HashMap;
 use rand::{rngs::SmallRng, SeedableRng};
 
 use aquatic_common::{
-    extract_response_peers, AmortizedIndexMap, IndexMap, PanicSentinel, SecondsSinceServerStart,
-    ServerStartInstant,
+    extract_response_peers, IndexMap, PanicSentinel, SecondsSinceServerStart, ServerStartInstant,
 };
 use aquatic_ws_protocol::*;
 
@@ -61,6 +62,8 @@ type PeerMap = IndexMap<PeerId, Peer>;
 struct TorrentData {
     pub peers: PeerMap,
     pub num_seeders: usize,
+    /// Only updated if `config.protocol.track_completed_downloads` is set
+    pub completed_downloads: CompletedDownloads<PeerId>,
 }
 
 impl Default for TorrentData {
@@ -69,6 +72,7 @@ impl Default for TorrentData {
         Self {
             peers: Default::default(),
             num_seeders: 0,
+            completed_downloads: Default::default(),
         }
     }
 }
@@ -87,16 +91,84 @@ impl TorrentData {
     }
 }
 
-type TorrentMap = AmortizedIndexMap<InfoHash, TorrentData>;
+/// Torrents indexed by info hash bytes
+type TorrentMap = ShardedMap<TorrentData>;
+
+/// Remove forbidden or inactive torrents and reclaim space in next shard
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+fn clean_next_shard(
+    config: &Config,
+    torrent_map: &mut TorrentMap,
+    access_list_cache: &mut AccessListCache,
+    now: SecondsSinceServerStart,
+    ip_version: &'static str,
+) {
+    torrent_map.clean_next_shard(|info_hash, torrent_data| {
+        if !access_list_cache
+            .load()
+            .allows(config.access_list.mode, info_hash)
+        {
+            return None;
+        }
+
+        let num_seeders = &mut torrent_data.num_seeders;
+
+        torrent_data.peers.retain(|_, peer| {
+            let keep = peer.valid_until.valid(now);
+
+            if (!keep) & peer.seeder {
+                *num_seeders -= 1;
+            }
+
+            keep
+        });
+
+        (!torrent_data.peers.is_empty()).then_some(torrent_data.peers.len())
+    });
+
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(
+        "aquatic_peers",
+        torrent_map.num_peers() as f64,
+        "ip_version" => ip_version,
+        "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+    );
+}
 
-#[derive(Default)]
 struct TorrentMaps {
     pub ipv4: TorrentMap,
     pub ipv6: TorrentMap,
+    /// Store peers of both IP versions in `ipv4`
+    share_peers_across_ip_versions: bool,
 }
 
 impl TorrentMaps {
-    fn clean(
+    fn new(config: &Config) -> Self {
+        Self {
+            ipv4: TorrentMap::new(config.cleaning.torrent_map_shards),
+            ipv6: TorrentMap::new(config.cleaning.torrent_map_shards),
+            share_peers_across_ip_versions: config.protocol.share_peers_across_ip_versions,
+        }
+    }
+
+    /// Get torrent map storing peers with given IP version, together with
+    /// IP version label for metrics
+    fn by_ip_version(&mut self, ip_version: IpVersion) -> (&mut TorrentMap, &'static str) {
+        match ip_version {
+            IpVersion::V6 if !self.share_peers_across_ip_versions => (&mut self.ipv6, "6"),
+            _ => (&mut self.ipv4, "4"),
+        }
+    }
+
+    /// Time between cleaning shards of each torrent map
+    fn shard_cleaning_interval(&self, config: &Config) -> Duration {
+        self.ipv4.shard_cleaning_interval(Duration::from_secs(
+            config.cleaning.torrent_cleaning_interval,
+        ))
+    }
+
+    /// Clean next shard of each torrent map
+    fn clean_next_shard(
         &mut self,
         config: &Config,
         access_list: &Arc<AccessListArcSwap>,
@@ -105,55 +177,8 @@ impl TorrentMaps {
         let mut access_list_cache = create_access_list_cache(access_list);
         let now = server_start_instant.seconds_elapsed();
 
-        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv4, now, "4");
-        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6, now, "6");
-    }
-
-    fn clean_torrent_map(
-        config: &Config,
-        access_list_cache: &mut AccessListCache,
-        torrent_map: &mut TorrentMap,
-        now: SecondsSinceServerStart,
-        ip_version: &'static str,
-    ) {
-        let mut total_num_peers = 0u64;
-
-        torrent_map.retain(|info_hash, torrent_data| {
-            if !access_list_cache
-                .load()
-                .allows(config.access_list.mode, &info_hash.0)
-            {
-                return false;
-            }
-
-            let num_seeders = &mut torrent_data.num_seeders;
-
-            torrent_data.peers.retain(|_, peer| {
-                let keep = peer.valid_until.valid(now);
-
-                if (!keep) & peer.seeder {
-                    *num_seeders -= 1;
-                }
-
-                keep
-            });
-
-            total_num_peers += torrent_data.peers.len() as u64;
-
-            !torrent_data.peers.is_empty()
-        });
-
-        torrent_map.shrink_to_fit();
-
-        let total_num_peers = total_num_peers as f64;
-
-        #[cfg(feature = "metrics")]
-        ::metrics::gauge!(
-            "aquatic_peers",
-            total_num_peers,
-            "ip_version" => ip_version,
-            "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
-        );
+        clean_next_shard(config, &mut self.ipv4, &mut access_list_cache, now, "4");
+        clean_next_shard(config, &mut self.ipv6, &mut access_list_cache, now, "6");
     }
 }
 
@@ -180,15 +205,18 @@ pub async fn run_swarm_worker(
 
     let out_message_senders = Rc::new(out_message_senders);
 
-    let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
+    let torrents = Rc::new(RefCell::new(TorrentMaps::new(&config)));
     let access_list = state.access_list;
 
-    // Periodically clean torrents
+    // Periodically clean torrents, one shard at a time, spreading cleaning
+    // work evenly over the cleaning interval
+    let shard_cleaning_interval = torrents.borrow().shard_cleaning_interval(&config);
+
     TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
         enclose!((config, torrents, access_list) move || async move {
-            torrents.borrow_mut().clean(&config, &access_list, server_start_instant);
+            torrents.borrow_mut().clean_next_shard(&config, &access_list, server_start_instant);
 
-            Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
+            Some(shard_cleaning_interval)
         })()
     }));
 
@@ -200,13 +228,13 @@ pub async fn run_swarm_worker(
 
             ::metrics::gauge!(
                 "aquatic_torrents",
-                torrents.ipv4.len() as f64,
+                torrents.ipv4.num_torrents() as f64,
                 "ip_version" => "4",
                 "worker_index" => worker_index.to_string(),
             );
             ::metrics::gauge!(
                 "aquatic_torrents",
-                torrents.ipv6.len() as f64,
+                torrents.ipv6.num_torrents() as f64,
                 "ip_version" => "6",
                 "worker_index" => worker_index.to_string(),
             );
@@ -255,14 +283,11 @@ where
             } => {
                 ::log::debug!("Removing peer from torrents because connection was closed");
 
-                if let IpVersion::V4 = ip_version {
-                    if let Some(torrent_data) = torrents.borrow_mut().ipv4.get_mut(&info_hash) {
-                        torrent_data.remove_peer(peer_id);
-                    }
-                } else {
-                    if let Some(torrent_data) = torrents.borrow_mut().ipv6.get_mut(&info_hash) {
-                        torrent_data.remove_peer(peer_id);
-                    }
+                let mut torrents = torrents.borrow_mut();
+                let (torrent_map, _) = torrents.by_ip_version(ip_version);
+
+                if let Some(torrent_data) = torrent_map.get_mut(&info_hash.0) {
+                    torrent_data.remove_peer(peer_id);
                 }
             }
         }
@@ -347,12 +372,8 @@ fn handle_announce_request(
     request_sender_meta: InMessageMeta,
     request: AnnounceRequest,
 ) {
-    let (torrent_data, ip_version): (&mut TorrentData, &'static str) =
-        if let IpVersion::V4 = request_sender_meta.ip_version {
-            (torrent_maps.ipv4.entry(request.info_hash).or_default(), "4")
-        } else {
-            (torrent_maps.ipv6.entry(request.info_hash).or_default(), "6")
-        };
+    let (torrent_map, ip_version) = torrent_maps.by_ip_version(request_sender_meta.ip_version);
+    let torrent_data = torrent_map.get_or_insert_default(request.info_hash.0);
 
     // If there is already a peer with this peer_id, check that connection id
     // is same as that of request sender. Otherwise, ignore request. Since
@@ -368,6 +389,8 @@ fn handle_announce_request(
 
     // Insert/update/remove peer who sent this request
     {
+        let event_completed = request.event == Some(AnnounceEvent::Completed);
+
         let peer_status = PeerStatus::from_event_and_bytes_left(
             request.event.unwrap_or_default(),
             request.bytes_left,
@@ -403,6 +426,15 @@ fn handle_announce_request(
             torrent_data.num_seeders -= 1;
         }
 
+        if config.protocol.track_completed_downloads {
+            torrent_data.completed_downloads.update(
+                request.peer_id,
+                peer_status == PeerStatus::Seeding,
+                opt_removed_peer.as_ref().map(|peer| peer.seeder),
+                event_completed,
+            );
+        }
+
         #[cfg(feature = "metrics")]
         match peer_status {
             PeerStatus::Stopped if opt_removed_peer.is_some() => {
@@ -431,7 +463,7 @@ fn handle_announce_request(
         let max_num_peers_to_take = offers.len().min(config.protocol.max_offers);
 
         #[inline]
-        fn f(peer: &Peer) -> Peer {
+        fn f(_: &PeerId, peer: &Peer) -> Peer {
             *peer
         }
 
@@ -518,17 +550,13 @@ fn handle_scrape_request(
         files: HashMap::with_capacity(num_to_take),
     };
 
-    let torrent_map: &mut TorrentMap = if let IpVersion::V4 = meta.ip_version {
-        &mut torrent_maps.ipv4
-    } else {
-        &mut torrent_maps.ipv6
-    };
+    let (torrent_map, _) = torrent_maps.by_ip_version(meta.ip_version);
 
     for info_hash in info_hashes.into_iter().take(num_to_take) {
-        if let Some(torrent_data) = torrent_map.get(&info_hash) {
+        if let Some(torrent_data) = torrent_map.get(&info_hash.0) {
             let stats = ScrapeStatistics {
                 complete: torrent_data.num_seeders,
-                downloaded: 0, // No implementation planned
+                downloaded: torrent_data.completed_downloads.count(),
                 incomplete: torrent_data.num_leechers(),
             };
 